@@ -0,0 +1,131 @@
+//! Round-trips to the X server made by the manage hook for a few typical windows.
+//!
+//! Each window is managed three times against a mock connection: with every rule fetching the
+//! properties it needs itself, with a property snapshot taken one request at a time and with
+//! the snapshot requests pipelined as they are on a real connection.
+//!
+//! Run with `cargo bench --bench round_trips --features testing`.
+use std::{collections::HashMap, time::Instant};
+
+use favilo_penrose::{
+    config::Settings,
+    hooks::{manage_hook, rule_hooks},
+    mock::MockConn,
+};
+use penrose::{
+    core::{hooks::ManageHook, Config, State, WindowManager},
+    x::Prop,
+    Xid,
+};
+
+const ITERATIONS: u32 = 1000;
+
+fn strs(s: &[&str]) -> Prop {
+    Prop::UTF8String(s.iter().map(|s| s.to_string()).collect())
+}
+
+fn window(class: &[&str], title: &str, window_type: &str, hints: Option<[u32; 18]>) -> MockConn {
+    let x = MockConn::default()
+        .with_prop(1, "WM_CLASS", strs(class))
+        .with_prop(1, "WM_NAME", strs(&[title]))
+        .with_prop(1, "_NET_WM_NAME", strs(&[title]))
+        .with_prop(
+            1,
+            "_NET_WM_WINDOW_TYPE",
+            Prop::Atom(vec![format!("_NET_WM_WINDOW_TYPE_{window_type}")]),
+        );
+
+    match hints {
+        Some(raw) => x.with_prop(1, "WM_NORMAL_HINTS", Prop::Cardinal(raw.to_vec())),
+        None => x,
+    }
+}
+
+fn state() -> State<MockConn> {
+    let wm = WindowManager::new(
+        Config::default(),
+        HashMap::new(),
+        HashMap::new(),
+        MockConn::default(),
+    )
+    .expect("a mock window manager");
+
+    wm.state
+}
+
+// The round-trips made managing a window with `hook`, and the mean time taken
+fn run(x: &MockConn, hook: &mut dyn ManageHook<MockConn>) -> (usize, f64) {
+    let id = Xid::from(1);
+    let mut trips = 0;
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        let mut state = state();
+        state.client_set.insert(id);
+        x.take_round_trips();
+        hook.call(id, &mut state, x).expect("hooks to run");
+        trips = x.take_round_trips();
+    }
+
+    let micros = start.elapsed().as_secs_f64() * 1e6 / ITERATIONS as f64;
+
+    (trips, micros)
+}
+
+fn main() {
+    // Roughly what kitty sets: 2px of padding around 9x18 character cells
+    let mut terminal = [0; 18];
+    terminal[0] = 1 << 4 | 1 << 6 | 1 << 8;
+    terminal[5..11].copy_from_slice(&[22, 40, 0, 0, 9, 18]);
+    terminal[15..17].copy_from_slice(&[4, 4]);
+
+    // A dialog that can not be resized
+    let mut fixed = [0; 18];
+    fixed[0] = 1 << 4 | 1 << 5;
+    fixed[5..9].copy_from_slice(&[400, 300, 400, 300]);
+
+    let windows = [
+        (
+            "terminal",
+            window(&["kitty", "kitty"], "~", "NORMAL", Some(terminal)),
+        ),
+        (
+            "zoom popup",
+            window(
+                &["zoom", "zoom"],
+                "zoom_linux_float_video_window",
+                "NORMAL",
+                None,
+            ),
+        ),
+        (
+            "fixed size dialog",
+            window(
+                &["pavucontrol", "Pavucontrol"],
+                "Volume Control",
+                "DIALOG",
+                Some(fixed),
+            ),
+        ),
+    ];
+
+    let settings = Settings::default();
+    println!(
+        "{:<20}{:>24}{:>24}{:>24}",
+        "window", "per query", "snapshot", "pipelined snapshot"
+    );
+
+    for (name, x) in windows {
+        let before = run(&x, &mut rule_hooks(&settings));
+        let sequential = run(&x, manage_hook(&settings).as_mut());
+        let pipelined = run(&x.pipelined(), manage_hook(&settings).as_mut());
+
+        let cell = |(trips, micros): (usize, f64)| format!("{trips} ({micros:.1}µs)");
+        println!(
+            "{name:<20}{:>24}{:>24}{:>24}",
+            cell(before),
+            cell(sequential),
+            cell(pipelined)
+        );
+    }
+}
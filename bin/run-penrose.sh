@@ -5,5 +5,7 @@ eval $(ssh-agent)
 
 while true; do
   favilo-penrose
+  # Logging out from the power menu exits with 64, anything else is a restart
+  [ $? -eq 64 ] && break
   export RESTARTED=true
 done
@@ -0,0 +1,32 @@
+#! /usr/bin/env bash
+# ----------------------------------------------------------------
+# Render favilo-penrose state for a polybar custom/script module:
+#
+#   [module/penrose]
+#   type = custom/script
+#   exec = /usr/local/scripts/polybar-penrose.sh
+#   tail = true
+#
+# Each JSON line from `favilo-penrosectl subscribe` becomes one line
+# of polybar formatted text. Reconnects when penrose restarts.
+# ----------------------------------------------------------------
+
+FOCUSED="#458588"
+URGENT="#cc241d"
+OCCUPIED="#ebdbb2"
+EMPTY="#665c54"
+
+while true; do
+  favilo-penrosectl subscribe 2>/dev/null | jq --unbuffered -r \
+    --arg focused "$FOCUSED" --arg urgent "$URGENT" \
+    --arg occupied "$OCCUPIED" --arg empty "$EMPTY" '
+    ([.tags[] |
+      if .focused then "%{B\($focused)} \(.tag) %{B-}"
+      elif .urgent then "%{B\($urgent)} \(.tag) %{B-}"
+      elif .occupied then "%{F\($occupied)} \(.tag) %{F-}"
+      else "%{F\($empty)} \(.tag) %{F-}"
+      end
+    ] | join("")) + "  [\(.layout)] \(.screen)  \(.title // "")"
+    '
+  sleep 1
+done
@@ -0,0 +1,619 @@
+//! Starting, and keeping running, the programs listed under `[[autostart.programs]]`.
+//! Started processes are recorded on disk so that restarting doesn't run them twice.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    os::{
+        fd::AsRawFd,
+        unix::process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use penrose::{core::State, x::XConn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{state_home, Settings},
+    session::RESTARTED_VAR,
+    xdg_autostart,
+};
+
+const DEFAULT_PROGRAMS: &str = include_str!("default_autostart.toml");
+const RECORD_FILE_NAME: &str = "autostart.json";
+
+// How often a program left running by the previous window manager is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The built in programs, used unless the config file lists its own.
+pub fn default_programs() -> Vec<Program> {
+    #[derive(Deserialize)]
+    struct DefaultPrograms {
+        programs: Vec<Program>,
+    }
+
+    let defaults: DefaultPrograms =
+        toml::from_str(DEFAULT_PROGRAMS).expect("default autostart programs are valid");
+
+    defaults.programs
+}
+
+/// A program started along with the window manager.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Program {
+    /// The name the program is logged and recorded under. Defaults to the name of the executable
+    /// being run.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A shell command, run with `sh -c`.
+    pub command: String,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Only run the program once per session rather than every time the window manager starts.
+    #[serde(default = "once_by_default")]
+    pub once: bool,
+}
+
+fn once_by_default() -> bool {
+    true
+}
+
+impl Program {
+    pub fn name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => self.executable(),
+        }
+    }
+
+    /// The file name of the executable being run, ignoring any quoting.
+    pub fn executable(&self) -> &str {
+        let exe = self.command.split_whitespace().next().unwrap_or_default();
+        let exe = exe.trim_matches(|c| c == '\'' || c == '"');
+
+        exe.rsplit('/').next().unwrap_or(exe)
+    }
+}
+
+/// What to do when a program exits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Leave it: the program is a one off command or manages itself.
+    #[default]
+    Never,
+    /// Restart the program if it exits with a non-zero status or is killed by a signal.
+    OnFailure,
+    /// Restart the program whenever it exits.
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn restarts(&self, success: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnFailure => !success,
+            Self::Always => true,
+        }
+    }
+}
+
+/// How long to wait before restarting a program: `initial` after the first exit, doubling each
+/// time up to `max`. Programs that ran for at least `max` before exiting start again from
+/// `initial`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    fn next(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max)
+    }
+}
+
+// A process identified by its pid and start time (in clock ticks after boot) so that a pid
+// reused since it was recorded is not mistaken for the original process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Process {
+    pid: u32,
+    start_time: u64,
+}
+
+impl Process {
+    // None if there is no such process or it has exited and is waiting to be reaped.
+    fn of(pid: u32) -> Option<Self> {
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The command name in brackets can contain spaces so split after it
+        let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+        if fields.next()? == "Z" {
+            return None;
+        }
+        let start_time = fields.nth(18)?.parse().ok()?;
+
+        Some(Self { pid, start_time })
+    }
+
+    fn is_running(&self) -> bool {
+        Self::of(self.pid) == Some(*self)
+    }
+
+    fn terminate(&self) {
+        // SAFETY: kill has no memory safety requirements
+        unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
+// What has been started so far this session, as written to the record file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Record {
+    started: BTreeSet<String>,
+    running: BTreeMap<String, Process>,
+}
+
+impl Record {
+    fn load(path: &Path) -> Self {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                tracing::warn!(%e, ?path, "unable to read autostart record");
+                return Self::default();
+            }
+        };
+
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            tracing::warn!(%e, ?path, "invalid autostart record");
+            Self::default()
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    path: PathBuf,
+    record: Mutex<Record>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn update(&self, f: impl FnOnce(&mut Record)) {
+        let mut record = self.record.lock().unwrap();
+        f(&mut record);
+
+        let res = fs::create_dir_all(self.path.parent().unwrap_or(&self.path)).and_then(|_| {
+            let raw = serde_json::to_string(&*record)?;
+            fs::write(&self.path, raw)
+        });
+        if let Err(e) = res {
+            tracing::error!(%e, path = ?self.path, "unable to write autostart record");
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// A handle on the running autostart programs. Dropping it leaves them running.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    shared: Arc<Shared>,
+}
+
+impl Supervisor {
+    /// Start supervising `programs`, recording what was started in `record`.
+    ///
+    /// If the window manager has been `restarted`, the record left by the previous window
+    /// manager is used to work out which programs need starting.
+    pub fn start(
+        programs: &[Program],
+        backoff: Backoff,
+        restarted: bool,
+        record: impl Into<PathBuf>,
+    ) -> Self {
+        let path = record.into();
+        let previous = if restarted {
+            Record::load(&path)
+        } else {
+            Record::default()
+        };
+
+        let mut record = Record {
+            started: previous.started.clone(),
+            running: BTreeMap::new(),
+        };
+        let mut seen = BTreeSet::new();
+        let mut to_start = Vec::new();
+
+        for program in programs {
+            let name = program.name().to_string();
+            if !seen.insert(name.clone()) {
+                tracing::warn!(%name, "duplicate autostart program name: skipping");
+                continue;
+            }
+            record.started.insert(name.clone());
+
+            let running = previous
+                .running
+                .get(&name)
+                .filter(|process| process.is_running());
+
+            let adopted = match running {
+                Some(&process) if program.once => {
+                    record.running.insert(name.clone(), process);
+                    Some(process)
+                }
+                Some(process) => {
+                    tracing::info!(%name, pid = process.pid, "stopping program from the previous window manager");
+                    process.terminate();
+                    None
+                }
+                // Its exit went unseen, so for all we know it failed
+                None if program.once
+                    && previous.started.contains(&name)
+                    && !program.restart.restarts(false) =>
+                {
+                    continue;
+                }
+                None => None,
+            };
+
+            to_start.push((program.clone(), name, adopted));
+        }
+
+        let shared = Arc::new(Shared {
+            path,
+            record: Mutex::new(Record::default()),
+            stopped: AtomicBool::new(false),
+        });
+        shared.update(|r| *r = record);
+
+        for (program, name, adopted) in to_start {
+            let shared = shared.clone();
+            thread::spawn(move || supervise(program, name, adopted, backoff, shared));
+        }
+
+        Self { shared }
+    }
+
+    /// The pid of each program that is currently running.
+    pub fn running(&self) -> BTreeMap<String, u32> {
+        let record = self.shared.record.lock().unwrap();
+
+        record
+            .running
+            .iter()
+            .map(|(name, process)| (name.clone(), process.pid))
+            .collect()
+    }
+
+    /// Stop restarting programs and terminate all of those that are running.
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        for process in self.shared.record.lock().unwrap().running.values() {
+            process.terminate();
+        }
+    }
+}
+
+fn supervise(
+    program: Program,
+    name: String,
+    adopted: Option<Process>,
+    backoff: Backoff,
+    shared: Arc<Shared>,
+) {
+    if let Some(process) = adopted {
+        tracing::info!(%name, pid = process.pid, "program is still running");
+        while process.is_running() {
+            if shared.is_stopped() {
+                return;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        shared.update(|r| {
+            r.running.remove(&name);
+        });
+        // The exit status went with the previous window manager
+        tracing::warn!(%name, "program exited");
+        if !program.restart.restarts(false) {
+            return;
+        }
+    }
+
+    let mut delay = backoff.initial;
+    loop {
+        if shared.is_stopped() {
+            return;
+        }
+
+        let started = Instant::now();
+        let success = match run(&program, &name, &shared) {
+            Ok(status) if status.success() => {
+                tracing::info!(%name, "program exited");
+                true
+            }
+            Ok(status) => {
+                tracing::warn!(%name, %status, "program failed");
+                false
+            }
+            Err(e) => {
+                tracing::error!(%name, %e, "unable to start program");
+                false
+            }
+        };
+
+        if shared.is_stopped() || !program.restart.restarts(success) {
+            return;
+        }
+
+        if started.elapsed() >= backoff.max {
+            delay = backoff.initial;
+        }
+        tracing::info!(%name, ?delay, "restarting program");
+        thread::sleep(delay);
+        delay = backoff.next(delay);
+    }
+}
+
+// Penrose ignores SIGCHLD so that clients are reaped automatically, which leaves us unable to
+// wait on our own children. Programs are run in the background of a small shell that waits on
+// them instead and writes their pid and then their exit status to fd 3.
+const WRAPPER: &str = r#"sh -c "$1" 3>&- & echo $! >&3; wait $!; echo $? >&3"#;
+const STATUS_FD: libc::c_int = 3;
+
+fn run(program: &Program, name: &str, shared: &Shared) -> io::Result<ExitStatus> {
+    let (reader, writer) = io::pipe()?;
+    let status_fd = writer.as_raw_fd();
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(WRAPPER)
+        .arg("sh")
+        .arg(&program.command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // SAFETY: signal, dup2 and fcntl are async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            // Programs left running across a restart would otherwise be killed the next time
+            // they write anything, as nothing is reading their output any more.
+            libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+            // Inherited from penrose, and stops the wrapper from being able to wait
+            libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+
+            // The pipe is opened close-on-exec, which dup2 clears for the new fd
+            let res = if status_fd == STATUS_FD {
+                libc::fcntl(STATUS_FD, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(status_fd, STATUS_FD)
+            };
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    let mut child = cmd.spawn()?;
+    drop(writer);
+    if let Some(out) = child.stdout.take() {
+        log_lines(name, "stdout", out);
+    }
+    if let Some(err) = child.stderr.take() {
+        log_lines(name, "stderr", err);
+    }
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut next_number = || lines.next()?.ok()?.trim().parse::<u32>().ok();
+
+    let pid = next_number().unwrap_or(child.id());
+    tracing::info!(%name, pid, "started program");
+    if let Some(process) = Process::of(pid) {
+        shared.update(|r| {
+            r.running.insert(name.to_string(), process);
+        });
+        if shared.is_stopped() {
+            process.terminate();
+        }
+    }
+
+    let code = next_number();
+    let waited = child.wait();
+    shared.update(|r| {
+        r.running.remove(name);
+    });
+
+    match code {
+        Some(code) => Ok(ExitStatus::from_raw((code as i32) << 8)),
+        None => waited,
+    }
+}
+
+fn log_lines(name: &str, stream: &'static str, out: impl Read + Send + 'static) {
+    let name = name.to_string();
+    thread::spawn(move || {
+        for line in BufReader::new(out).lines().map_while(Result::ok) {
+            tracing::info!(program = %name, stream, "{line}");
+        }
+    });
+}
+
+/// A startup hook running the programs in the `[autostart]` section of the config file, along
+/// with any XDG autostart entries.
+pub fn autostart<X: XConn>(state: &mut State<X>, _: &X) -> penrose::Result<()> {
+    let settings = state.extension::<Settings>()?;
+    let settings = settings.borrow();
+    let backoff = Backoff {
+        initial: Duration::from_secs(settings.autostart.backoff_secs),
+        max: Duration::from_secs(settings.autostart.max_backoff_secs),
+    };
+    let restarted = std::env::var_os(RESTARTED_VAR).is_some();
+
+    let mut programs = settings.autostart.programs.clone();
+    if settings.autostart.xdg {
+        programs.extend(xdg_autostart::programs(
+            &xdg_autostart::autostart_dirs(),
+            &xdg_autostart::current_desktops(),
+            &programs,
+        ));
+    }
+
+    Supervisor::start(
+        &programs,
+        backoff,
+        restarted,
+        state_home().join(RECORD_FILE_NAME),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wait_until;
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(40),
+    };
+
+    fn program(name: &str, command: String, restart: RestartPolicy, once: bool) -> Program {
+        Program {
+            name: Some(name.to_string()),
+            command,
+            restart,
+            once,
+        }
+    }
+
+    // A command appending a line to `runs` each time it is run
+    fn counted(runs: &Path, then: &str) -> String {
+        format!("echo run >> {}; {then}", runs.display())
+    }
+
+    fn runs(path: &Path) -> usize {
+        fs::read_to_string(path).map_or(0, |s| s.lines().count())
+    }
+
+    #[test]
+    fn programs_are_restarted_according_to_their_policy() {
+        use RestartPolicy::*;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cases = [
+            ("never-fails", Never, "exit 1", false),
+            ("on-failure-succeeds", OnFailure, "exit 0", false),
+            ("on-failure-fails", OnFailure, "exit 1", true),
+            ("always-succeeds", Always, "exit 0", true),
+        ];
+        let programs: Vec<_> = cases
+            .iter()
+            .map(|&(name, restart, then, _)| {
+                program(name, counted(&dir.path().join(name), then), restart, true)
+            })
+            .collect();
+
+        let supervisor = Supervisor::start(&programs, BACKOFF, false, dir.path().join("record"));
+
+        for (name, _, _, restarts) in cases {
+            let path = dir.path().join(name);
+            if restarts {
+                assert!(wait_until(|| runs(&path) >= 3), "{name} was not restarted");
+            } else {
+                assert!(wait_until(|| runs(&path) == 1), "{name} was not run");
+            }
+        }
+
+        thread::sleep(BACKOFF.max * 3);
+        supervisor.stop();
+        for (name, _, _, restarts) in cases {
+            if !restarts {
+                assert_eq!(runs(&dir.path().join(name)), 1, "{name} was restarted");
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let delays: Vec<_> =
+            std::iter::successors(Some(BACKOFF.initial), |&d| Some(BACKOFF.next(d)))
+                .take(4)
+                .map(|d| d.as_millis())
+                .collect();
+
+        assert_eq!(delays, vec![10, 20, 40, 40]);
+    }
+
+    #[test]
+    fn programs_run_once_per_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = dir.path().join("record");
+        let (once, every_time, one_off) = (
+            dir.path().join("once"),
+            dir.path().join("every-time"),
+            dir.path().join("one-off"),
+        );
+        let programs = [
+            program(
+                "once",
+                counted(&once, "exec sleep 30"),
+                RestartPolicy::Never,
+                true,
+            ),
+            program(
+                "every-time",
+                counted(&every_time, "exec sleep 30"),
+                RestartPolicy::Never,
+                false,
+            ),
+            program(
+                "one-off",
+                counted(&one_off, "true"),
+                RestartPolicy::Never,
+                true,
+            ),
+        ];
+
+        let first = Supervisor::start(&programs, BACKOFF, false, &record);
+        assert!(wait_until(
+            || first.running().len() == 2 && runs(&one_off) == 1
+        ));
+        let before = first.running();
+
+        // A restart, leaving the first supervisor's programs running
+        let second = Supervisor::start(&programs, BACKOFF, true, &record);
+        assert!(wait_until(|| runs(&every_time) == 2));
+        assert!(wait_until(|| second.running().len() == 2));
+        let after = second.running();
+
+        assert_eq!(after["once"], before["once"]);
+        assert_ne!(after["every-time"], before["every-time"]);
+        assert!(wait_until(|| !first.running().contains_key("every-time")));
+        assert_eq!(runs(&once), 1);
+        assert_eq!(runs(&one_off), 1);
+
+        // A new session starts everything again
+        second.stop();
+        first.stop();
+        let third = Supervisor::start(&programs, BACKOFF, false, &record);
+        assert!(wait_until(|| runs(&once) == 2 && runs(&one_off) == 2));
+        third.stop();
+    }
+}
@@ -1,23 +1,127 @@
-use std::time::Duration;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use penrose::{util::spawn_for_output_with_args, x::XConn, Color};
+use penrose::{x::XConn, Color};
 use penrose_ui::{
-    bar::widgets::{
-        sys::refresh::{amixer_volume, battery_summary, current_date_and_time, wifi_network},
-        ActiveWindowName, CurrentLayout, IntervalText, Widget, Workspaces,
+    bar::{
+        widgets::{
+            sys::refresh::{amixer_volume, battery_summary, current_date_and_time, wifi_network},
+            ActiveWindowName, CurrentLayout, IntervalText, Widget, Workspaces,
+        },
+        PerScreen,
     },
     Position, StatusBar, TextStyle,
 };
 
-use crate::{BAR_HEIGHT_PX, BLACK, BLUE, FONT, GREY, MAX_ACTIVE_WINDOW_CHARS, POINT_SIZE, WHITE};
+use crate::{
+    config::{BarWidget, ScreenWidgets, Settings},
+    tray::{Measured, SystemTray, Tray, Widths},
+    weather::{self, Weather},
+};
+
+/// The built in status bar, drawn along the top of every screen using the widgets configured for
+/// that screen.
+///
+/// The bar is only built on startup: changes to `[bar]` require a restart to take effect.
+pub fn status_bar<X: XConn + 'static>(settings: &Settings) -> penrose_ui::Result<StatusBar<X>> {
+    let theme = &settings.theme;
+    let default_screens = [ScreenWidgets::default()];
+    let screens = if settings.bar.screens.is_empty() {
+        &default_screens[..]
+    } else {
+        &settings.bar.screens[..]
+    };
+
+    // Shared between screens so that the weather is only fetched once per interval
+    let weather = Arc::new(Mutex::new(Weather::new(
+        settings.weather.clone(),
+        weather::cache_path(),
+    )));
+    let mut tray_started = false;
+    let per_screen = screens
+        .iter()
+        .enumerate()
+        .map(|(i, screen)| {
+            let tray = match screen.widgets.iter().position(|&w| w == BarWidget::Tray) {
+                Some(ix) if !tray_started => {
+                    tray_started = true;
+                    system_tray(settings).map(|tray| (ix, tray))
+                }
+                _ => None,
+            };
+
+            let widgets = match tray {
+                Some((ix, tray)) => with_tray(screen, i, ix, tray, settings, &weather),
+                None => screen
+                    .widgets
+                    .iter()
+                    .map(|&w| widget(w, settings, &weather))
+                    .collect(),
+            };
+            PerScreen::new(theme.point_size, settings.bar.height_px, widgets)
+        })
+        .collect();
+
+    StatusBar::try_new_per_screen(Position::Top, theme.black, &theme.font, per_screen)
+}
+
+fn system_tray(settings: &Settings) -> Option<SystemTray> {
+    match SystemTray::try_new(None, settings.bar.height_px, settings.theme.black) {
+        Ok(tray) => Some(tray),
+        Err(e) => {
+            tracing::error!(%e, "unable to start the system tray");
+            None
+        }
+    }
+}
+
+// The widgets for screen `i`, with `tray` shown in place of the tray widget at index `ix`
+fn with_tray<X: XConn + 'static>(
+    screen: &ScreenWidgets,
+    i: usize,
+    ix: usize,
+    tray: SystemTray,
+    settings: &Settings,
+    weather: &SharedWeather,
+) -> Vec<Box<dyn Widget<X>>> {
+    let widths = Widths::default();
+    let mut widgets: Vec<Box<dyn Widget<X>>> = screen.widgets[..ix]
+        .iter()
+        .enumerate()
+        .map(|(j, &w)| -> Box<dyn Widget<X>> {
+            Box::new(Measured::new(
+                widget(w, settings, weather),
+                j,
+                widths.clone(),
+            ))
+        })
+        .collect();
+    widgets.push(Box::new(Tray::new(tray, i, widths)));
+    widgets.extend(
+        screen.widgets[ix + 1..]
+            .iter()
+            .map(|&w| widget(w, settings, weather)),
+    );
 
-pub fn status_bar<X: XConn>() -> penrose_ui::Result<StatusBar<X>> {
-    let highlight: Color = BLUE.into();
-    let empty_ws: Color = GREY.into();
+    widgets
+}
+
+type SharedWeather = Arc<Mutex<Weather>>;
+
+fn widget<X: XConn>(
+    kind: BarWidget,
+    settings: &Settings,
+    weather: &SharedWeather,
+) -> Box<dyn Widget<X>> {
+    let theme = &settings.theme;
+    let highlight: Color = theme.blue;
+    let empty_ws: Color = theme.grey;
 
     let style = TextStyle {
-        fg: WHITE.into(),
-        bg: Some(BLACK.into()),
+        fg: theme.white,
+        bg: Some(theme.black),
         padding: (2, 2),
     };
 
@@ -26,55 +130,55 @@ pub fn status_bar<X: XConn>() -> penrose_ui::Result<StatusBar<X>> {
         ..style
     };
 
-    StatusBar::try_new(
-        Position::Top,
-        BAR_HEIGHT_PX,
-        style.bg.unwrap_or_else(|| 0x000000.into()),
-        FONT,
-        POINT_SIZE,
-        vec![
-            Box::new(Empty(100, false)),
-            // Box::new(Text::new("                       ", style, false, true)),
-            Box::new(Workspaces::new(style, highlight, empty_ws)),
-            Box::new(CurrentLayout::new(style)),
-            Box::new(ActiveWindowName::new(
-                MAX_ACTIVE_WINDOW_CHARS,
-                TextStyle {
-                    bg: Some(highlight),
-                    padding: (6, 4),
-                    ..style
-                },
-                true,
-                false,
-            )),
-            Box::new(current_weather_info(padded_style)),
-            Box::new(wifi_network(padded_style)),
-            // Box::new(battery_summary("BAT1", padded_style)),
-            Box::new(battery_summary("BAT0", padded_style)),
-            Box::new(amixer_volume("Master", padded_style)),
-            Box::new(current_date_and_time(padded_style)),
-        ],
-    )
+    match kind {
+        // Only shown on the first screen listing it: see `with_tray`
+        BarWidget::Tray => Box::new(Empty(0, false)),
+        BarWidget::Workspaces => Box::new(Workspaces::new(style, highlight, empty_ws)),
+        BarWidget::Layout => Box::new(CurrentLayout::new(style)),
+        BarWidget::WindowName => Box::new(ActiveWindowName::new(
+            settings.bar.max_active_window_chars,
+            TextStyle {
+                bg: Some(highlight),
+                padding: (6, 4),
+                ..style
+            },
+            true,
+            false,
+        )),
+        BarWidget::Weather => Box::new(current_weather_info(padded_style, settings, weather)),
+        BarWidget::Wifi => Box::new(wifi_network(padded_style)),
+        BarWidget::Battery => {
+            // The bar lives for as long as the window manager so leaking the name is fine
+            let battery: &'static str = settings.bar.battery.clone().leak();
+            Box::new(battery_summary(battery, padded_style))
+        }
+        BarWidget::Volume => Box::new(amixer_volume("Master", padded_style)),
+        BarWidget::Clock => Box::new(current_date_and_time(padded_style)),
+    }
 }
 
-fn current_weather_info(style: TextStyle) -> IntervalText {
+fn current_weather_info(
+    style: TextStyle,
+    settings: &Settings,
+    weather: &SharedWeather,
+) -> IntervalText {
+    let interval = Duration::from_secs(settings.weather.interval_secs);
+    let weather = Arc::clone(weather);
+    // The widgets on other screens tick at the same interval, just slightly out of step
+    let max_age = interval / 2;
+
     IntervalText::new(
         style,
-        get_weather_text,
-        Duration::from_secs(60 * 5),
+        move || match weather.lock() {
+            Ok(mut weather) => weather.update_every(max_age),
+            Err(poisoned) => poisoned.into_inner().update_every(max_age),
+        },
+        interval,
         false,
         false,
     )
 }
 
-// Make a curl request to wttr.in to fetch the current weather information
-// for our location.
-fn get_weather_text() -> Option<String> {
-    spawn_for_output_with_args("curl", &["-s", "http://wttr.in?format=%c%t"])
-        .ok()
-        .map(|s| s.trim().to_string())
-}
-
 struct Empty(u32, bool);
 
 impl<X: XConn> Widget<X> for Empty {
@@ -105,14 +209,3 @@ impl<X: XConn> Widget<X> for Empty {
         self.1
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn get_weather_text_works() {
-        let s = get_weather_text();
-        assert!(!s.is_empty());
-    }
-}
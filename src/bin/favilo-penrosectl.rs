@@ -0,0 +1,70 @@
+//! favilo-penrosectl :: drive a running favilo-penrose from the command line
+//!
+//! Usage: `favilo-penrosectl <command> [args...]`
+//!
+//! e.g. `favilo-penrosectl move-to-tag 3`, `favilo-penrosectl key M-S-j` or
+//! `favilo-penrosectl subscribe`. Responses are printed to stdout as JSON.
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    process::ExitCode,
+};
+
+use color_eyre::eyre::{eyre, Context, Result};
+use favilo_penrose::ipc::{socket_path, Request, Response};
+
+fn main() -> Result<ExitCode> {
+    color_eyre::install()?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        print_usage();
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let request = Request::from_args(&args).map_err(|e| eyre!(e))?;
+
+    let path = socket_path().context("unable to find the control socket")?;
+    let mut stream = UnixStream::connect(&path)
+        .with_context(|| format!("unable to connect to {}", path.display()))?;
+    writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    if request == Request::Subscribe {
+        let mut stdout = std::io::stdout();
+        for line in lines {
+            writeln!(stdout, "{}", line?)?;
+            stdout.flush()?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let line = lines
+        .next()
+        .ok_or_else(|| eyre!("connection closed without a response"))??;
+    println!("{line}");
+
+    match serde_json::from_str(&line)? {
+        Response::Error { .. } => Ok(ExitCode::FAILURE),
+        _ => Ok(ExitCode::SUCCESS),
+    }
+}
+
+fn print_usage() {
+    println!(
+        "usage: favilo-penrosectl <command> [args...]
+
+commands:
+  focus up|down              move focus within the current workspace
+  focus-tag <tag>            bring a tag to the screen under the pointer
+  move-to-tag <tag>          move the focused window to a tag
+  set-layout <name>          switch the current workspace to the named layout
+  layout-message <message>   inc-main <n>, expand-main or shrink-main
+  spawn <command...>         run a program
+  reload                     reload the config file
+  action <action...>         run any action that can be bound in the [keys] config table
+  key <chord>                run whatever is bound to a key chord, e.g. M-S-j
+  query-state                print the current state
+  subscribe                  print the current state and then every change to it"
+    );
+}
@@ -1,17 +1,179 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
 
 use penrose::{
     builtin::{
-        actions::{
-            exit, floating::sink_focused, key_handler, modify_with, send_layout_message, spawn,
-        },
+        actions::{floating::sink_focused, key_handler, modify_with, spawn},
         layout::messages::{ExpandMain, IncMain, ShrinkMain},
     },
-    core::{bindings::KeyEventHandler, State},
-    map, util,
-    x::XConnExt,
+    core::{
+        bindings::{
+            parse_keybindings_with_xmodmap, KeyBindings, KeyCode, KeyEventHandler, MouseState,
+        },
+        ClientSet, State,
+    },
+    map,
+    pure::geometry::Point,
+    util,
+    x::{XConn, XConnExt, XEvent},
     x11rb::RustConn,
 };
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Settings,
+    idle::toggle_idle_inhibit,
+    layouts::LayoutParams,
+    mouse::{screen_at, MouseHandler},
+    power::{show_power_menu, PowerAction},
+    reload::reload,
+    session::save_and_exit,
+    sleep::{suspend, SleepMode},
+};
+
+/// The built in key bindings merged with any bindings from the `[keys]` table of the user's
+/// config file. Bindings from the config file take precedence over the built in ones.
+pub fn key_bindings(settings: &Settings) -> HashMap<String, Box<dyn KeyEventHandler<RustConn>>> {
+    let mut bindings: HashMap<String, Box<dyn KeyEventHandler<RustConn>>> = raw_key_bindings()
+        .into_iter()
+        .map(|(chord, handler)| (normalize_chord(&chord), handler))
+        .collect();
+
+    for (chord, action) in settings.keys.iter() {
+        let action = action.as_ref().clone();
+        tracing::debug!(%chord, %action, "binding key from config");
+        bindings.insert(normalize_chord(chord), action.into_handler());
+    }
+
+    bindings
+}
+
+/// The active key bindings, stored as a [State] extension rather than being handed to the
+/// [WindowManager][penrose::core::WindowManager] so that they can be replaced when the config
+/// file is reloaded.
+pub struct DynamicKeyBindings {
+    bindings: KeyBindings<RustConn>,
+    mouse_states: Vec<MouseState>,
+    generation: usize,
+}
+
+impl fmt::Debug for DynamicKeyBindings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynamicKeyBindings")
+            .field("bindings", &self.bindings.len())
+            .field("mouse_states", &self.mouse_states)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl DynamicKeyBindings {
+    /// Parse the key bindings for the given settings. The mouse states are needed so that they
+    /// can be re-grabbed along with the keys.
+    pub fn try_new(settings: &Settings, mouse_states: Vec<MouseState>) -> penrose::Result<Self> {
+        Ok(Self {
+            bindings: parse_keybindings_with_xmodmap(key_bindings(settings))?,
+            mouse_states,
+            generation: 0,
+        })
+    }
+
+    /// Replace the current bindings with those for the given settings.
+    ///
+    /// The existing bindings are left in place if the new ones fail to parse.
+    pub fn update(&mut self, settings: &Settings) -> penrose::Result<()> {
+        self.bindings = parse_keybindings_with_xmodmap(key_bindings(settings))?;
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    /// Grab the current key bindings (and mouse states) from the X server.
+    pub fn grab<X: XConn>(&self, x: &X) -> penrose::Result<()> {
+        let key_codes: Vec<_> = self.bindings.keys().copied().collect();
+        x.grab(&key_codes, &self.mouse_states)
+    }
+}
+
+/// A startup hook grabbing the [DynamicKeyBindings].
+pub fn grab_key_bindings(state: &mut State<RustConn>, x: &RustConn) -> penrose::Result<()> {
+    state.extension::<DynamicKeyBindings>()?.borrow().grab(x)
+}
+
+/// Run the handler bound to the given [KeyCode] in the [DynamicKeyBindings], returning whether or
+/// not anything was bound to it.
+pub fn run_key_binding(
+    code: KeyCode,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<bool> {
+    let dynamic = state.extension::<DynamicKeyBindings>()?;
+
+    // The handler is taken out for the duration of the call so that it is free to borrow the
+    // extension itself (reloading the config replaces every binding).
+    let (handler, generation) = {
+        let mut d = dynamic.borrow_mut();
+        (d.bindings.remove(&code), d.generation)
+    };
+
+    let mut handler = match handler {
+        Some(handler) => handler,
+        None => return Ok(false),
+    };
+
+    let res = handler.call(state, x);
+    let mut d = dynamic.borrow_mut();
+    if d.generation == generation {
+        d.bindings.insert(code, handler);
+    }
+
+    res.map(|_| true)
+}
+
+/// Look up the [KeyCode] for a single key chord such as `M-S-j`.
+pub fn parse_chord(chord: &str) -> penrose::Result<KeyCode> {
+    let noop: Box<dyn KeyEventHandler<RustConn>> = key_handler(|_, _| Ok(()));
+    let parsed = parse_keybindings_with_xmodmap(HashMap::from([(chord, noop)]))?;
+
+    Ok(*parsed
+        .keys()
+        .next()
+        .expect("one binding to have been parsed"))
+}
+
+/// An event hook running the [DynamicKeyBindings] in place of the default key press handling.
+///
+/// Mapping changes are handled here as well: the default handling would re-grab the (empty) set
+/// of key bindings given to the window manager itself.
+pub fn key_bindings_event_hook(
+    event: &XEvent,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<bool> {
+    let dynamic = state.extension::<DynamicKeyBindings>()?;
+
+    match event {
+        XEvent::KeyPress(code) => {
+            if let Err(e) = run_key_binding(*code, state, x) {
+                tracing::error!(%e, ?code, "error running user keybinding");
+            }
+
+            Ok(false)
+        }
+
+        XEvent::MappingNotify => {
+            let settings = state.extension::<Settings>()?.borrow().clone();
+            let mut d = dynamic.borrow_mut();
+            if let Err(e) = d.update(&settings) {
+                tracing::error!(%e, "unable to re-parse key bindings after mapping change");
+            }
+            d.grab(x)?;
+
+            Ok(false)
+        }
+
+        _ => Ok(true),
+    }
+}
 
 pub fn raw_key_bindings() -> HashMap<String, Box<dyn KeyEventHandler<RustConn>>> {
     let mut raw_bindings = map! {
@@ -29,24 +191,22 @@ pub fn raw_key_bindings() -> HashMap<String, Box<dyn KeyEventHandler<RustConn>>>
         "M-bracketleft" => modify_with(|cs| cs.previous_screen()),
         "M-grave" => modify_with(|cs| cs.next_layout()),
         "M-S-grave" => modify_with(|cs| cs.previous_layout()),
-        "M-S-comma" => send_layout_message(|| IncMain(1)),
-        "M-S-period" => send_layout_message(|| IncMain(-1)),
-        "M-S-Up" => send_layout_message(|| IncMain(1)),
-        "M-S-Down" => send_layout_message(|| IncMain(-1)),
-        "M-S-Right" => send_layout_message(|| ExpandMain),
-        "M-S-Left" => send_layout_message(|| ShrinkMain),
-        "M-S-z" => key_handler(|_, _| {
-            util::spawn("i3lock")?;
-            util::spawn("systemctl suspend-then-hibernate")?;
-            Ok(())
-        }),
+        "M-S-comma" => layout_message(LayoutMessage::IncMain(1)),
+        "M-S-period" => layout_message(LayoutMessage::IncMain(-1)),
+        "M-S-Up" => layout_message(LayoutMessage::IncMain(1)),
+        "M-S-Down" => layout_message(LayoutMessage::IncMain(-1)),
+        "M-S-Right" => layout_message(LayoutMessage::ExpandMain),
+        "M-S-Left" => layout_message(LayoutMessage::ShrinkMain),
+        "M-S-z" => key_handler(|state, _| suspend(state, SleepMode::SuspendThenHibernate)),
         "M-r" => spawn("dmenu_run"),
         "M-Return" => spawn("kitty"),
 
         // Restart the WM because we want to run inside a wrapper script
-        "M-q" => exit(),
-        // Kill the WM
-        "M-S-q" => spawn("pkill -fi penrose"),
+        "M-q" => save_and_exit(),
+        // Re-read the config file without restarting
+        "M-S-r" => key_handler(reload),
+        // Lock, suspend, log out, reboot or shut down
+        "M-S-q" => key_handler(show_power_menu),
 
         // Volume control
         "XF86AudioRaiseVolume" => spawn("pactl set-sink-volume @DEFAULT_SINK@ +5%"),
@@ -66,30 +226,8 @@ pub fn raw_key_bindings() -> HashMap<String, Box<dyn KeyEventHandler<RustConn>>>
     };
 
     for tag in &["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"] {
-        let focus_tag: Box<dyn KeyEventHandler<RustConn>> = Box::new(
-            move |state: &mut State<RustConn>, x: &RustConn| -> penrose::Result<()> {
-                // let current_mouse = state
-                //     .extension::<MouseHandler>()?
-                //     .borrow()
-                //     .current_mouse_position();
-                let client_set = &mut state.client_set;
-                // let screen = client_set
-                //     .screens()
-                //     .find(|s| s.geometry().contains_point(current_mouse));
-                let screen = None::<&penrose::pure::Screen<RustConn>>;
-                match screen {
-                    // TODO: Lets add some methods to the penrose crate
-                    Some(_screen) => todo!("We need to implement this in the penrose crate"),
-                    None => {
-                        client_set.pull_tag_to_screen(tag);
-                    }
-                };
-
-                x.refresh(state)
-            },
-        );
         raw_bindings.extend([
-            (format!("M-{tag}"), focus_tag),
+            (format!("M-{tag}"), pull_tag(tag.to_string())),
             (
                 format!("M-S-{tag}"),
                 modify_with(move |client_set| client_set.move_focused_to_tag(tag)),
@@ -99,3 +237,357 @@ pub fn raw_key_bindings() -> HashMap<String, Box<dyn KeyEventHandler<RustConn>>>
 
     raw_bindings
 }
+
+/// Bring the workspace for the given tag to the screen under the mouse pointer, swapping it
+/// with whatever was there if it is already visible on another screen.
+pub fn pull_tag(tag: String) -> Box<dyn KeyEventHandler<RustConn>> {
+    Box::new(
+        move |state: &mut State<RustConn>, x: &RustConn| -> penrose::Result<()> {
+            let pointer = state.extension::<MouseHandler>()?.borrow_mut().refresh(x);
+            pull_tag_to_point(&mut state.client_set, &tag, pointer);
+
+            x.refresh(state)
+        },
+    )
+}
+
+/// Focus the screen containing `point` and pull the given tag to it. If the point is unknown or
+/// falls outside of every screen then the tag is pulled to the focused screen instead.
+pub fn pull_tag_to_point(client_set: &mut ClientSet, tag: &str, point: Option<Point>) {
+    if let Some(index) = point.and_then(|p| screen_at(client_set, p)) {
+        client_set.focus_screen(index);
+    }
+
+    client_set.pull_tag_to_screen(tag);
+}
+
+/// Put the modifiers of a key chord in a canonical order so that `S-M-j` and `M-S-j` are treated
+/// as the same binding.
+pub fn normalize_chord(chord: &str) -> String {
+    let mut parts: Vec<&str> = chord.split('-').collect();
+    let key = parts.pop().unwrap_or_default();
+    parts.sort_by_key(|m| ["C", "A", "S", "M"].iter().position(|o| o == m));
+    parts.dedup();
+    parts.push(key);
+
+    parts.join("-")
+}
+
+/// An action that can be bound to a key chord from the `[keys]` table of the config file.
+///
+/// Actions are written as a name followed by any arguments, e.g. `"spawn kitty"`,
+/// `"move-to-tag 3"` or `"layout-message expand-main"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum KeyAction {
+    Spawn(String),
+    FocusUp,
+    FocusDown,
+    SwapUp,
+    SwapDown,
+    FocusTag(String),
+    MoveToTag(String),
+    LayoutMessage(LayoutMessage),
+    NextLayout,
+    PreviousLayout,
+    SetLayout(String),
+    Kill,
+    Reload,
+    Exit,
+    ToggleIdleInhibit,
+    PowerMenu,
+    Power(PowerAction),
+}
+
+/// The layout messages that can be sent to the active layout from a [KeyAction].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LayoutMessage {
+    IncMain(i8),
+    ExpandMain,
+    ShrinkMain,
+}
+
+impl KeyAction {
+    /// Convert this action into a handler that can be used in a key binding map.
+    pub fn into_handler(self) -> Box<dyn KeyEventHandler<RustConn>> {
+        match self {
+            Self::Spawn(cmd) => key_handler(move |_, _| util::spawn(cmd.as_str())),
+            Self::FocusUp => modify_with(|cs| cs.focus_up()),
+            Self::FocusDown => modify_with(|cs| cs.focus_down()),
+            Self::SwapUp => modify_with(|cs| cs.swap_up()),
+            Self::SwapDown => modify_with(|cs| cs.swap_down()),
+            Self::FocusTag(tag) => pull_tag(tag),
+            Self::MoveToTag(tag) => modify_with(move |cs| cs.move_focused_to_tag(&tag)),
+            Self::LayoutMessage(msg) => layout_message(msg),
+            Self::NextLayout => modify_with(|cs| cs.next_layout()),
+            Self::PreviousLayout => modify_with(|cs| cs.previous_layout()),
+            Self::SetLayout(name) => {
+                modify_with(move |cs| cs.current_workspace_mut().set_layout_by_name(&name))
+            }
+            Self::Kill => modify_with(|cs| cs.kill_focused()),
+            Self::Reload => key_handler(reload),
+            Self::Exit => save_and_exit(),
+            Self::ToggleIdleInhibit => key_handler(toggle_idle_inhibit),
+            Self::PowerMenu => key_handler(show_power_menu),
+            Self::Power(action) => key_handler(move |state, x| action.run(state, x)),
+        }
+    }
+}
+
+/// Send a message to the active layout, recording the change in the [LayoutParams] extension so
+/// that it can be restored after a restart.
+pub fn layout_message(msg: LayoutMessage) -> Box<dyn KeyEventHandler<RustConn>> {
+    key_handler(move |state: &mut State<RustConn>, x: &RustConn| {
+        let settings = state.extension::<Settings>()?.borrow().layout;
+        let ws = state.client_set.current_workspace();
+        state.extension::<LayoutParams>()?.borrow_mut().record(
+            ws.tag(),
+            &ws.layout_name(),
+            msg,
+            &settings,
+        );
+
+        x.modify_and_refresh(state, |cs| {
+            let ws = cs.current_workspace_mut();
+            match msg {
+                LayoutMessage::IncMain(n) => ws.handle_message(IncMain(n)),
+                LayoutMessage::ExpandMain => ws.handle_message(ExpandMain),
+                LayoutMessage::ShrinkMain => ws.handle_message(ShrinkMain),
+            }
+        })
+    })
+}
+
+impl FromStr for KeyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, args) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let args = args.trim();
+
+        let no_args = |action: KeyAction| {
+            if args.is_empty() {
+                Ok(action)
+            } else {
+                Err(format!(
+                    "'{name}' does not take any arguments: got '{args}'"
+                ))
+            }
+        };
+        let required = |what: &str| {
+            if args.is_empty() {
+                Err(format!("'{name}' requires {what}"))
+            } else {
+                Ok(args.to_string())
+            }
+        };
+
+        match name {
+            "spawn" => required("a command to run").map(Self::Spawn),
+            "focus-up" => no_args(Self::FocusUp),
+            "focus-down" => no_args(Self::FocusDown),
+            "swap-up" => no_args(Self::SwapUp),
+            "swap-down" => no_args(Self::SwapDown),
+            "focus-tag" => required("a tag").map(Self::FocusTag),
+            "move-to-tag" => required("a tag").map(Self::MoveToTag),
+            "layout-message" => required("a message")?.parse().map(Self::LayoutMessage),
+            "next-layout" => no_args(Self::NextLayout),
+            "previous-layout" => no_args(Self::PreviousLayout),
+            "set-layout" => required("a layout name").map(Self::SetLayout),
+            "kill" => no_args(Self::Kill),
+            "reload" => no_args(Self::Reload),
+            "exit" => no_args(Self::Exit),
+            "toggle-idle-inhibit" => no_args(Self::ToggleIdleInhibit),
+            "power-menu" => no_args(Self::PowerMenu),
+            "power" => required("an action")?.parse().map(Self::Power),
+            _ => Err(format!(
+                "unknown action '{name}': expected one of spawn, focus-up, focus-down, swap-up, \
+                 swap-down, focus-tag, move-to-tag, layout-message, next-layout, previous-layout, \
+                 set-layout, kill, reload, exit, toggle-idle-inhibit, power-menu, power"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for KeyAction {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<KeyAction> for String {
+    fn from(action: KeyAction) -> Self {
+        action.to_string()
+    }
+}
+
+impl fmt::Display for KeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn(cmd) => write!(f, "spawn {cmd}"),
+            Self::FocusUp => write!(f, "focus-up"),
+            Self::FocusDown => write!(f, "focus-down"),
+            Self::SwapUp => write!(f, "swap-up"),
+            Self::SwapDown => write!(f, "swap-down"),
+            Self::FocusTag(tag) => write!(f, "focus-tag {tag}"),
+            Self::MoveToTag(tag) => write!(f, "move-to-tag {tag}"),
+            Self::LayoutMessage(m) => write!(f, "layout-message {m}"),
+            Self::NextLayout => write!(f, "next-layout"),
+            Self::PreviousLayout => write!(f, "previous-layout"),
+            Self::SetLayout(name) => write!(f, "set-layout {name}"),
+            Self::Kill => write!(f, "kill"),
+            Self::Reload => write!(f, "reload"),
+            Self::Exit => write!(f, "exit"),
+            Self::ToggleIdleInhibit => write!(f, "toggle-idle-inhibit"),
+            Self::PowerMenu => write!(f, "power-menu"),
+            Self::Power(action) => write!(f, "power {action}"),
+        }
+    }
+}
+
+impl FromStr for LayoutMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["expand-main"] => Ok(Self::ExpandMain),
+            ["shrink-main"] => Ok(Self::ShrinkMain),
+            ["inc-main", n] => n
+                .parse()
+                .map(Self::IncMain)
+                .map_err(|_| format!("inc-main expects an integer: got '{n}'")),
+            _ => Err(format!(
+                "unknown layout message '{s}': expected one of inc-main <n>, expand-main, shrink-main"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for LayoutMessage {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<LayoutMessage> for String {
+    fn from(msg: LayoutMessage) -> Self {
+        msg.to_string()
+    }
+}
+
+impl fmt::Display for LayoutMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncMain(n) => write!(f, "inc-main {n}"),
+            Self::ExpandMain => write!(f, "expand-main"),
+            Self::ShrinkMain => write!(f, "shrink-main"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layouts::layouts;
+    use penrose::pure::{geometry::Rect, StackSet};
+
+    #[test]
+    fn actions_round_trip_through_display() {
+        let actions = [
+            KeyAction::Spawn("light -A 5".to_string()),
+            KeyAction::FocusUp,
+            KeyAction::SwapDown,
+            KeyAction::FocusTag("2".to_string()),
+            KeyAction::MoveToTag("3".to_string()),
+            KeyAction::LayoutMessage(LayoutMessage::IncMain(-1)),
+            KeyAction::LayoutMessage(LayoutMessage::ExpandMain),
+            KeyAction::PreviousLayout,
+            KeyAction::SetLayout("Mono".to_string()),
+            KeyAction::Reload,
+            KeyAction::Exit,
+            KeyAction::ToggleIdleInhibit,
+            KeyAction::PowerMenu,
+            KeyAction::Power(PowerAction::SuspendThenHibernate),
+        ];
+
+        for action in actions {
+            assert_eq!(action.to_string().parse(), Ok(action));
+        }
+    }
+
+    #[test]
+    fn invalid_actions_are_rejected() {
+        assert!("launch kitty".parse::<KeyAction>().is_err());
+        assert!("spawn".parse::<KeyAction>().is_err());
+        assert!("kill now".parse::<KeyAction>().is_err());
+        assert!("layout-message inc-main lots".parse::<KeyAction>().is_err());
+    }
+
+    #[test]
+    fn tags_are_pulled_to_the_screen_under_the_pointer() {
+        let screens = [
+            Rect::new(0, 0, 1920, 1080),
+            Rect::new(1920, 0, 2560, 1440),
+            Rect::new(4480, 0, 1920, 1080),
+        ];
+        let mut cs: ClientSet = StackSet::try_new(
+            layouts(&Settings::default()),
+            ["1", "2", "3", "4", "5"],
+            screens,
+        )
+        .unwrap();
+        let visible = |cs: &ClientSet| {
+            cs.screens()
+                .map(|s| s.workspace.tag().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // A hidden tag replaces the one on the screen under the pointer
+        pull_tag_to_point(&mut cs, "4", Some(Point::new(2000, 700)));
+        assert_eq!(visible(&cs), vec!["1", "4", "3"]);
+        assert_eq!(cs.current_screen().index(), 1);
+
+        // A tag visible elsewhere swaps places with it
+        pull_tag_to_point(&mut cs, "1", Some(Point::new(5000, 10)));
+        assert_eq!(visible(&cs), vec!["3", "4", "1"]);
+        assert_eq!(cs.current_screen().index(), 2);
+
+        // Without a pointer position the focused screen is used
+        pull_tag_to_point(&mut cs, "5", None);
+        pull_tag_to_point(&mut cs, "2", Some(Point::new(-100, -100)));
+        assert_eq!(visible(&cs), vec!["3", "4", "2"]);
+        assert_eq!(cs.current_tag(), "2");
+    }
+
+    #[test]
+    fn chords_are_normalized() {
+        assert_eq!(normalize_chord("S-M-j"), "S-M-j");
+        assert_eq!(normalize_chord("M-S-j"), "S-M-j");
+        assert_eq!(normalize_chord("M-C-S-Return"), "C-S-M-Return");
+        assert_eq!(normalize_chord("XF86AudioMute"), "XF86AudioMute");
+    }
+
+    #[test]
+    fn config_bindings_override_and_extend_the_defaults() {
+        let settings = Settings::from_toml_str(
+            r#"
+            [keys]
+            "S-M-Return" = "spawn alacritty"
+            "M-Return" = "spawn alacritty"
+            "#,
+        )
+        .unwrap();
+
+        let defaults = raw_key_bindings();
+        let bindings = key_bindings(&settings);
+
+        assert_eq!(bindings.len(), defaults.len() + 1);
+        assert!(bindings.contains_key("S-M-Return"));
+    }
+}
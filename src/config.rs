@@ -0,0 +1,649 @@
+//! User settings loaded from `$XDG_CONFIG_HOME/penrose/config.toml`, falling back to the
+//! gruvbox defaults for anything that is missing.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, eyre, Context, Result};
+use penrose::Color;
+use serde::{Deserialize, Deserializer};
+use toml::Spanned;
+
+use crate::{
+    autostart::{default_programs, Program},
+    bindings::{normalize_chord, KeyAction},
+    idle::{default_inhibitors, default_stages, IdleStage},
+    rules::{Matcher, Rule},
+};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// The top level settings for the window manager.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub theme: Theme,
+    pub bar: BarSettings,
+    pub layout: LayoutSettings,
+    pub weather: WeatherSettings,
+    pub notifications: NotificationSettings,
+    pub dialogs: DialogSettings,
+    pub autostart: AutostartSettings,
+    pub idle: IdleSettings,
+    pub lock: LockSettings,
+    /// Key chords mapped to actions, merged with the built in key bindings.
+    pub keys: BTreeMap<String, Spanned<KeyAction>>,
+    /// Whether the built in window rules should run before those in `rules`.
+    pub default_rules: bool,
+    /// Window rules applied to newly managed windows.
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            bar: BarSettings::default(),
+            layout: LayoutSettings::default(),
+            weather: WeatherSettings::default(),
+            notifications: NotificationSettings::default(),
+            dialogs: DialogSettings::default(),
+            autostart: AutostartSettings::default(),
+            idle: IdleSettings::default(),
+            lock: LockSettings::default(),
+            keys: BTreeMap::new(),
+            default_rules: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Colours and font used when drawing the status bar and window borders.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub font: String,
+    pub point_size: u8,
+    #[serde(deserialize_with = "hex_color")]
+    pub black: Color,
+    #[serde(deserialize_with = "hex_color")]
+    pub white: Color,
+    #[serde(deserialize_with = "hex_color")]
+    pub grey: Color,
+    #[serde(deserialize_with = "hex_color")]
+    pub blue: Color,
+    #[serde(deserialize_with = "hex_color")]
+    pub normal_border: Color,
+    #[serde(deserialize_with = "hex_color")]
+    pub focused_border: Color,
+    pub border_width: u32,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            font: "ProFontIIx Nerd Font".to_string(),
+            point_size: 10,
+            black: 0x282828ff.into(),
+            white: 0xebdbb2ff.into(),
+            grey: 0x3c3836ff.into(),
+            blue: 0x458588ff.into(),
+            normal_border: 0x3c3836ff.into(),
+            focused_border: 0xcc241dff.into(),
+            border_width: 2,
+        }
+    }
+}
+
+/// The status bar. Space for it is reserved at the top of every screen whether the built in
+/// bar is enabled or an external one (e.g. polybar) is being used instead.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BarSettings {
+    /// Whether to draw the built in status bar.
+    pub enabled: bool,
+    pub height_px: u32,
+    pub max_active_window_chars: usize,
+    /// The battery shown by the `battery` widget, as named under `/sys/class/power_supply`.
+    pub battery: String,
+    /// The widgets to show on each screen, by screen index. Screens past the end of the list use
+    /// the last entry.
+    pub screens: Vec<ScreenWidgets>,
+}
+
+impl Default for BarSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            height_px: 28,
+            max_active_window_chars: 50,
+            battery: "BAT0".to_string(),
+            screens: vec![ScreenWidgets::default()],
+        }
+    }
+}
+
+/// The widgets shown in the status bar on a single screen, from left to right.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScreenWidgets {
+    pub widgets: Vec<BarWidget>,
+}
+
+impl Default for ScreenWidgets {
+    fn default() -> Self {
+        use BarWidget::*;
+
+        Self {
+            widgets: vec![
+                Tray, Workspaces, Layout, WindowName, Weather, Wifi, Battery, Volume, Clock,
+            ],
+        }
+    }
+}
+
+/// The widgets available for the status bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BarWidget {
+    /// The system tray. Only the first screen listing it gets one.
+    #[serde(alias = "tray-gap")]
+    Tray,
+    Workspaces,
+    Layout,
+    WindowName,
+    Weather,
+    Wifi,
+    Battery,
+    Volume,
+    Clock,
+}
+
+/// Parameters shared by all of the tiling layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutSettings {
+    pub max_main: u32,
+    pub ratio: f32,
+    pub ratio_step: f32,
+    pub outer_px: u32,
+    pub inner_px: u32,
+    /// Shrink tiled clients to a whole number of their resize increments (e.g. the character
+    /// cells of a terminal), centring them in the space left over.
+    pub respect_size_hints: bool,
+}
+
+impl Default for LayoutSettings {
+    fn default() -> Self {
+        Self {
+            max_main: 1,
+            ratio: 0.6,
+            ratio_step: 0.1,
+            outer_px: 0,
+            inner_px: 0,
+            respect_size_hints: false,
+        }
+    }
+}
+
+/// Where the `weather` bar widget gets its readings from.
+///
+/// Readings are fetched from a [wttr.in](https://github.com/chubin/wttr.in) compatible
+/// endpoint. Like the rest of the bar, changes here require a restart to take effect.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherSettings {
+    pub endpoint: String,
+    /// A city, airport code or `lat,lon` pair. Left empty, the location is guessed from the
+    /// public IP address making the request.
+    pub location: String,
+    pub units: WeatherUnits,
+    /// A wttr.in one line format string, e.g. `%c%t` for the condition icon and temperature.
+    pub format: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// The number of failed fetches in a row after which the last good reading is shown with
+    /// `stale_marker` appended.
+    pub stale_after: u32,
+    pub stale_marker: String,
+}
+
+impl Default for WeatherSettings {
+    fn default() -> Self {
+        Self {
+            endpoint: "https://wttr.in".to_string(),
+            location: String::new(),
+            units: WeatherUnits::Auto,
+            format: "%c%t".to_string(),
+            interval_secs: 5 * 60,
+            timeout_secs: 10,
+            stale_after: 3,
+            stale_marker: "?".to_string(),
+        }
+    }
+}
+
+/// Units used for weather readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WeatherUnits {
+    /// Whatever is usual for the location being reported on
+    Auto,
+    Metric,
+    Imperial,
+}
+
+/// Where notification windows are stacked.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationSettings {
+    pub corner: Corner,
+    /// The gap left between notifications and between them and the edge of the screen.
+    pub padding_px: u32,
+    /// The index of the screen to show notifications on. Left unset, they are shown on the
+    /// focused screen.
+    pub screen: Option<usize>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            corner: Corner::TopRight,
+            padding_px: 10,
+            screen: None,
+        }
+    }
+}
+
+/// How dialogs, splash screens, utility windows and transient windows are placed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialogSettings {
+    /// Float these windows centred over the window they belong to. Turning this off leaves
+    /// them to penrose, which floats them in the middle of the screen, and the window rules.
+    pub float: bool,
+    /// Classes (the second string of `WM_CLASS`) whose dialogs are tiled instead.
+    pub tiled_classes: Vec<String>,
+}
+
+impl Default for DialogSettings {
+    fn default() -> Self {
+        Self {
+            float: true,
+            tiled_classes: Vec::new(),
+        }
+    }
+}
+
+/// The programs started along with the window manager, see [crate::autostart].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutostartSettings {
+    /// How long to wait before restarting a program that has exited, doubling each time it
+    /// exits again up to `max_backoff_secs`.
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /// Left out, the built in programs are started. Changes need a restart to take effect.
+    pub programs: Vec<Program>,
+    /// Also start the applications with an XDG autostart entry, see [crate::xdg_autostart].
+    pub xdg: bool,
+}
+
+impl Default for AutostartSettings {
+    fn default() -> Self {
+        Self {
+            backoff_secs: 1,
+            max_backoff_secs: 60,
+            programs: default_programs(),
+            xdg: true,
+        }
+    }
+}
+
+/// What to do once the user has been idle for a while, see [crate::idle].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleSettings {
+    pub enabled: bool,
+    /// Run in order of `after_secs`, each at most once per idle period.
+    pub stages: Vec<IdleStage>,
+    /// Hold off the stages while the focused window is fullscreen.
+    pub inhibit_when_fullscreen: bool,
+    /// Hold off the stages while a window matching any of these exists.
+    pub inhibit_when: Vec<Matcher>,
+}
+
+impl Default for IdleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stages: default_stages(),
+            inhibit_when_fullscreen: true,
+            inhibit_when: default_inhibitors(),
+        }
+    }
+}
+
+/// How the screen is locked.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockSettings {
+    /// A shell command that keeps running until the screen is unlocked.
+    pub command: String,
+    /// The class (the second string of `WM_CLASS`) of the window shown by `command`.
+    pub window_class: String,
+    /// Lock the screen whenever the machine goes to sleep, see [crate::sleep]. Changes need a
+    /// restart to take effect.
+    pub before_sleep: bool,
+}
+
+impl Default for LockSettings {
+    fn default() -> Self {
+        Self {
+            command: "i3lock -n".to_string(),
+            window_class: "i3lock".to_string(),
+            before_sleep: true,
+        }
+    }
+}
+
+/// A corner of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Settings {
+    /// The default location of the config file: `$XDG_CONFIG_HOME/penrose/config.toml`.
+    pub fn path() -> PathBuf {
+        config_home().join(CONFIG_FILE_NAME)
+    }
+
+    /// Load settings from the default config file location, falling back to the defaults if
+    /// there is no config file present.
+    pub fn load() -> Result<Self> {
+        Self::load_from(Self::path())
+    }
+
+    /// Load settings from the given path, falling back to the defaults if the file does not
+    /// exist.
+    pub fn load_from(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            tracing::info!(?path, "no config file found: using default settings");
+            return Ok(Self::default());
+        }
+
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config file {}", path.display()))?;
+
+        Self::from_toml_str(&raw).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Parse settings from a TOML string.
+    ///
+    /// Errors name the key that could not be parsed.
+    pub fn from_toml_str(raw: &str) -> Result<Self> {
+        let de = toml::Deserializer::parse(raw).map_err(|e| eyre!("{e}"))?;
+
+        let settings: Self = serde_path_to_error::deserialize(de).map_err(|e| {
+            let key = key_path(e.path());
+            eyre!("invalid value for `{key}`: {}", e.into_inner())
+        })?;
+        settings.check_for_duplicate_keys(raw)?;
+
+        Ok(settings)
+    }
+
+    // TOML already rejects keys that are written identically so we only need to check for
+    // chords that differ in the order of their modifiers.
+    fn check_for_duplicate_keys(&self, raw: &str) -> Result<()> {
+        let mut seen: HashMap<String, (&str, usize)> = HashMap::new();
+
+        for (chord, action) in self.keys.iter() {
+            let line = line_number(raw, action.span().start);
+            if let Some((prev, prev_line)) = seen.insert(normalize_chord(chord), (chord, line)) {
+                bail!(
+                    "duplicate key binding `{chord}` on line {line}: already bound as `{prev}` on line {prev_line}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Render a deserialization path as it would be written in TOML, skipping the internal
+// segments introduced by `Spanned` values.
+fn key_path(path: &serde_path_to_error::Path) -> String {
+    use serde_path_to_error::Segment;
+
+    let mut key = String::new();
+    for seg in path.iter() {
+        match seg {
+            Segment::Seq { index } => key.push_str(&format!("[{index}]")),
+            Segment::Map { key: k } | Segment::Enum { variant: k } => {
+                if k.starts_with("$__serde_spanned") {
+                    continue;
+                }
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(k);
+            }
+            Segment::Unknown => key.push_str(".?"),
+        }
+    }
+
+    key
+}
+
+fn line_number(raw: &str, offset: usize) -> usize {
+    raw[..offset].lines().count().max(1)
+}
+
+/// The directory holding all penrose related user files: `$XDG_CONFIG_HOME/penrose`.
+pub fn config_home() -> PathBuf {
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .unwrap_or_else(|_| format!("{}/.config", std::env::var("HOME").unwrap()));
+
+    PathBuf::from(config_home).join("penrose")
+}
+
+/// The directory holding penrose state that should survive a restart:
+/// `$XDG_STATE_HOME/penrose`.
+pub fn state_home() -> PathBuf {
+    let state_home = std::env::var("XDG_STATE_HOME")
+        .unwrap_or_else(|_| format!("{}/.local/state", std::env::var("HOME").unwrap()));
+
+    PathBuf::from(state_home).join("penrose")
+}
+
+fn hex_color<'de, D>(deserializer: D) -> std::result::Result<Color, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+
+    Color::try_from(s.as_str()).map_err(|_| {
+        serde::de::Error::custom(format!(
+            "expected a '#RRGGBB' or '#RRGGBBAA' hex colour, got '{s}'"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idle::IdleAction;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let settings = Settings::from_toml_str("").unwrap();
+
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn partial_config_overrides_only_given_keys() {
+        let settings = Settings::from_toml_str(
+            r##"
+            [theme]
+            blue = "#83a598"
+
+            [bar]
+            height_px = 32
+            "##,
+        )
+        .unwrap();
+
+        assert_eq!(settings.theme.blue, 0x83a598ff.into());
+        assert_eq!(settings.theme.black, Theme::default().black);
+        assert_eq!(settings.bar.height_px, 32);
+        assert_eq!(settings.bar.max_active_window_chars, 50);
+    }
+
+    #[test]
+    fn bar_widgets_can_be_set_per_screen() {
+        let settings = Settings::from_toml_str(
+            r#"
+            [bar]
+            enabled = true
+
+            [[bar.screens]]
+            widgets = ["workspaces", "window-name", "clock"]
+
+            [[bar.screens]]
+            widgets = ["workspaces", "layout"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(settings.bar.enabled);
+        assert_eq!(settings.bar.screens.len(), 2);
+        assert_eq!(
+            settings.bar.screens[1].widgets,
+            vec![BarWidget::Workspaces, BarWidget::Layout]
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_the_offending_key() {
+        let err = Settings::from_toml_str(
+            r##"
+            [theme]
+            grey = "not a colour"
+            "##,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("`theme.grey`"), "{err}");
+    }
+
+    #[test]
+    fn unknown_key_actions_report_their_line() {
+        let err = Settings::from_toml_str(
+            r#"
+            [keys]
+            "M-Return" = "spawn kitty"
+            "M-r" = "launch dmenu_run"
+            "#,
+        )
+        .unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.contains("`keys.M-r`"), "{msg}");
+        assert!(msg.contains("line 4"), "{msg}");
+        assert!(msg.contains("unknown action 'launch'"), "{msg}");
+    }
+
+    #[test]
+    fn duplicate_chords_report_both_lines() {
+        let err = Settings::from_toml_str(
+            r#"
+            [keys]
+            "M-S-j" = "swap-down"
+            "M-Return" = "spawn kitty"
+            "S-M-j" = "swap-up"
+            "#,
+        )
+        .unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.contains("line 3"), "{msg}");
+        assert!(msg.contains("line 5"), "{msg}");
+    }
+
+    #[test]
+    fn invalid_rules_name_the_offending_rule() {
+        let err = Settings::from_toml_str(
+            r#"
+            [[rules]]
+            match = { class = "kitty" }
+            action = "tile"
+
+            [[rules]]
+            match = { class = "mpv" }
+            action = "fullscreen"
+            "#,
+        )
+        .unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.contains("`rules[1].action`"), "{msg}");
+        assert!(msg.contains("line 8"), "{msg}");
+    }
+
+    #[test]
+    fn idle_stages_can_be_configured() {
+        let settings = Settings::from_toml_str(
+            r#"
+            [idle]
+            inhibit_when = [{ class = "mpv" }]
+
+            [[idle.stages]]
+            after_secs = 300
+            action = { notify = "Still there?" }
+
+            [[idle.stages]]
+            after_secs = 600
+            action = "suspend-then-hibernate"
+            "#,
+        )
+        .unwrap();
+
+        assert!(settings.idle.enabled);
+        assert_eq!(
+            settings.idle.stages,
+            vec![
+                IdleStage {
+                    after_secs: 300,
+                    action: IdleAction::Notify("Still there?".to_string()),
+                },
+                IdleStage {
+                    after_secs: 600,
+                    action: IdleAction::SuspendThenHibernate,
+                },
+            ]
+        );
+        assert_eq!(settings.idle.inhibit_when.len(), 1);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = Settings::from_toml_str(
+            r##"
+            [bar]
+            hieght_px = 32
+            "##,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("hieght_px"), "{err}");
+    }
+}
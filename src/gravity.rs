@@ -0,0 +1,200 @@
+//! Placing floating clients according to their ICCCM `win_gravity`, allowing for the border
+//! drawn around them.
+use penrose::{
+    core::{hooks::ManageHook, State},
+    pure::geometry::Rect,
+    x::{event::ConfigureEvent, XConn, XConnExt, XEvent},
+    x11rb::RustConn,
+    Xid,
+};
+use x11rb::protocol::xproto::Gravity;
+
+use crate::{props::PropConn, size_hints::SizeHints};
+
+/// The frame (client window plus border) that keeps the reference point of a client with the
+/// given gravity where it was requested.
+///
+/// `requested` is the position and size the client asked for, not including our border.
+pub fn frame_for(requested: Rect, gravity: Gravity, border: u32) -> Rect {
+    // How many border widths the frame is shifted left and up for each gravity
+    let (dx, dy) = match gravity {
+        Gravity::NORTH => (1, 0),
+        Gravity::NORTH_EAST => (2, 0),
+        Gravity::WEST => (0, 1),
+        Gravity::CENTER => (1, 1),
+        Gravity::EAST => (2, 1),
+        Gravity::SOUTH_WEST => (0, 2),
+        Gravity::SOUTH => (1, 2),
+        Gravity::SOUTH_EAST => (2, 2),
+        // The client window itself stays put with the border drawn around it
+        Gravity::STATIC => (1, 1),
+        _ => (0, 0),
+    };
+    let border = border as i32;
+
+    Rect::new(
+        requested.x - dx * border,
+        requested.y - dy * border,
+        requested.w + 2 * border as u32,
+        requested.h + 2 * border as u32,
+    )
+}
+
+/// The frame for a client that has sent a configure request for `requested` while currently at
+/// `current`.
+///
+/// X fills in any part of the request that the client left out with the current geometry so a
+/// request that leaves the position unchanged is a resize in place rather than a move.
+pub fn configured_frame(requested: Rect, current: Rect, gravity: Gravity, border: u32) -> Rect {
+    if (requested.x, requested.y) == (current.x, current.y) {
+        let mut r = frame_for(requested, Gravity::NORTH_WEST, border);
+        (r.x, r.y) = (current.x, current.y);
+        r
+    } else {
+        frame_for(requested, gravity, border)
+    }
+}
+
+/// The frame for a newly mapped client at `requested`, if its size hints say that it asked for
+/// that position (`USPosition` or `PPosition`).
+pub fn placed_frame(requested: Rect, hints: Option<&SizeHints>, border: u32) -> Option<Rect> {
+    let hints = hints.filter(|h| h.position.is_some())?;
+
+    Some(frame_for(requested, hints.gravity(), border))
+}
+
+fn gravity_for<X: PropConn>(id: Xid, x: &X) -> Gravity {
+    match x.cached_size_hints(id) {
+        Ok(Some(hints)) => hints.gravity(),
+        _ => Gravity::NORTH_WEST,
+    }
+}
+
+/// A [ManageHook] moving floating clients that have asked for a position so that their
+/// reference point ends up where they requested.
+///
+/// Clients that have not asked for a position are left where penrose put them (centred on the
+/// screen or their parent).
+pub struct PlaceByGravity;
+
+impl<X: PropConn> ManageHook<X> for PlaceByGravity {
+    fn call(&mut self, client: Xid, state: &mut State<X>, x: &X) -> penrose::Result<()> {
+        if !state.client_set.is_floating(&client) {
+            return Ok(());
+        }
+
+        let hints = x.cached_size_hints(client)?;
+        let requested = x.client_geometry(client)?;
+        let Some(r) = placed_frame(requested, hints.as_ref(), state.config.border_width) else {
+            return Ok(());
+        };
+        tracing::debug!(%client, ?requested, ?r, "placing client by gravity");
+
+        state.client_set.float(client, r)
+    }
+}
+
+/// An event hook handling configure requests from floating clients, moving them according to
+/// their gravity and remembering the new position so that it survives the next refresh.
+///
+/// Requests from tiled and unmanaged clients are left to penrose.
+pub fn configure_request_with_gravity(
+    event: &XEvent,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<bool> {
+    let XEvent::ConfigureRequest(ConfigureEvent {
+        id,
+        r,
+        is_root: false,
+    }) = event
+    else {
+        return Ok(true);
+    };
+
+    if !state.client_set.is_floating(id) {
+        return Ok(true);
+    }
+
+    let current = x.client_geometry(*id)?;
+    let frame = configured_frame(*r, current, gravity_for(*id, x), state.config.border_width);
+    if state.client_set.float(*id, frame).is_err() {
+        return Ok(true); // Not on a visible screen
+    }
+
+    x.refresh(state)?;
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTED: Rect = Rect::new(100, 100, 200, 100);
+    const BORDER: u32 = 3;
+
+    #[test]
+    fn frames_keep_the_reference_point_for_every_gravity() {
+        let cases = [
+            (Gravity::NORTH_WEST, 100, 100),
+            (Gravity::NORTH, 97, 100),
+            (Gravity::NORTH_EAST, 94, 100),
+            (Gravity::WEST, 100, 97),
+            (Gravity::CENTER, 97, 97),
+            (Gravity::EAST, 94, 97),
+            (Gravity::SOUTH_WEST, 100, 94),
+            (Gravity::SOUTH, 97, 94),
+            (Gravity::SOUTH_EAST, 94, 94),
+            (Gravity::STATIC, 97, 97),
+        ];
+
+        for (gravity, x, y) in cases {
+            let r = frame_for(REQUESTED, gravity, BORDER);
+            assert_eq!(r, Rect::new(x, y, 206, 106), "{gravity:?}");
+
+            // Without a border there is nothing to adjust for
+            assert_eq!(frame_for(REQUESTED, gravity, 0), REQUESTED, "{gravity:?}");
+        }
+    }
+
+    fn hints(flags: u32, gravity: u32) -> SizeHints {
+        let mut raw = [0; 18];
+        raw[0] = flags;
+        raw[17] = gravity;
+        SizeHints::try_from_raw(&raw).unwrap()
+    }
+
+    #[test]
+    fn only_clients_asking_for_a_position_are_placed() {
+        // PPosition | PWinGravity and USPosition | PWinGravity
+        let program = hints(1 << 2 | 1 << 9, Gravity::SOUTH_EAST.into());
+        let user = hints(1 << 0 | 1 << 9, Gravity::SOUTH_EAST.into());
+        // PWinGravity alone
+        let unplaced = hints(1 << 9, Gravity::SOUTH_EAST.into());
+
+        for hints in [&program, &user] {
+            let r = placed_frame(REQUESTED, Some(hints), BORDER);
+            assert_eq!(r, Some(Rect::new(94, 94, 206, 106)));
+        }
+        assert_eq!(placed_frame(REQUESTED, Some(&unplaced), BORDER), None);
+        assert_eq!(placed_frame(REQUESTED, None, BORDER), None);
+
+        // Asking for the origin is still asking for a position
+        let origin = Rect::new(0, 0, 200, 100);
+        let r = placed_frame(origin, Some(&program), BORDER);
+        assert_eq!(r, Some(Rect::new(-6, -6, 206, 106)));
+    }
+
+    #[test]
+    fn resizing_in_place_does_not_move_the_frame() {
+        let current = Rect::new(94, 94, 200, 100);
+        let requested = Rect::new(94, 94, 300, 150);
+
+        let r = configured_frame(requested, current, Gravity::SOUTH_EAST, BORDER);
+        assert_eq!(r, Rect::new(94, 94, 306, 156));
+
+        let r = configured_frame(REQUESTED, current, Gravity::SOUTH_EAST, BORDER);
+        assert_eq!(r, Rect::new(94, 94, 206, 106));
+    }
+}
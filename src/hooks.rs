@@ -1,266 +1,355 @@
-use std::sync::Arc;
+use std::fs;
+
+use globset::{Glob, GlobMatcher};
 
 use penrose::{
     core::{hooks::ManageHook, State},
-    extensions::hooks::manage::{DefaultTiled, FloatingFixed, FloatingRelative},
-    manage_hooks,
     pure::geometry::{Rect, RelativeRect},
-    x::{
-        property::WmNormalHints,
-        query::{ClassName, StringProperty, Title},
-        Atom, Prop, Query, XConn,
-    },
-    x11rb::RustConn,
+    x::{Atom, Prop, Query, XConn},
+};
+use regex::Regex;
+use x11rb::protocol::xproto::Gravity;
+
+use crate::{
+    config::Settings,
+    gravity::PlaceByGravity,
+    props::{PropConn, SnapshotProps},
+    rules::{default_rules, dialog_rules},
+    size_hints::{Size, SizeHints, TrackSizeHints},
 };
-use x11rb::protocol::xproto::{ConnectionExt, Gravity};
-use x11rb_protocol::protocol::xproto::AtomEnum;
 
-use crate::BAR_HEIGHT_PX;
+const WM_WINDOW_ROLE: &str = "WM_WINDOW_ROLE";
+const NET_WM_PID: &str = "_NET_WM_PID";
+
+/// The string property of a client that a [StrQuery] compares against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StrProp {
+    /// `WM_NAME`, falling back to `_NET_WM_NAME`
+    Title,
+    /// The first string of `WM_CLASS`
+    AppName,
+    /// The second string of `WM_CLASS`
+    ClassName,
+    /// `WM_WINDOW_ROLE`
+    Role,
+    /// The name of the process that owns the client, see [process_name]
+    ProcessName,
+    /// The first string of an arbitrary property
+    Prop(String),
+}
 
-#[derive(Debug, PartialEq, Eq)]
-struct OneOfQuery<C, Q, X> {
-    strs: Arc<[&'static str]>,
-    constr: Arc<C>,
-    _phantom: std::marker::PhantomData<(Q, X)>,
+/// How a [StrQuery] compares a property against a pattern.
+#[derive(Debug, Clone)]
+pub enum StrMatch {
+    Exact(String),
+    /// Equal ignoring case. The pattern is stored lowercased.
+    IgnoreCase(String),
+    Prefix(String),
+    Suffix(String),
+    Regex(Regex),
+    Glob(GlobMatcher),
 }
 
-impl<C, Q, X> Clone for OneOfQuery<C, Q, X> {
-    fn clone(&self) -> Self {
-        Self {
-            strs: Arc::clone(&self.strs),
-            constr: Arc::clone(&self.constr),
-            _phantom: std::marker::PhantomData,
+impl StrMatch {
+    pub fn ignore_case(s: &str) -> Self {
+        Self::IgnoreCase(s.to_lowercase())
+    }
+
+    pub fn regex(s: &str) -> Result<Self, regex::Error> {
+        Regex::new(s).map(Self::Regex)
+    }
+
+    pub fn glob(s: &str) -> Result<Self, globset::Error> {
+        Glob::new(s).map(|g| Self::Glob(g.compile_matcher()))
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        match self {
+            Self::Exact(p) => s == p,
+            Self::IgnoreCase(p) => s.to_lowercase() == *p,
+            Self::Prefix(p) => s.starts_with(p.as_str()),
+            Self::Suffix(p) => s.ends_with(p.as_str()),
+            Self::Regex(r) => r.is_match(s),
+            Self::Glob(g) => g.is_match(s),
         }
     }
 }
 
-impl<C, Q, X> OneOfQuery<C, Q, X>
-where
-    X: XConn,
-    Q: Query<X>,
-    C: Fn(&'static str) -> Q,
-{
-    pub fn new(strs: &[&'static str], constr: C) -> Self {
-        Self {
-            strs: strs.into(),
-            constr: constr.into(),
-            _phantom: std::marker::PhantomData,
+impl PartialEq for StrMatch {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Exact(a), Self::Exact(b))
+            | (Self::IgnoreCase(a), Self::IgnoreCase(b))
+            | (Self::Prefix(a), Self::Prefix(b))
+            | (Self::Suffix(a), Self::Suffix(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            (Self::Glob(a), Self::Glob(b)) => a.glob() == b.glob(),
+            _ => false,
         }
     }
 }
 
-impl<C, Q, X> Query<X> for OneOfQuery<C, Q, X>
-where
-    X: XConn,
-    Q: Query<X>,
-    C: Fn(&'static str) -> Q,
-{
-    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
-        self.strs.iter().try_fold(false, |acc, title| {
-            Ok(acc || (self.constr)(title).run(id, x)?)
-        })
-    }
-}
-
-const ZOOM_TILE_TITLES: &[&str] = &[
-    "Zoom - Free Account",               // main window
-    "Zoom Workplace - Free account",     // main window
-    "Zoom - Licensed Account",           // main window
-    "Zoom Workplace - Licensed account", // main window
-    "Zoom",                              // meeting window on creation
-    "Zoom Workplace",                    // meeting window on creation
-    "Zoom Meeting",                      // meeting window shortly after creation
-    "Meeting",                           // meeting window while in meeting
-    "Settings",                          // settings window
-    "Meeting chat",                      // chat window while in meeting
-    "Chat",                              // chat window shortly after creation
-    "",                                  // main window before renamed to "Zoom Workplace"
-];
-
-// FIXME: horrible hack to get size hint Rects from WmNormalHints
-trait WmNormalHintsExt {
-    fn max(&self) -> Option<Rect>;
-    #[allow(unused)]
-    fn min(&self) -> Option<Rect>;
-    fn base(&self) -> Option<Rect>;
-    fn user_specified(&self) -> Option<Rect>;
-
-    fn apply_to_elementwise(&self, mut r: Rect) -> Rect {
-        if let Some(max) = self.max() {
-            if r.w > max.w {
-                r.w = max.w;
-            }
-            if r.h > max.h {
-                r.h = max.h;
-            }
-        }
+impl From<&str> for StrMatch {
+    fn from(s: &str) -> Self {
+        Self::Exact(s.to_string())
+    }
+}
 
-        if let Some(min) = self.min() {
-            if r.w < min.w {
-                r.w = min.w;
-            }
-            if r.h < min.h {
-                r.h = min.h;
-            }
+impl From<String> for StrMatch {
+    fn from(s: String) -> Self {
+        Self::Exact(s)
+    }
+}
+
+/// A [Query] matching a string property of a client against one or more owned patterns.
+///
+/// This mirrors the `Title`, `AppName`, `ClassName` and `StringProperty` queries from penrose
+/// for strings that are only known at runtime (e.g. loaded from the config file). The property
+/// is fetched once no matter how many patterns there are, and the query holds if any of them
+/// match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrQuery {
+    prop: StrProp,
+    patterns: Vec<StrMatch>,
+}
+
+impl StrQuery {
+    pub fn new(prop: StrProp, pattern: impl Into<StrMatch>) -> Self {
+        Self::any(prop, [pattern.into()])
+    }
+
+    pub fn any(prop: StrProp, patterns: impl IntoIterator<Item = StrMatch>) -> Self {
+        Self {
+            prop,
+            patterns: patterns.into_iter().collect(),
         }
+    }
 
-        r
+    /// Whether any of the patterns match the value of the property.
+    pub fn matches(&self, value: &str) -> bool {
+        self.patterns.iter().any(|p| p.is_match(value))
     }
 }
 
-impl WmNormalHintsExt for WmNormalHints {
-    fn max(&self) -> Option<Rect> {
-        let json = serde_json::to_value(self).ok()?;
-        get_rect_from_value(json.get("max")?)
-    }
+impl<X: PropConn> Query<X> for StrQuery {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        let strs = match &self.prop {
+            StrProp::Title => match str_prop(Atom::WmName.as_ref(), id, x)? {
+                Some(strs) => Some(strs),
+                None => str_prop(Atom::NetWmName.as_ref(), id, x)?,
+            },
+            StrProp::AppName | StrProp::ClassName => str_prop(Atom::WmClass.as_ref(), id, x)?,
+            StrProp::Role => str_prop(WM_WINDOW_ROLE, id, x)?,
+            StrProp::ProcessName => window_pid(id, x)?
+                .and_then(process_name)
+                .map(|name| vec![name]),
+            StrProp::Prop(p) => str_prop(p, id, x)?,
+        };
 
-    fn min(&self) -> Option<Rect> {
-        let json = serde_json::to_value(self).ok()?;
-        get_rect_from_value(json.get("min")?)
+        let ix = if self.prop == StrProp::ClassName {
+            1
+        } else {
+            0
+        };
+
+        Ok(matches!(strs, Some(strs) if strs.get(ix).is_some_and(|s| self.matches(s))))
     }
+}
 
-    fn base(&self) -> Option<Rect> {
-        let json = serde_json::to_value(self).ok()?;
-        get_rect_from_value(json.get("base")?)
+fn str_prop<X: PropConn>(
+    prop: &str,
+    id: penrose::Xid,
+    x: &X,
+) -> penrose::Result<Option<Vec<String>>> {
+    match x.cached_prop(id, prop)? {
+        Some(Prop::UTF8String(strs)) if !strs.is_empty() => Ok(Some(strs)),
+        _ => Ok(None),
     }
+}
 
-    fn user_specified(&self) -> Option<Rect> {
-        let json = serde_json::to_value(self).ok()?;
-        get_rect_from_value(json.get("user_specified")?)
+/// The pid of the process that owns a client, from `_NET_WM_PID`.
+pub fn window_pid<X: PropConn>(id: penrose::Xid, x: &X) -> penrose::Result<Option<u32>> {
+    match x.cached_prop(id, NET_WM_PID)? {
+        Some(Prop::Cardinal(vals)) => Ok(vals.first().copied()),
+        _ => Ok(None),
     }
 }
 
-fn get_rect_from_value(value: &serde_json::Value) -> Option<Rect> {
-    Some(Rect::new(
-        value.get("x")?.as_u64()? as i32,
-        value.get("y")?.as_u64()? as i32,
-        value.get("w")?.as_u64()? as u32,
-        value.get("h")?.as_u64()? as u32,
-    ))
+/// The name of a running process as given in `/proc/<pid>/comm`.
+///
+/// This is the executable name truncated to 15 bytes, rather than the full command line.
+pub fn process_name(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+
+    Some(comm.trim_end().to_string())
+}
+
+/// The client that a client is transient for (e.g. the main window of a dialog), from
+/// `WM_TRANSIENT_FOR`.
+pub fn transient_for<X: PropConn>(
+    id: penrose::Xid,
+    x: &X,
+) -> penrose::Result<Option<penrose::Xid>> {
+    match x.cached_prop(id, Atom::WmTransientFor.as_ref())? {
+        // Some clients point this at the root window or themselves, and 0 means None
+        Some(Prop::Window(ids)) => Ok(ids
+            .first()
+            .copied()
+            .filter(|&p| *p != 0 && p != x.root() && p != id)),
+        _ => Ok(None),
+    }
 }
 
-struct ConstrainedSizeHints;
+/// A [Query] checking whether `_NET_WM_WINDOW_TYPE` contains the given atom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowType(pub String);
 
-impl<X: XConn> Query<X> for ConstrainedSizeHints {
+impl<X: PropConn> Query<X> for WindowType {
     fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
-        let p = Atom::WmNormalHints.as_ref();
-        let Ok(Some(Prop::WmNormalHints(hints))) = x.get_prop(id, p) else {
-            return Ok(false);
-        };
-
-        let Some(min) = hints.min() else {
-            return Ok(false);
-        };
-        let Some(max) = hints.max() else {
-            return Ok(false);
-        };
+        AnyWindowType(vec![self.0.clone()]).run(id, x)
+    }
+}
 
-        Ok(min.w == max.w && min.h == max.h)
+/// A [Query] checking whether `_NET_WM_WINDOW_TYPE` contains any of the given atoms.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnyWindowType(pub Vec<String>);
+
+impl AnyWindowType {
+    /// Match the `_NET_WM_WINDOW_TYPE_*` atoms with the given suffixes, e.g. "dialog".
+    pub fn named<S: AsRef<str>>(names: impl IntoIterator<Item = S>) -> Self {
+        Self(
+            names
+                .into_iter()
+                .map(|t| format!("_NET_WM_WINDOW_TYPE_{}", t.as_ref().to_uppercase()))
+                .collect(),
+        )
     }
 }
 
-struct StaticSizeHints;
+impl<X: PropConn> Query<X> for AnyWindowType {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        match x.cached_prop(id, Atom::NetWmWindowType.as_ref())? {
+            Some(Prop::Atom(atoms)) => Ok(atoms.iter().any(|a| self.0.contains(a))),
+            _ => Ok(false),
+        }
+    }
+}
 
-impl StaticSizeHints {
-    fn gravity_is_static(&self, id: penrose::Xid, x: &RustConn) -> penrose::Result<bool> {
-        let atom = Atom::WmNormalHints.as_ref();
-        let atom_xid = x.intern_atom(atom)?;
+/// A type erased [Query] that can itself be combined with `and`, `or` and `not`.
+pub struct BoxedQuery<X: XConn>(pub Box<dyn Query<X>>);
 
-        let r = x
-            .connection()
-            .get_property(false, *id, atom_xid, AtomEnum::WM_SIZE_HINTS, 0, 1024)?
-            .reply()?;
+impl<X: XConn> BoxedQuery<X> {
+    pub fn new(q: impl Query<X> + 'static) -> Self {
+        Self(Box::new(q))
+    }
+}
 
-        let prop_type = match r.type_ {
-            0 => return Ok(false),
-            id => x.atom_name(penrose::Xid::from(id))?,
-        };
+impl<X: XConn> Query<X> for BoxedQuery<X> {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        self.0.run(id, x)
+    }
+}
 
-        let raw = match prop_type.as_ref() {
-            "WM_SIZE_HINTS" => r
-                .value32()
-                .ok_or_else(|| penrose::Error::InvalidPropertyData {
-                    id,
-                    prop: atom.to_owned(),
-                    ty: prop_type.to_owned(),
-                })?
-                .collect::<Vec<_>>(),
-            _ => {
-                return Err(penrose::Error::InvalidHints {
-                    reason: format!("expected WM_SIZE_HINTS but got {}", prop_type),
-                })
-            }
-        };
+/// A [Query] that holds for every client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Always;
 
-        if raw.len() != 18 {
-            return Err(penrose::Error::InvalidHints {
-                reason: format!("raw bytes should be [u32; 18] but got [u32; {}]", raw.len()),
-            });
-        }
+impl<X: XConn> Query<X> for Always {
+    fn run(&self, _: penrose::Xid, _: &X) -> penrose::Result<bool> {
+        Ok(true)
+    }
+}
 
-        let gravity = Gravity::from(raw[17]);
-        tracing::trace!(?gravity, "gravity");
+/// A [Query] matching clients whose minimum and maximum sizes are the same.
+pub(crate) struct ConstrainedSizeHints;
 
-        Ok(gravity == Gravity::STATIC)
+impl<X: PropConn> Query<X> for ConstrainedSizeHints {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        Ok(matches!(x.cached_size_hints(id), Ok(Some(hints)) if hints.is_fixed()))
     }
 }
 
-impl Query<RustConn> for StaticSizeHints {
-    fn run(&self, id: penrose::Xid, x: &RustConn) -> penrose::Result<bool> {
-        tracing::trace!(%id, "checking if gravity is static");
-        if !self.gravity_is_static(id, x)? {
-            return Ok(false);
-        }
+/// A [Query] matching clients with static gravity that have asked for a specific size.
+pub(crate) struct StaticSizeHints;
 
-        let p = Atom::WmNormalHints.as_ref();
-        let Ok(Some(Prop::WmNormalHints(hints))) = x.get_prop(id, p) else {
+impl<X: PropConn> Query<X> for StaticSizeHints {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        tracing::trace!(%id, "checking if gravity is static");
+        let Some(hints) = x.cached_size_hints(id)? else {
             return Ok(false);
         };
+        tracing::trace!(?hints, "size hints");
 
-        tracing::trace!(?hints, "gravity is static");
+        Ok(hints.gravity() == Gravity::STATIC && hints.size.is_some())
+    }
+}
 
-        let Some(spec) = hints.user_specified() else {
-            return Ok(false);
-        };
-        tracing::trace!(?spec, "user_specified");
-
-        Ok(spec.w > 0 && spec.h > 0)
-    }
-}
-
-pub fn manage_hook<'a>() -> Box<dyn ManageHook<RustConn> + 'a> {
-    let top_right_corner = RelativeRect::new(0.75, 0.0, 0.90, 0.10);
-    let zoom_titles = OneOfQuery::new(ZOOM_TILE_TITLES, Title);
-    let manage_hook = manage_hooks! {
-        // Need this to handle the stupid zoom audio notifications
-        // _NET_WM_NAME == "zoom", WM_NAME == "", and gravity is static
-        StaticSizeHints
-            .and(StringProperty(Atom::NetWmName.as_ref(), "zoom"))
-            .and(StringProperty(Atom::WmName.as_ref(), ""))
-                => NotificationUserSuggested::default(),
-        ClassName("zoom").and(zoom_titles.clone()) => DefaultTiled,
-        ClassName("zoom").and(zoom_titles.not()) => FloatingRelative(top_right_corner),
-        ClassName("obsidian").and(
-            OneOfQuery::new(&["Obsidian Help"], Title))
-                 => FloatingSuggestedCentered::default(),
-        // Windows that have max size == min size are not resizable, float them
-        ConstrainedSizeHints => FloatingSuggestedCentered::default(),
-        IsDock => FloatingFixed(Rect::new(0, 0, 100, BAR_HEIGHT_PX)).then(IgnoreWindow),
-        ClassName("stalonetray") => FloatingFixed(Rect::new(0, 0, 100, BAR_HEIGHT_PX)),
+/// Build the manage hook from the window rules in the user's settings.
+///
+/// The properties of each new window are fetched once up front and shared by every rule.
+pub fn manage_hook<X: PropConn + 'static>(settings: &Settings) -> Box<dyn ManageHook<X>> {
+    Box::new(SnapshotProps(rule_hooks(settings)))
+}
+
+/// The hooks making up [manage_hook], each fetching the properties that it needs itself.
+pub fn rule_hooks<X: PropConn + 'static>(settings: &Settings) -> Vec<Box<dyn ManageHook<X>>> {
+    let mut rules = if settings.default_rules {
+        default_rules()
+    } else {
+        vec![]
     };
-    manage_hook
+    rules.extend(dialog_rules(&settings.dialogs));
+    rules.extend(settings.rules.iter().cloned());
+
+    let mut hooks: Vec<Box<dyn ManageHook<X>>> =
+        vec![Box::new(TrackSizeHints), Box::new(PlaceByGravity)];
+    hooks.extend(
+        rules
+            .into_iter()
+            .map(|rule| -> Box<dyn ManageHook<X>> { Box::new(LogErrors(rule.compile())) }),
+    );
+
+    hooks
+}
+
+// Runs a window rule, logging rather than returning any error so that the rules after it still
+// run.
+struct LogErrors<X: XConn>(Box<dyn ManageHook<X>>);
+
+impl<X: XConn> ManageHook<X> for LogErrors<X> {
+    fn call(&mut self, client: penrose::Xid, state: &mut State<X>, x: &X) -> penrose::Result<()> {
+        if let Err(e) = self.0.call(client, state, x) {
+            tracing::warn!(%e, %client, "window rule failed");
+        }
+
+        Ok(())
+    }
 }
 
 pub struct IsDock;
 
-impl<X: XConn> Query<X> for IsDock {
+impl<X: PropConn> Query<X> for IsDock {
     fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
-        let window_type = Atom::NetWmWindowType.as_ref();
+        AnyWindowType::named(["dock", "desktop"]).run(id, x)
+    }
+}
 
-        Ok(
-            StringProperty(window_type, Atom::NetWindowTypeDock.as_ref()).run(id, x)?
-                || StringProperty(window_type, Atom::NetWindowTypeDesktop.as_ref()).run(id, x)?,
-        )
+/// A [Query] matching dialogs and the other short lived windows that belong to a main window:
+/// utility windows (e.g. palettes), splash screens and torn off menus.
+pub struct IsDialog;
+
+impl<X: PropConn> Query<X> for IsDialog {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        AnyWindowType::named(["dialog", "utility", "splash", "menu"]).run(id, x)
+    }
+}
+
+/// A [Query] matching clients that are transient for another client.
+pub struct IsTransient;
+
+impl<X: PropConn> Query<X> for IsTransient {
+    fn run(&self, id: penrose::Xid, x: &X) -> penrose::Result<bool> {
+        Ok(transient_for(id, x)?.is_some())
     }
 }
 
@@ -273,11 +362,22 @@ impl<X: XConn> ManageHook<X> for IgnoreWindow {
     }
 }
 
-/// Float clients at the size and position suggested by WmNormalHints on the currently focused
-/// screen. Otherwise, use the relative position and size of the client.
+/// Move the client to the given tag. An owned equivalent of penrose's `SetWorkspace`.
+pub struct MoveToTag(pub String);
+
+impl<X: XConn> ManageHook<X> for MoveToTag {
+    fn call(&mut self, client: penrose::Xid, state: &mut State<X>, _: &X) -> penrose::Result<()> {
+        state.client_set.move_client_to_tag(&client, &self.0);
+        Ok(())
+    }
+}
+
+/// Float clients at the size suggested by WmNormalHints, otherwise at the given fractions of
+/// the screen size. Transient clients are centred over their parent and everything else on the
+/// currently focused screen.
 pub struct FloatingSuggestedCentered {
-    width: f64,
-    height: f64,
+    pub(crate) width: f64,
+    pub(crate) height: f64,
 }
 
 impl FloatingSuggestedCentered {
@@ -297,79 +397,265 @@ impl Default for FloatingSuggestedCentered {
     }
 }
 
-impl<X: XConn> ManageHook<X> for FloatingSuggestedCentered {
+impl FloatingSuggestedCentered {
+    /// The floating position for a client with the given hints, centred over `parent` and kept
+    /// within `screen`.
+    pub fn rect(&self, hints: Option<&SizeHints>, parent: Rect, screen: Rect) -> Rect {
+        let fallback = RelativeRect::new(0.0, 0.0, self.width, self.height).applied_to(&screen);
+        let bounds = Size::new(screen.w, screen.h);
+        let r = match hints {
+            Some(hints) => {
+                let base = hints.base.map(|s| Rect::new(0, 0, s.w, s.h));
+                tracing::trace!(?hints, ?base, "rect: from base");
+                hints.constrain_within(base.unwrap_or(fallback), bounds)
+            }
+            None => fallback,
+        };
+        tracing::trace!(?hints, ?r, "rect: size hints applied");
+
+        centred_within(r, parent, screen)
+    }
+}
+
+// Centre r over parent, moving it back onto the screen if that leaves it hanging off an edge.
+fn centred_within(r: Rect, parent: Rect, screen: Rect) -> Rect {
+    let clamp = |pos: i32, len: u32, start: i32, screen_len: u32| {
+        let end = start + screen_len as i32 - len as i32;
+        pos.min(end).max(start)
+    };
+    let x = parent.x + (parent.w as i32 - r.w as i32) / 2;
+    let y = parent.y + (parent.h as i32 - r.h as i32) / 2;
+
+    Rect::new(
+        clamp(x, r.w, screen.x, screen.w),
+        clamp(y, r.h, screen.y, screen.h),
+        r.w,
+        r.h,
+    )
+}
+
+impl<X: PropConn> ManageHook<X> for FloatingSuggestedCentered {
     fn call(&mut self, client: penrose::Xid, state: &mut State<X>, x: &X) -> penrose::Result<()> {
-        let p = Atom::WmNormalHints.as_ref();
-        let Ok(Some(Prop::WmNormalHints(hints))) = x.get_prop(client, p) else {
+        // Transients of clients on a hidden workspace are put there with them, out of sight
+        if state.client_set.screen_for_client(&client).is_none() {
+            tracing::debug!(%client, "not floating a client on a hidden workspace");
             return Ok(());
-        };
+        }
 
-        let base = hints.base();
+        let hints = x.cached_size_hints(client).ok().flatten();
 
-        let r_screen = state.client_set.current_screen();
-        let r = base.unwrap_or(
-            RelativeRect::new(0.0, 0.0, self.width, self.height).applied_to(&r_screen.geometry()),
-        );
-        tracing::trace!(?hints, ?r, "rect: from base");
-        let r = hints.apply_to_elementwise(r);
-        tracing::trace!(?hints, ?r, "rect: size hints applied");
-        let r = r
-            .centered_in(&r_screen.geometry())
-            .expect("scaled to smaller than screen");
+        // Parents on a hidden workspace have nowhere on screen to be centred over
+        let parent = transient_for(client, x)?.and_then(|p| {
+            let screen = state.client_set.screen_for_client(&p)?.geometry();
+            let r = x.client_geometry(p).unwrap_or(screen);
+            Some((r, screen))
+        });
+        let (parent, screen) = parent.unwrap_or_else(|| {
+            let screen = state.client_set.current_screen().geometry();
+            (screen, screen)
+        });
+
+        let r = self.rect(hints.as_ref(), parent, screen);
         tracing::trace!(%client, ?r, "client: applying size hints");
 
         state.client_set.float(client, r)
     }
 }
 
-pub struct NotificationUserSuggested {
-    width: f64,
-    height: f64,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{MockConn, ROOT},
+        rules::{Matcher, OneOrMany, Rule, RuleAction},
+    };
+    use penrose::{
+        core::{Config, WindowManager},
+        Xid,
+    };
+    use std::collections::HashMap;
 
-impl NotificationUserSuggested {
-    pub fn new(width: f64, height: f64) -> Self {
-        assert!(width > 0.0 && width <= 1.0);
-        assert!(height > 0.0 && height <= 1.0);
-        Self { width, height }
+    const SCREEN: Rect = Rect::new(0, 0, 1920, 1080);
+
+    fn hints(f: impl FnOnce(&mut [u32; 18])) -> SizeHints {
+        let mut raw = [0; 18];
+        f(&mut raw);
+        SizeHints::try_from_raw(&raw).unwrap()
     }
-}
 
-impl Default for NotificationUserSuggested {
-    fn default() -> Self {
-        Self {
-            width: 0.15,
-            height: 0.05,
+    #[test]
+    fn str_patterns_match() {
+        let cases = [
+            (StrMatch::from("Zoom"), "Zoom", "zoom"),
+            (StrMatch::ignore_case("Zoom"), "ZOOM", "Zoom Meeting"),
+            (StrMatch::Prefix("Zoom".into()), "Zoom Meeting", "Meeting"),
+            (
+                StrMatch::Suffix("- Firefox".into()),
+                "Inbox - Firefox",
+                "Firefox",
+            ),
+            (
+                StrMatch::regex("^Zoom( Workplace)?$").unwrap(),
+                "Zoom Workplace",
+                "Zoom Work",
+            ),
+            (
+                StrMatch::glob("*.pdf - [Zz]athura").unwrap(),
+                "a.pdf - zathura",
+                "a.pdf",
+            ),
+        ];
+
+        for (pattern, matching, other) in cases {
+            assert!(pattern.is_match(matching), "{pattern:?} {matching}");
+            assert!(!pattern.is_match(other), "{pattern:?} {other}");
         }
+
+        let q = StrQuery::any(
+            StrProp::Title,
+            [StrMatch::from(""), StrMatch::ignore_case("a")],
+        );
+        assert!(q.matches("") && q.matches("A") && !q.matches("b"));
     }
-}
 
-impl<X: XConn> ManageHook<X> for NotificationUserSuggested {
-    fn call(&mut self, client: penrose::Xid, state: &mut State<X>, x: &X) -> penrose::Result<()> {
-        let p = Atom::WmNormalHints.as_ref();
-        let Ok(Some(Prop::WmNormalHints(hints))) = x.get_prop(client, p) else {
-            return Ok(());
-        };
+    fn strs(s: &[&str]) -> Prop {
+        Prop::UTF8String(s.iter().map(|s| s.to_string()).collect())
+    }
 
-        let spec = hints.user_specified();
+    #[test]
+    fn titles_fall_back_to_net_wm_name() {
+        let x = MockConn::default()
+            .with_prop(1, "WM_NAME", strs(&["Zoom"]))
+            .with_prop(1, "_NET_WM_NAME", strs(&["Zoom Meeting"]))
+            .with_prop(2, "_NET_WM_NAME", strs(&["Zoom Meeting"]))
+            .with_prop(3, "WM_NAME", strs(&[]))
+            .with_prop(3, "_NET_WM_NAME", strs(&["Zoom Meeting"]));
+        let q = StrQuery::new(StrProp::Title, "Zoom Meeting");
+
+        assert!(!q.run(Xid::from(1), &x).unwrap());
+        assert!(q.run(Xid::from(2), &x).unwrap());
+        assert!(q.run(Xid::from(3), &x).unwrap());
+        assert!(!q.run(Xid::from(4), &x).unwrap());
+    }
 
-        let r_screen = state.client_set.current_screen();
-        const PADDING: f64 = 0.025;
-        let r = spec.unwrap_or(
-            RelativeRect::new(
-                1.0 - PADDING,
-                PADDING - self.height,
-                self.width,
-                self.height,
+    #[test]
+    fn window_property_queries() {
+        let (dialog, parent, other) = (Xid::from(1), Xid::from(2), Xid::from(3));
+        let x = MockConn::default()
+            .with_prop(1, "WM_WINDOW_ROLE", strs(&["GtkFileChooserDialog"]))
+            .with_prop(1, "WM_TRANSIENT_FOR", Prop::Window(vec![parent]))
+            .with_prop(1, "_NET_WM_PID", Prop::Cardinal(vec![std::process::id()]))
+            .with_prop(
+                1,
+                "_NET_WM_WINDOW_TYPE",
+                Prop::Atom(vec![
+                    "_KDE_NET_WM_WINDOW_TYPE_OVERRIDE".into(),
+                    "_NET_WM_WINDOW_TYPE_DIALOG".into(),
+                ]),
             )
-            .applied_to(&r_screen.geometry()),
+            // Some clients mark themselves as transient for the root window or themselves
+            .with_prop(3, "WM_TRANSIENT_FOR", Prop::Window(vec![Xid::from(ROOT)]))
+            .with_prop(4, "WM_TRANSIENT_FOR", Prop::Window(vec![Xid::from(4)]));
+
+        let role = StrQuery::new(StrProp::Role, StrMatch::Suffix("Dialog".into()));
+        assert!(role.run(dialog, &x).unwrap());
+        assert!(!role.run(other, &x).unwrap());
+
+        assert_eq!(window_pid(dialog, &x).unwrap(), Some(std::process::id()));
+        let name = process_name(std::process::id()).unwrap();
+        assert!(!name.is_empty() && !name.ends_with('\n'));
+        let process = StrQuery::new(StrProp::ProcessName, name.as_str());
+        assert!(process.run(dialog, &x).unwrap());
+        assert!(!process.run(other, &x).unwrap());
+
+        assert_eq!(transient_for(dialog, &x).unwrap(), Some(parent));
+        assert!(IsTransient.run(dialog, &x).unwrap());
+        assert!(!IsTransient.run(other, &x).unwrap());
+        assert!(!IsTransient.run(Xid::from(4), &x).unwrap());
+
+        assert!(IsDialog.run(dialog, &x).unwrap());
+        assert!(AnyWindowType::named(["splash", "dialog"])
+            .run(dialog, &x)
+            .unwrap());
+        assert!(!AnyWindowType::named(["splash"]).run(dialog, &x).unwrap());
+        assert!(!IsDock.run(dialog, &x).unwrap());
+        assert!(!IsDialog.run(other, &x).unwrap());
+    }
+
+    #[test]
+    fn transients_on_hidden_workspaces_do_not_stop_later_rules() {
+        let (dialog, parent) = (Xid::from(1), Xid::from(2));
+        let wm = WindowManager::new(
+            Config::default(),
+            HashMap::new(),
+            HashMap::new(),
+            MockConn::default(),
         );
-        tracing::info!(?hints, ?r, "rect: from base");
-        let top_left = RelativeRect::new(1.0 - PADDING, PADDING, PADDING, PADDING)
-            .applied_to(&r_screen.geometry());
-        let r = Rect::new(top_left.x - r.w as i32, top_left.y, r.w, r.h);
-        tracing::info!(?hints, ?r, ?top_left, "rect: from top_left");
+        let mut state = wm.unwrap().state;
+        let x = MockConn::default()
+            .with_prop(1, "WM_TRANSIENT_FOR", Prop::Window(vec![parent]))
+            .with_prop(1, "WM_CLASS", strs(&["dialog", "Dialog"]));
+
+        state.client_set.insert(parent);
+        state.client_set.move_client_to_tag(&parent, "2");
+        state.client_set.insert(dialog);
+        state.client_set.move_client_to_tag(&dialog, "2");
+
+        let settings = Settings {
+            rules: vec![Rule {
+                matcher: Matcher {
+                    class: Some(OneOrMany::One("Dialog".into())),
+                    ..Matcher::default()
+                },
+                actions: OneOrMany::One(RuleAction::SendToTag("5".to_string())),
+            }],
+            ..Settings::default()
+        };
+        rule_hooks(&settings).call(dialog, &mut state, &x).unwrap();
 
-        state.client_set.float(client, r)
+        assert_eq!(state.client_set.tag_for_client(&dialog), Some("5"));
+        assert!(!state.client_set.is_floating(&dialog));
+    }
+
+    #[test]
+    fn floating_clients_are_sized_to_whole_increments() {
+        // P_MIN_SIZE | P_RESIZE_INC with a 22x40 min size and 9x18 cells
+        let terminal = hints(|raw| {
+            raw[0] = 1 << 4 | 1 << 6;
+            raw[5..11].copy_from_slice(&[22, 40, 0, 0, 9, 18]);
+        });
+
+        // 25% of the screen is 480x270: 22 + 50*9 = 472 and 40 + 12*18 = 256
+        let r = FloatingSuggestedCentered::default().rect(Some(&terminal), SCREEN, SCREEN);
+        assert_eq!(r, Rect::new(724, 412, 472, 256));
+    }
+
+    #[test]
+    fn dialogs_are_centred_over_their_parent_within_the_screen() {
+        // A 400x300 base size with no max size
+        let dialog = hints(|raw| {
+            raw[0] = 1 << 8;
+            raw[15..17].copy_from_slice(&[400, 300]);
+        });
+        let float = FloatingSuggestedCentered::default();
+
+        let r = float.rect(Some(&dialog), Rect::new(100, 100, 800, 600), SCREEN);
+        assert_eq!(r, Rect::new(300, 250, 400, 300));
+
+        // A parent in the corner of the screen would leave the dialog hanging off the edge
+        let r = float.rect(Some(&dialog), Rect::new(1700, 0, 220, 100), SCREEN);
+        assert_eq!(r, Rect::new(1520, 0, 400, 300));
+
+        // Dialogs larger than the screen are shrunk to fit
+        let huge = hints(|raw| {
+            raw[0] = 1 << 8;
+            raw[15..17].copy_from_slice(&[2400, 1200]);
+        });
+        let r = float.rect(Some(&huge), SCREEN, SCREEN);
+        assert_eq!(r, SCREEN);
+
+        // Without any hints a fraction of the screen is used
+        let r = float.rect(None, Rect::new(100, 100, 800, 600), SCREEN);
+        assert_eq!(r, Rect::new(260, 265, 480, 270));
     }
 }
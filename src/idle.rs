@@ -0,0 +1,457 @@
+//! Dimming, locking and suspending as the X server's idle counter passes each configured stage.
+use std::{thread, time::Duration};
+
+use penrose::{
+    core::{ClientSet, State},
+    util,
+    x::{Query, XConn},
+    x11rb::RustConn,
+    Result,
+};
+use serde::Deserialize;
+use x11rb::protocol::screensaver::ConnectionExt;
+
+use crate::{
+    config::{IdleSettings, Settings},
+    hooks::{BoxedQuery, StrMatch},
+    lock::lock_screen,
+    props::PropConn,
+    remote::{Command, Remote},
+    rules::{Matcher, OneOrMany},
+    sleep::{suspend, SleepMode},
+};
+
+/// How often the idle time is checked.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+const NET_WM_STATE: &str = "_NET_WM_STATE";
+const NET_WM_STATE_FULLSCREEN: &str = "_NET_WM_STATE_FULLSCREEN";
+
+/// The built in stages, replacing the `xautolock` setup from the old startup script.
+pub fn default_stages() -> Vec<IdleStage> {
+    vec![
+        IdleStage {
+            after_secs: 540,
+            action: IdleAction::Notify("Locking the screen in a minute".to_string()),
+        },
+        IdleStage {
+            after_secs: 600,
+            action: IdleAction::Lock,
+        },
+        IdleStage {
+            after_secs: 610,
+            action: IdleAction::Dim,
+        },
+        IdleStage {
+            after_secs: 900,
+            action: IdleAction::SuspendThenHibernate,
+        },
+    ]
+}
+
+/// Windows that hold off the idle stages by default: a Zoom meeting.
+pub fn default_inhibitors() -> Vec<Matcher> {
+    vec![Matcher {
+        class: Some(OneOrMany::One(StrMatch::from("zoom"))),
+        title: Some(OneOrMany::Many(vec![
+            StrMatch::from("Zoom Meeting"),
+            StrMatch::from("Meeting"),
+        ])),
+        ..Matcher::default()
+    }]
+}
+
+/// Something to do once the user has been idle for `after_secs`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdleStage {
+    pub after_secs: u64,
+    pub action: IdleAction,
+}
+
+impl IdleStage {
+    pub fn after(&self) -> Duration {
+        Duration::from_secs(self.after_secs)
+    }
+}
+
+/// What an [IdleStage] does.
+///
+/// Actions without arguments are written as a bare string, e.g. `"lock"`. The rest are written
+/// as a single entry table, e.g. `{ notify = "Locking soon" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdleAction {
+    /// Show a notification
+    Notify(String),
+    /// Put the screens into DPMS standby, which the X server undoes on the next input
+    Dim,
+    /// Run the `[lock]` command unless the screen is already locked
+    Lock,
+    Suspend,
+    SuspendThenHibernate,
+    /// Run a shell command
+    Run(String),
+}
+
+impl IdleAction {
+    /// Carry out this action.
+    pub fn run(&self, state: &mut State<RustConn>) -> Result<()> {
+        tracing::info!(action = ?self, "running idle action");
+        match self {
+            Self::Notify(msg) => util::notify(msg)?,
+            Self::Dim => util::spawn("xset dpms force standby")?,
+            Self::Lock => {
+                lock_screen(state)?;
+            }
+            Self::Suspend => suspend(state, SleepMode::Suspend)?,
+            Self::SuspendThenHibernate => suspend(state, SleepMode::SuspendThenHibernate)?,
+            Self::Run(cmd) => util::spawn_with_args("sh", &["-c", cmd])?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Something that knows how long it has been since the user last gave any input.
+pub trait IdleClock {
+    fn idle_time(&self) -> Result<Duration>;
+}
+
+impl IdleClock for RustConn {
+    fn idle_time(&self) -> Result<Duration> {
+        let info = self
+            .connection()
+            .screensaver_query_info(*self.root())?
+            .reply()?;
+
+        Ok(Duration::from_millis(info.ms_since_user_input.into()))
+    }
+}
+
+/// Works out which [IdleStage]s are due from successive readings of the idle time.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IdleTimer {
+    stages: Vec<IdleStage>,
+    // The number of stages (in order) that have run during the current idle period
+    fired: usize,
+    // The idle time at which the current idle period started
+    baseline: Duration,
+    last: Duration,
+}
+
+impl IdleTimer {
+    pub fn new(mut stages: Vec<IdleStage>) -> Self {
+        stages.sort_by_key(|s| s.after_secs);
+
+        Self {
+            stages,
+            ..Self::default()
+        }
+    }
+
+    /// The actions of the stages that are now due given the current idle time.
+    ///
+    /// A reading lower than the last one means there has been some input in between, which
+    /// starts a new idle period.
+    pub fn tick(&mut self, idle: Duration, inhibited: bool) -> Vec<IdleAction> {
+        if idle < self.last {
+            self.baseline = Duration::ZERO;
+            self.fired = 0;
+        }
+        self.last = idle;
+
+        if inhibited {
+            self.baseline = idle;
+            self.fired = 0;
+            return Vec::new();
+        }
+
+        let elapsed = idle.saturating_sub(self.baseline);
+        let due: Vec<_> = self.stages[self.fired..]
+            .iter()
+            .take_while(|s| s.after() <= elapsed)
+            .map(|s| s.action.clone())
+            .collect();
+        self.fired += due.len();
+
+        due
+    }
+}
+
+/// The idle state of the window manager. Stored as a [State] extension.
+pub struct Idle<X: XConn> {
+    settings: IdleSettings,
+    timer: IdleTimer,
+    inhibit_when: Vec<BoxedQuery<X>>,
+    inhibited_by_user: bool,
+}
+
+impl<X: PropConn + 'static> Idle<X> {
+    pub fn new(settings: &IdleSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            timer: IdleTimer::new(settings.stages.clone()),
+            inhibit_when: settings.inhibit_when.iter().map(Matcher::compile).collect(),
+            inhibited_by_user: false,
+        }
+    }
+
+    /// Swap in new settings, keeping the manual inhibit toggle as it is.
+    pub fn update(&mut self, settings: &IdleSettings) {
+        if &self.settings != settings {
+            let inhibited_by_user = self.inhibited_by_user;
+            *self = Self::new(settings);
+            self.inhibited_by_user = inhibited_by_user;
+        }
+    }
+
+    /// Turn the manual inhibit on or off, returning whether it is now on.
+    pub fn toggle_inhibit(&mut self) -> bool {
+        self.inhibited_by_user = !self.inhibited_by_user;
+        self.inhibited_by_user
+    }
+
+    /// Whether the idle stages are currently being held off.
+    pub fn is_inhibited(&self, client_set: &ClientSet, x: &X) -> Result<bool> {
+        if self.inhibited_by_user {
+            return Ok(true);
+        }
+
+        if self.settings.inhibit_when_fullscreen {
+            if let Some(&focused) = client_set.current_client() {
+                if let Some(penrose::x::Prop::Atom(states)) = x.get_prop(focused, NET_WM_STATE)? {
+                    if states.iter().any(|s| s == NET_WM_STATE_FULLSCREEN) {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+
+        for &client in client_set.clients() {
+            for query in self.inhibit_when.iter() {
+                if query.run(client, x)? {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Read the idle time from `clock`, returning the actions that are now due.
+    pub fn tick(
+        &mut self,
+        clock: &impl IdleClock,
+        client_set: &ClientSet,
+        x: &X,
+    ) -> Result<Vec<IdleAction>> {
+        if !self.settings.enabled {
+            return Ok(Vec::new());
+        }
+
+        let idle = clock.idle_time()?;
+        let inhibited = self.is_inhibited(client_set, x)?;
+
+        Ok(self.timer.tick(idle, inhibited))
+    }
+}
+
+/// Check the idle time and run any stages that are now due.
+pub fn idle_tick(state: &mut State<RustConn>, x: &RustConn) -> Result<()> {
+    let actions =
+        state
+            .extension::<Idle<RustConn>>()?
+            .borrow_mut()
+            .tick(x, &state.client_set, x)?;
+
+    for action in actions {
+        if let Err(e) = action.run(state) {
+            tracing::error!(%e, ?action, "unable to run idle action");
+        }
+    }
+
+    Ok(())
+}
+
+/// Toggle the manual idle inhibit, showing a notification with its new state.
+pub fn toggle_idle_inhibit(state: &mut State<RustConn>, _: &RustConn) -> Result<()> {
+    let inhibited = state
+        .extension::<Idle<RustConn>>()?
+        .borrow_mut()
+        .toggle_inhibit();
+    tracing::info!(%inhibited, "toggled idle inhibit");
+
+    let msg = if inhibited {
+        "Idle actions are paused"
+    } else {
+        "Idle actions are resumed"
+    };
+    if let Err(e) = util::notify(msg) {
+        tracing::warn!(%e, "unable to send notification");
+    }
+
+    Ok(())
+}
+
+/// Apply the `[idle]` settings after they have changed.
+pub fn reapply_idle_settings(state: &mut State<RustConn>) -> Result<()> {
+    let settings = state.extension::<Settings>()?.borrow().idle.clone();
+    state
+        .extension::<Idle<RustConn>>()?
+        .borrow_mut()
+        .update(&settings);
+
+    Ok(())
+}
+
+/// Spawn a background thread asking the window manager to check the idle time every
+/// [TICK_INTERVAL].
+pub fn watch_idle_time(remote: Remote) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("idle-ticker".to_string())
+        .spawn(move || loop {
+            thread::sleep(TICK_INTERVAL);
+            remote.send(Command::IdleTick);
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{config::Settings, layouts::layouts, mock::MockConn};
+    use penrose::{
+        pure::{geometry::Rect, StackSet},
+        x::Prop,
+    };
+
+    // An idle counter that tests move along by hand.
+    #[derive(Default)]
+    struct FakeClock(Cell<Duration>);
+
+    impl FakeClock {
+        fn wait(&self, secs: u64) {
+            self.0.set(self.0.get() + Duration::from_secs(secs));
+        }
+
+        fn input(&self) {
+            self.0.set(Duration::ZERO);
+        }
+    }
+
+    impl IdleClock for FakeClock {
+        fn idle_time(&self) -> Result<Duration> {
+            Ok(self.0.get())
+        }
+    }
+
+    fn settings() -> IdleSettings {
+        IdleSettings {
+            stages: vec![
+                IdleStage {
+                    after_secs: 600,
+                    action: IdleAction::Lock,
+                },
+                IdleStage {
+                    after_secs: 540,
+                    action: IdleAction::Notify("soon".to_string()),
+                },
+                IdleStage {
+                    after_secs: 900,
+                    action: IdleAction::Suspend,
+                },
+            ],
+            ..IdleSettings::default()
+        }
+    }
+
+    fn client_set(clients: &[u32]) -> ClientSet {
+        let mut cs: ClientSet = StackSet::try_new(
+            layouts(&Settings::default()),
+            ["1", "2"],
+            [Rect::new(0, 0, 1920, 1080)],
+        )
+        .unwrap();
+        for &id in clients {
+            cs.insert(id.into());
+        }
+
+        cs
+    }
+
+    #[test]
+    fn stages_run_once_in_order_until_there_is_input() {
+        let clock = FakeClock::default();
+        let cs = client_set(&[]);
+        let x = MockConn::default();
+        let mut idle: Idle<MockConn> = Idle::new(&settings());
+        let mut tick = |clock: &FakeClock| idle.tick(clock, &cs, &x).unwrap();
+
+        clock.wait(500);
+        assert_eq!(tick(&clock), vec![]);
+        clock.wait(100);
+        assert_eq!(
+            tick(&clock),
+            vec![IdleAction::Notify("soon".to_string()), IdleAction::Lock]
+        );
+        clock.wait(5);
+        assert_eq!(tick(&clock), vec![]);
+
+        clock.input();
+        clock.wait(545);
+        assert_eq!(tick(&clock), vec![IdleAction::Notify("soon".to_string())]);
+        clock.wait(1000);
+        assert_eq!(tick(&clock), vec![IdleAction::Lock, IdleAction::Suspend]);
+        clock.wait(1000);
+        assert_eq!(tick(&clock), vec![]);
+    }
+
+    #[test]
+    fn fullscreen_clients_and_matching_windows_hold_off_the_stages() {
+        let clock = FakeClock::default();
+        let fullscreen = Prop::Atom(vec![NET_WM_STATE_FULLSCREEN.to_string()]);
+        let zoom = Prop::UTF8String(vec!["zoom".into(), "zoom".into()]);
+        let x = MockConn::default()
+            .with_prop(1, NET_WM_STATE, fullscreen)
+            .with_prop(2, "WM_CLASS", zoom.clone())
+            .with_prop(2, "WM_NAME", Prop::UTF8String(vec!["Zoom".into()]))
+            .with_prop(3, "WM_CLASS", zoom)
+            .with_prop(3, "WM_NAME", Prop::UTF8String(vec!["Meeting".into()]));
+        let mut idle: Idle<MockConn> = Idle::new(&IdleSettings {
+            inhibit_when: default_inhibitors(),
+            ..settings()
+        });
+
+        // Focused and fullscreen
+        let cs = client_set(&[2, 1]);
+        clock.wait(1000);
+        assert!(idle.is_inhibited(&cs, &x).unwrap());
+        assert_eq!(idle.tick(&clock, &cs, &x).unwrap(), vec![]);
+
+        // Stages count from the end of the inhibition, not from the last input
+        let cs = client_set(&[1, 2]);
+        assert_eq!(idle.tick(&clock, &cs, &x).unwrap(), vec![]);
+        clock.wait(540);
+        assert_eq!(
+            idle.tick(&clock, &cs, &x).unwrap(),
+            vec![IdleAction::Notify("soon".to_string())]
+        );
+
+        // A zoom meeting window anywhere
+        let cs = client_set(&[1, 2, 3]);
+        assert!(idle.is_inhibited(&cs, &x).unwrap());
+
+        // And the manual toggle
+        let cs = client_set(&[2]);
+        assert!(!idle.is_inhibited(&cs, &x).unwrap());
+        assert!(idle.toggle_inhibit());
+        assert!(idle.is_inhibited(&cs, &x).unwrap());
+        idle.update(&IdleSettings::default());
+        assert!(idle.is_inhibited(&cs, &x).unwrap());
+        assert!(!idle.toggle_inhibit());
+    }
+}
@@ -0,0 +1,620 @@
+//! A Unix socket for driving the window manager from scripts, taking one JSON [Request] per
+//! line and answering each with a JSON [Response].
+use std::{
+    collections::HashSet,
+    fs::{self, DirBuilder},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+use penrose::{
+    core::State,
+    pure::StackSet,
+    x::{event::PropertyEvent, Atom, XConn, XConnExt, XEvent},
+    x11rb::RustConn,
+    Xid,
+};
+use serde::{Deserialize, Serialize};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt};
+
+use crate::{
+    bindings::{parse_chord, run_key_binding, KeyAction, LayoutMessage},
+    remote::{Command, Remote},
+};
+
+const DEMANDS_ATTENTION: &str = "_NET_WM_STATE_DEMANDS_ATTENTION";
+
+// Properties that affect the title or urgency of a client.
+const WATCHED_PROPS: [&str; 4] = ["WM_NAME", "_NET_WM_NAME", "WM_HINTS", "_NET_WM_STATE"];
+
+/// Overrides the default location of the control socket.
+pub const SOCKET_VAR: &str = "FAVILO_PENROSE_SOCKET";
+
+const SOCKET_NAME: &str = "favilo-penrose.sock";
+
+/// The location of the control socket: `$XDG_RUNTIME_DIR/favilo-penrose.sock` unless overridden
+/// by `$FAVILO_PENROSE_SOCKET`.
+///
+/// Without `$XDG_RUNTIME_DIR` the socket goes in a directory of our own under `/tmp`, which is
+/// created if needed and refused if anyone else could have created or written to it.
+pub fn socket_path() -> io::Result<PathBuf> {
+    if let Some(path) = std::env::var_os(SOCKET_VAR) {
+        return Ok(PathBuf::from(path));
+    }
+
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Ok(PathBuf::from(dir).join(SOCKET_NAME)),
+        None => {
+            // SAFETY: getuid has no preconditions and always succeeds
+            let uid = unsafe { libc::getuid() };
+            let dir = std::env::temp_dir().join(format!("favilo-penrose-{uid}"));
+            private_dir(&dir, uid)?;
+
+            Ok(dir.join(SOCKET_NAME))
+        }
+    }
+}
+
+fn private_dir(dir: &Path, uid: u32) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => (),
+    }
+
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory owned by us", dir.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A command sent to the window manager over the control socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Focus {
+        direction: Direction,
+    },
+    FocusTag {
+        tag: String,
+    },
+    MoveToTag {
+        tag: String,
+    },
+    SetLayout {
+        layout: String,
+    },
+    LayoutMessage {
+        message: LayoutMessage,
+    },
+    Spawn {
+        cmd: String,
+    },
+    Reload,
+    /// Run any action that can be bound in the `[keys]` table of the config file.
+    Action {
+        action: KeyAction,
+    },
+    /// Run whatever is currently bound to the given key chord.
+    Key {
+        chord: String,
+    },
+    QueryState,
+    Subscribe,
+}
+
+/// The direction to move focus in for [Request::Focus].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// The reply to a [Request].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    State { state: WmState },
+    Error { message: String },
+}
+
+/// A summary of the window manager state, returned from `query-state` and written to
+/// subscribers each time it changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WmState {
+    pub tags: Vec<TagState>,
+    pub focused_tag: String,
+    pub layout: String,
+    /// The index of the focused screen
+    pub screen: usize,
+    pub focused_client: Option<Xid>,
+    /// The title of the focused client
+    pub title: Option<String>,
+}
+
+/// The state of a single workspace within a [WmState].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TagState {
+    pub tag: String,
+    pub clients: usize,
+    pub occupied: bool,
+    /// Whether any client on this tag is asking for attention
+    pub urgent: bool,
+    pub focused: bool,
+    /// The index of the screen this tag is shown on, if any
+    pub screen: Option<usize>,
+}
+
+impl Request {
+    /// Build a request from command line arguments such as `move-to-tag 3` or `focus up`.
+    pub fn from_args<S: AsRef<str>>(args: &[S]) -> Result<Self, String> {
+        let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+        let (command, rest) = args.split_first().ok_or("no command given")?;
+        let rest = rest.join(" ");
+        let required = |what: &str| {
+            if rest.is_empty() {
+                Err(format!("'{command}' requires {what}"))
+            } else {
+                Ok(rest.clone())
+            }
+        };
+        let no_args = |req: Request| {
+            if rest.is_empty() {
+                Ok(req)
+            } else {
+                Err(format!(
+                    "'{command}' does not take any arguments: got '{rest}'"
+                ))
+            }
+        };
+
+        match *command {
+            "focus" => match rest.as_str() {
+                "up" => Ok(Self::Focus {
+                    direction: Direction::Up,
+                }),
+                "down" => Ok(Self::Focus {
+                    direction: Direction::Down,
+                }),
+                _ => Err(format!("focus expects 'up' or 'down': got '{rest}'")),
+            },
+            "focus-tag" => required("a tag").map(|tag| Self::FocusTag { tag }),
+            "move-to-tag" => required("a tag").map(|tag| Self::MoveToTag { tag }),
+            "set-layout" => required("a layout name").map(|layout| Self::SetLayout { layout }),
+            "layout-message" => Ok(Self::LayoutMessage {
+                message: required("a message")?.parse()?,
+            }),
+            "spawn" => required("a command to run").map(|cmd| Self::Spawn { cmd }),
+            "reload" => no_args(Self::Reload),
+            "action" => Ok(Self::Action {
+                action: required("an action")?.parse()?,
+            }),
+            "key" => required("a key chord").map(|chord| Self::Key { chord }),
+            "query-state" | "state" => no_args(Self::QueryState),
+            "subscribe" => no_args(Self::Subscribe),
+            _ => Err(format!(
+                "unknown command '{command}': expected one of focus, focus-tag, move-to-tag, \
+                 set-layout, layout-message, spawn, reload, action, key, query-state, subscribe"
+            )),
+        }
+    }
+
+    /// The [KeyAction] this request maps on to, if any.
+    pub fn action(&self) -> Option<KeyAction> {
+        let action = match self {
+            Self::Focus {
+                direction: Direction::Up,
+            } => KeyAction::FocusUp,
+            Self::Focus {
+                direction: Direction::Down,
+            } => KeyAction::FocusDown,
+            Self::FocusTag { tag } => KeyAction::FocusTag(tag.clone()),
+            Self::MoveToTag { tag } => KeyAction::MoveToTag(tag.clone()),
+            Self::SetLayout { layout } => KeyAction::SetLayout(layout.clone()),
+            Self::LayoutMessage { message } => KeyAction::LayoutMessage(*message),
+            Self::Spawn { cmd } => KeyAction::Spawn(cmd.clone()),
+            Self::Reload => KeyAction::Reload,
+            Self::Action { action } => action.clone(),
+            Self::Key { .. } | Self::QueryState | Self::Subscribe => return None,
+        };
+
+        Some(action)
+    }
+}
+
+impl WmState {
+    /// Summarise the given client set. `title` is the title of the focused client and
+    /// `is_urgent` is used to check whether a client is asking for attention.
+    pub fn new(cs: &StackSet<Xid>, title: Option<String>, is_urgent: impl Fn(Xid) -> bool) -> Self {
+        let screen_for_tag = |tag: &str| {
+            cs.screens()
+                .find(|s| s.workspace.tag() == tag)
+                .map(|s| s.index())
+        };
+
+        let tags = cs
+            .ordered_tags()
+            .into_iter()
+            .filter_map(|tag| {
+                let ws = cs.workspaces().find(|w| w.tag() == tag)?;
+                let clients = ws.clients().count();
+
+                Some(TagState {
+                    clients,
+                    occupied: clients > 0,
+                    urgent: ws.clients().any(|&c| is_urgent(c)),
+                    focused: tag == cs.current_tag(),
+                    screen: screen_for_tag(&tag),
+                    tag,
+                })
+            })
+            .collect();
+
+        Self {
+            tags,
+            focused_tag: cs.current_tag().to_string(),
+            layout: cs.current_workspace().layout_name(),
+            screen: cs.current_screen().index(),
+            focused_client: cs.current_client().copied(),
+            title,
+        }
+    }
+
+    /// Summarise the current window manager state.
+    pub fn capture(state: &State<RustConn>, x: &RustConn) -> Self {
+        let title = state
+            .client_set
+            .current_client()
+            .and_then(|&id| x.window_title(id).ok());
+
+        let urgent = urgent_clients(&state.client_set, x).unwrap_or_else(|e| {
+            tracing::warn!(%e, "unable to check for urgent clients");
+            HashSet::new()
+        });
+
+        Self::new(&state.client_set, title, |id| urgent.contains(&id))
+    }
+}
+
+// The clients that have set the ICCCM urgency hint or the EWMH demands attention state.
+//
+// This runs on every refresh so the properties of all clients are requested together before
+// waiting on any of the replies.
+fn urgent_clients(cs: &StackSet<Xid>, x: &RustConn) -> penrose::Result<HashSet<Xid>> {
+    const URGENCY_HINT: u32 = 1 << 8;
+
+    let conn = x.connection();
+    let net_wm_state = *x.intern_atom(Atom::NetWmState.as_ref())?;
+    let demands_attention = *x.intern_atom(DEMANDS_ATTENTION)?;
+
+    // penrose doesn't expose the flags of a parsed WmHints so we need to check the raw property
+    let cookies = cs
+        .clients()
+        .map(|&id| {
+            let state = conn.get_property(false, *id, net_wm_state, AtomEnum::ATOM, 0, 1024)?;
+            let hints =
+                conn.get_property(false, *id, AtomEnum::WM_HINTS, AtomEnum::WM_HINTS, 0, 1)?;
+            Ok((id, state, hints))
+        })
+        .collect::<penrose::Result<Vec<_>>>()?;
+
+    let mut urgent = HashSet::new();
+    for (id, state, hints) in cookies {
+        let state = state.reply().ok();
+        let hints = hints.reply().ok();
+
+        let is_demanding = state
+            .and_then(|r| {
+                r.value32()
+                    .map(|mut atoms| atoms.any(|a| a == demands_attention))
+            })
+            .unwrap_or(false);
+        let is_hinted = hints
+            .and_then(|r| r.value32().and_then(|mut v| v.next()))
+            .is_some_and(|f| f & URGENCY_HINT != 0);
+
+        if is_demanding || is_hinted {
+            urgent.insert(id);
+        }
+    }
+
+    Ok(urgent)
+}
+
+/// Start listening on the control socket, handing requests off to the window manager via the
+/// given [Remote].
+pub fn serve(path: impl AsRef<Path>, remote: Remote) -> io::Result<()> {
+    let path = path.as_ref();
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already in use", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    tracing::info!(?path, "listening for IPC connections");
+
+    thread::Builder::new()
+        .name("ipc".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let remote = remote.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_connection(stream, remote) {
+                                tracing::debug!(%e, "IPC connection closed");
+                            }
+                        });
+                    }
+                    Err(e) => tracing::error!(%e, "unable to accept IPC connection"),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_connection(stream: UnixStream, remote: Remote) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(Request::Subscribe) => return stream_events(writer, remote),
+            Ok(request) => remote.request(request),
+            Err(e) => Response::Error {
+                message: format!("invalid request: {e}"),
+            },
+        };
+
+        write_line(&mut writer, &response)?;
+    }
+
+    Ok(())
+}
+
+fn stream_events(mut writer: UnixStream, remote: Remote) -> io::Result<()> {
+    let (tx, rx) = channel();
+    remote.send(Command::Subscribe(tx));
+
+    for line in rx {
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+fn write_line(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    let json = serde_json::to_string(response)?;
+    writer.write_all(json.as_bytes())?;
+    writer.write_all(b"\n")
+}
+
+/// Run a [Request] against the window manager.
+pub fn handle_request(request: Request, state: &mut State<RustConn>, x: &RustConn) -> Response {
+    let res = match request {
+        Request::QueryState => {
+            return Response::State {
+                state: WmState::capture(state, x),
+            }
+        }
+
+        Request::Subscribe => {
+            return Response::Error {
+                message: "subscribe can only be sent over the control socket".to_string(),
+            }
+        }
+
+        Request::Key { ref chord } => parse_chord(chord)
+            .and_then(|code| run_key_binding(code, state, x))
+            .inspect(|&bound| {
+                if !bound {
+                    tracing::warn!(%chord, "nothing bound to key chord");
+                }
+            }),
+
+        request => {
+            let action = request
+                .action()
+                .expect("only key, query-state and subscribe lack actions");
+            action.into_handler().call(state, x).map(|_| true)
+        }
+    };
+
+    match res {
+        Ok(true) => Response::Ok,
+        Ok(false) => Response::Error {
+            message: "nothing is bound to that key chord".to_string(),
+        },
+        Err(e) => Response::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Everyone subscribed to state changes, stored as a [State] extension.
+#[derive(Debug, Default)]
+pub struct EventStream {
+    subscribers: Vec<Sender<String>>,
+    last: Option<WmState>,
+}
+
+impl EventStream {
+    /// Add a new subscriber, sending them the current state straight away.
+    pub fn subscribe(&mut self, tx: Sender<String>, current: WmState) {
+        if let Ok(line) = serde_json::to_string(&current) {
+            if tx.send(line).is_ok() {
+                self.subscribers.push(tx);
+            }
+        }
+        self.last = Some(current);
+    }
+
+    /// Send the given state to all subscribers if it differs from the last one sent, dropping any
+    /// subscribers that have since disconnected.
+    pub fn publish(&mut self, current: WmState) {
+        if self.last.as_ref() == Some(&current) {
+            return;
+        }
+
+        match serde_json::to_string(&current) {
+            Ok(line) => self.subscribers.retain(|tx| tx.send(line.clone()).is_ok()),
+            Err(e) => tracing::error!(%e, "unable to serialize state"),
+        }
+        self.last = Some(current);
+    }
+
+    /// Whether anyone is currently subscribed.
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+}
+
+/// An event hook publishing the current state to the [EventStream] when a property shown in the
+/// [WmState] changes without triggering a refresh.
+pub fn publish_on_property_change(
+    event: &XEvent,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<bool> {
+    if let XEvent::PropertyNotify(PropertyEvent {
+        atom,
+        is_root: false,
+        ..
+    }) = event
+    {
+        if WATCHED_PROPS.contains(&atom.as_str()) {
+            publish_state(state, x)?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// A refresh hook publishing the current state to the [EventStream].
+pub fn publish_state(state: &mut State<RustConn>, x: &RustConn) -> penrose::Result<()> {
+    let stream = state.extension::<EventStream>()?;
+    if stream.borrow().is_empty() {
+        return Ok(());
+    }
+
+    let current = WmState::capture(state, x);
+    stream.borrow_mut().publish(current);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn requests_round_trip_through_json() {
+        let requests = [
+            Request::Focus {
+                direction: Direction::Up,
+            },
+            Request::MoveToTag {
+                tag: "3".to_string(),
+            },
+            Request::LayoutMessage {
+                message: LayoutMessage::IncMain(-1),
+            },
+            Request::Action {
+                action: KeyAction::SwapDown,
+            },
+            Request::QueryState,
+        ];
+
+        for request in requests {
+            let json = serde_json::to_string(&request).unwrap();
+            assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn requests_parse_from_json() {
+        let request: Request =
+            serde_json::from_str(r#"{"command": "layout-message", "message": "expand-main"}"#)
+                .unwrap();
+
+        assert_eq!(
+            request.action(),
+            Some(KeyAction::LayoutMessage(LayoutMessage::ExpandMain))
+        );
+    }
+
+    #[test]
+    fn requests_parse_from_args() {
+        let cases = [
+            (
+                vec!["focus", "down"],
+                Request::Focus {
+                    direction: Direction::Down,
+                },
+            ),
+            (
+                vec!["spawn", "kitty", "--single-instance"],
+                Request::Spawn {
+                    cmd: "kitty --single-instance".to_string(),
+                },
+            ),
+            (
+                vec!["action", "move-to-tag", "2"],
+                Request::Action {
+                    action: KeyAction::MoveToTag("2".to_string()),
+                },
+            ),
+            (vec!["state"], Request::QueryState),
+        ];
+
+        for (args, expected) in cases {
+            assert_eq!(Request::from_args(&args), Ok(expected));
+        }
+
+        assert!(Request::from_args(&["focus", "left"]).is_err());
+        assert!(Request::from_args(&["reload", "now"]).is_err());
+        assert!(Request::from_args::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn the_fallback_socket_dir_must_be_private() {
+        let tmp = tempfile::tempdir().unwrap();
+        let uid = fs::metadata(tmp.path()).unwrap().uid();
+
+        let dir = tmp.path().join("created");
+        private_dir(&dir, uid).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        private_dir(&dir, uid).unwrap();
+
+        let shared = tmp.path().join("shared");
+        DirBuilder::new().mode(0o777).create(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(private_dir(&shared, uid).is_err());
+
+        assert!(private_dir(&dir, uid + 1).is_err());
+    }
+}
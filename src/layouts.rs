@@ -1,21 +1,33 @@
+use std::{collections::HashMap, sync::Arc};
+
 use penrose::{
     builtin::layout::{
+        messages::{ExpandMain, IncMain, ShrinkMain},
         transformers::{Gaps, ReflectHorizontal, ReserveTop},
         MainAndStack, Monocle,
     },
-    core::layout::Layout,
-    pure::Stack,
-    stack,
+    core::layout::{Layout, LayoutTransformer},
+    pure::{geometry::Rect, Stack, Workspace},
+    stack, Xid,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::BAR_HEIGHT_PX;
+use crate::{
+    bindings::LayoutMessage,
+    config::{LayoutSettings, Settings},
+    size_hints::TrackedSizeHints,
+    struts::{ReserveStruts, Struts},
+};
 
-pub fn layouts() -> Stack<Box<dyn Layout>> {
-    let max_main = 1;
-    let ratio = 0.6;
-    let ratio_step = 0.1;
-    let outer_px = 0;
-    let inner_px = 0;
+pub fn layouts(settings: &Settings) -> Stack<Box<dyn Layout>> {
+    let LayoutSettings {
+        max_main,
+        ratio,
+        ratio_step,
+        outer_px,
+        inner_px,
+        respect_size_hints,
+    } = settings.layout;
 
     stack!(
         MainAndStack::side(max_main, ratio, ratio_step),
@@ -23,5 +35,200 @@ pub fn layouts() -> Stack<Box<dyn Layout>> {
         MainAndStack::bottom(max_main, ratio, ratio_step),
         Monocle::boxed()
     )
-    .map(|layout| ReserveTop::wrap(Gaps::wrap(layout, outer_px, inner_px), BAR_HEIGHT_PX))
+    .map(|layout| {
+        let layout = Gaps::wrap(layout, outer_px, inner_px);
+        let layout = if respect_size_hints {
+            ResizeIncrements::wrap(layout, TrackedSizeHints::global())
+        } else {
+            layout
+        };
+        let layout = ReserveStruts::wrap(layout, Struts::global());
+
+        // The built in bar doesn't set a strut
+        if settings.bar.enabled {
+            ReserveTop::wrap(layout, settings.bar.height_px)
+        } else {
+            layout
+        }
+    })
+}
+
+/// Shrink each client to a whole number of its resize increments, centring it in the space
+/// that the wrapped layout gave it.
+#[derive(Debug, Clone)]
+pub struct ResizeIncrements {
+    pub layout: Box<dyn Layout>,
+    hints: Arc<TrackedSizeHints>,
+}
+
+impl ResizeIncrements {
+    /// Wrap an existing [Layout], looking up the hints of each client in `hints`.
+    pub fn wrap(layout: Box<dyn Layout>, hints: Arc<TrackedSizeHints>) -> Box<dyn Layout> {
+        Box::new(Self { layout, hints })
+    }
+}
+
+impl LayoutTransformer for ResizeIncrements {
+    fn transformed_name(&self) -> String {
+        self.layout.name()
+    }
+
+    fn inner_mut(&mut self) -> &mut Box<dyn Layout> {
+        &mut self.layout
+    }
+
+    fn transform_positions(&mut self, _: Rect, positions: Vec<(Xid, Rect)>) -> Vec<(Xid, Rect)> {
+        positions
+            .into_iter()
+            .map(|(id, r)| match self.hints.get(id) {
+                // Clients with a min size larger than they have been given are left to overflow
+                Some(hints) => (
+                    id,
+                    hints.round_to_increments(r).centered_in(&r).unwrap_or(r),
+                ),
+                None => (id, r),
+            })
+            .collect()
+    }
+}
+
+/// The main area parameters of a layout after it has been adjusted by [LayoutMessage]s.
+///
+/// Layouts keep their parameters private so these are tracked alongside them in order for them
+/// to be saved and restored across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MainParams {
+    pub max_main: u32,
+    pub ratio: f32,
+}
+
+impl MainParams {
+    fn new(settings: &LayoutSettings) -> Self {
+        Self {
+            max_main: settings.max_main,
+            ratio: settings.ratio,
+        }
+    }
+
+    // Mirrors the handling of layout messages in MainAndStack
+    fn apply(&mut self, msg: LayoutMessage, settings: &LayoutSettings) {
+        match msg {
+            LayoutMessage::IncMain(n) if n < 0 => {
+                self.max_main = self.max_main.saturating_sub(n.unsigned_abs() as u32)
+            }
+            LayoutMessage::IncMain(n) => self.max_main += n as u32,
+            LayoutMessage::ExpandMain => self.ratio = (self.ratio + settings.ratio_step).min(1.0),
+            LayoutMessage::ShrinkMain => self.ratio = (self.ratio - settings.ratio_step).max(0.0),
+        }
+    }
+
+    /// The messages needed to take a freshly created layout to these parameters.
+    pub fn messages(&self, settings: &LayoutSettings) -> Vec<LayoutMessage> {
+        let mut messages = Vec::new();
+
+        let mut inc = self.max_main as i64 - settings.max_main as i64;
+        while inc != 0 {
+            let n = inc.clamp(i8::MIN as i64, i8::MAX as i64);
+            messages.push(LayoutMessage::IncMain(n as i8));
+            inc -= n;
+        }
+
+        let steps = ((self.ratio - settings.ratio) / settings.ratio_step).round() as i32;
+        let msg = if steps < 0 {
+            LayoutMessage::ShrinkMain
+        } else {
+            LayoutMessage::ExpandMain
+        };
+        messages.extend(std::iter::repeat_n(msg, steps.unsigned_abs() as usize));
+
+        messages
+    }
+}
+
+/// The [MainParams] of every layout that has been sent a [LayoutMessage], keyed by workspace tag
+/// and layout name. Stored as a [State][penrose::core::State] extension.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LayoutParams(HashMap<(String, String), MainParams>);
+
+impl LayoutParams {
+    /// Record a message being sent to the named layout on the given tag.
+    pub fn record(
+        &mut self,
+        tag: &str,
+        layout: &str,
+        msg: LayoutMessage,
+        settings: &LayoutSettings,
+    ) {
+        self.0
+            .entry((tag.to_string(), layout.to_string()))
+            .or_insert_with(|| MainParams::new(settings))
+            .apply(msg, settings);
+    }
+
+    /// The adjusted layouts for the given tag.
+    pub fn for_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = (&'a str, MainParams)> {
+        self.0
+            .iter()
+            .filter(move |((t, _), _)| t == tag)
+            .map(|((_, layout), params)| (layout.as_str(), *params))
+    }
+
+    /// Re-apply previously recorded parameters to the layouts of a workspace, leaving the
+    /// workspace on the layout it started with.
+    pub fn restore(
+        &mut self,
+        ws: &mut Workspace<Xid>,
+        params: &[(String, MainParams)],
+        settings: &LayoutSettings,
+    ) {
+        let current = ws.layout_name();
+        for (layout, p) in params {
+            ws.set_layout_by_name(layout);
+            if &ws.layout_name() != layout {
+                continue; // no longer available
+            }
+            for msg in p.messages(settings) {
+                match msg {
+                    LayoutMessage::IncMain(n) => ws.handle_message(IncMain(n)),
+                    LayoutMessage::ExpandMain => ws.handle_message(ExpandMain),
+                    LayoutMessage::ShrinkMain => ws.handle_message(ShrinkMain),
+                }
+            }
+            self.0.insert((ws.tag().to_string(), layout.clone()), *p);
+        }
+        ws.set_layout_by_name(&current);
+    }
+
+    /// Forget all recorded parameters, e.g. after the layouts have been rebuilt.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::size_hints::SizeHints;
+
+    #[test]
+    fn tiled_clients_are_rounded_to_increments_and_centred() {
+        let mut raw = [0; 18];
+        // P_RESIZE_INC with 10x20 steps
+        raw[0] = 1 << 6;
+        raw[9..11].copy_from_slice(&[10, 20]);
+
+        let hints = Arc::new(TrackedSizeHints::default());
+        hints.insert(Xid::from(1), Some(SizeHints::try_from_raw(&raw).unwrap()));
+        let mut layout = ResizeIncrements::wrap(MainAndStack::side(1, 0.5, 0.1), hints);
+
+        let (_, positions) = layout.layout(&stack!(1, 2).map(Xid::from), Rect::new(0, 0, 205, 105));
+
+        assert_eq!(
+            positions,
+            vec![
+                (Xid::from(1), Rect::new(1, 2, 100, 100)),
+                (Xid::from(2), Rect::new(102, 0, 103, 105)),
+            ]
+        );
+    }
 }
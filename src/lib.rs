@@ -1,21 +1,28 @@
+pub mod autostart;
 pub mod bar;
 pub mod bindings;
+pub mod config;
+pub mod gravity;
 pub mod hooks;
+pub mod idle;
+pub mod ipc;
 pub mod layouts;
+pub mod lock;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
 pub mod mouse;
-
-pub const STARTUP_SCRIPT: &str = "/usr/local/scripts/penrose-startup.sh";
-
-const FONT: &str = "ProFontIIx Nerd Font";
-const BLACK: u32 = 0x282828ff;
-const WHITE: u32 = 0xebdbb2ff;
-const GREY: u32 = 0x3c3836ff;
-const BLUE: u32 = 0x458588ff;
-
-pub const BAR_HEIGHT_PX: u32 = 28;
-const POINT_SIZE: u8 = 10;
-
-const MAX_ACTIVE_WINDOW_CHARS: usize = 50;
-
-
-
+pub mod notifications;
+pub mod power;
+pub mod props;
+pub mod reload;
+pub mod remote;
+pub mod rules;
+pub mod session;
+pub mod size_hints;
+pub mod sleep;
+pub mod struts;
+#[cfg(test)]
+mod test_util;
+pub mod tray;
+pub mod weather;
+pub mod xdg_autostart;
@@ -0,0 +1,211 @@
+//! Locking the screen with a locker that stays running for as long as the screen is locked.
+use std::{
+    io,
+    process::{Child, Command as Process, Stdio},
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use penrose::{core::State, x11rb::RustConn};
+use x11rb::{
+    connection::Connection,
+    properties::WmClass,
+    protocol::{
+        xproto::{ChangeWindowAttributesAux, ConnectionExt, EventMask, Window},
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    config::{LockSettings, Settings},
+    remote::{Command, Remote},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The running screen locker, if any. Stored as a [State] extension.
+#[derive(Debug, Default)]
+pub struct Locker {
+    child: Option<Child>,
+}
+
+impl Locker {
+    /// Whether a locker we started is still running, reaping it if it has exited.
+    pub fn is_locked(&mut self) -> bool {
+        match self.child.as_mut().map(|c| c.try_wait()) {
+            Some(Ok(None)) => true,
+            Some(Ok(Some(status))) => {
+                tracing::debug!(%status, "screen locker exited");
+                self.child = None;
+                false
+            }
+            // Penrose ignores SIGCHLD so exited children are reaped before we get to them
+            Some(Err(e)) if e.raw_os_error() == Some(libc::ECHILD) => {
+                tracing::debug!("screen locker exited");
+                self.child = None;
+                false
+            }
+            Some(Err(e)) => {
+                tracing::warn!(%e, "unable to check on the screen locker");
+                self.child = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Run the configured locker unless the screen is already locked, returning whether a new
+    /// locker was started.
+    pub fn lock(&mut self, settings: &LockSettings) -> io::Result<bool> {
+        if self.is_locked() {
+            tracing::debug!("screen is already locked");
+            return Ok(false);
+        }
+
+        tracing::info!(command = %settings.command, "locking the screen");
+        let child = Process::new("sh")
+            .arg("-c")
+            .arg(&settings.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        self.child = Some(child);
+
+        Ok(true)
+    }
+}
+
+/// Lock the screen using the [Locker] extension, returning whether a new locker was started.
+pub fn lock_screen(state: &mut State<RustConn>) -> penrose::Result<bool> {
+    let settings = state.extension::<Settings>()?.borrow().lock.clone();
+    let started = state.extension::<Locker>()?.borrow_mut().lock(&settings)?;
+
+    Ok(started)
+}
+
+/// Locks the screen from outside of the main event loop, waiting until the lock window has
+/// actually been mapped.
+///
+/// This needs its own connection to the X server: the window manager is busy starting the
+/// locker when the window appears.
+#[derive(Debug)]
+pub struct LockWatcher {
+    conn: RustConnection,
+    root: Window,
+    window_class: String,
+}
+
+impl LockWatcher {
+    /// Connect to the X server on `display`, or `$DISPLAY` if not given.
+    pub fn try_new(display: Option<&str>, settings: &LockSettings) -> penrose::Result<Self> {
+        let (conn, screen_num) = x11rb::connect(display)?;
+        let root = conn.setup().roots[screen_num].root;
+
+        Ok(Self {
+            conn,
+            root,
+            window_class: settings.window_class.clone(),
+        })
+    }
+
+    /// Ask the window manager to lock the screen, returning once the lock window is mapped or
+    /// `timeout` has passed. Returns straight away if the screen was already locked.
+    pub fn lock(&self, remote: &Remote, timeout: Duration) -> penrose::Result<bool> {
+        self.lock_with(|tx| remote.send(Command::Lock(tx)), timeout)
+    }
+
+    /// As [LockWatcher::lock], with `start` asked to start the locker and send back whether it
+    /// did. Returns whether the screen is known to be locked.
+    pub fn lock_with(
+        &self,
+        start: impl FnOnce(Sender<bool>),
+        timeout: Duration,
+    ) -> penrose::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        self.select_map_events(true)?;
+
+        let (tx, rx) = channel();
+        start(tx);
+        let res = match rx.recv_timeout(timeout) {
+            Ok(true) => self.wait_for_lock_window(deadline),
+            Ok(false) => Ok(true),
+            Err(_) => {
+                tracing::warn!("timed out waiting for the window manager to lock the screen");
+                Ok(false)
+            }
+        };
+
+        self.select_map_events(false)?;
+        while self.conn.poll_for_event()?.is_some() {}
+
+        res
+    }
+
+    fn select_map_events(&self, selected: bool) -> penrose::Result<()> {
+        let mask = if selected {
+            EventMask::SUBSTRUCTURE_NOTIFY
+        } else {
+            EventMask::NO_EVENT
+        };
+        let aux = ChangeWindowAttributesAux::new().event_mask(mask);
+        self.conn.change_window_attributes(self.root, &aux)?;
+        // Round-trip so that the mask is in place before the locker is started
+        self.conn.get_input_focus()?.reply()?;
+
+        Ok(())
+    }
+
+    fn wait_for_lock_window(&self, deadline: Instant) -> penrose::Result<bool> {
+        while Instant::now() < deadline {
+            match self.conn.poll_for_event()? {
+                Some(Event::MapNotify(e)) if self.is_lock_window(e.window) => {
+                    tracing::debug!(window = e.window, "lock window mapped");
+                    return Ok(true);
+                }
+                Some(_) => (),
+                None => thread::sleep(POLL_INTERVAL),
+            }
+        }
+
+        tracing::warn!(class = %self.window_class, "timed out waiting for the lock window");
+        Ok(false)
+    }
+
+    fn is_lock_window(&self, window: Window) -> bool {
+        // The window may well have gone again by the time we ask
+        let class = WmClass::get(&self.conn, window)
+            .ok()
+            .and_then(|cookie| cookie.reply_unchecked().ok().flatten());
+
+        class.is_some_and(|c| c.class() == self.window_class.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_screen_is_only_locked_once() {
+        let settings = LockSettings {
+            command: "sleep 5".to_string(),
+            ..LockSettings::default()
+        };
+        let mut locker = Locker::default();
+        assert!(!locker.is_locked());
+
+        assert!(locker.lock(&settings).unwrap());
+        let pid = locker.child.as_ref().unwrap().id();
+        assert!(locker.is_locked());
+
+        assert!(!locker.lock(&settings).unwrap());
+        assert_eq!(locker.child.as_ref().unwrap().id(), pid);
+
+        locker.child.as_mut().unwrap().kill().unwrap();
+        locker.child.as_mut().unwrap().wait().unwrap();
+        assert!(!locker.is_locked());
+    }
+}
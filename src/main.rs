@@ -2,18 +2,36 @@
 //!
 //! This file will give you a functional if incredibly minimal window manager that
 //! has multiple workspaces and simple client / workspace movement.
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::HashMap, str::FromStr};
 
 use color_eyre::eyre::{Context, Result};
 use penrose::{
-    core::{bindings::parse_keybindings_with_xmodmap, Config, WindowManager},
-    extensions::hooks::{add_ewmh_hooks, SpawnOnStartup},
+    core::{Config, WindowManager},
+    extensions::hooks::add_ewmh_hooks,
     x11rb::RustConn,
 };
 
 use favilo_penrose::{
-    bindings::raw_key_bindings, hooks::manage_hook, layouts::layouts, mouse::mouse_bindings,
-    STARTUP_SCRIPT,
+    autostart::autostart,
+    bar::status_bar,
+    bindings::{grab_key_bindings, key_bindings_event_hook, DynamicKeyBindings},
+    config::{self, Settings},
+    gravity::configure_request_with_gravity,
+    hooks::manage_hook,
+    idle::{watch_idle_time, Idle},
+    ipc::{self, publish_on_property_change, publish_state, EventStream},
+    layouts::layouts,
+    layouts::LayoutParams,
+    lock::Locker,
+    mouse::{mouse_bindings, track_mouse_position, MouseHandler},
+    notifications::{reflow_notifications, NotificationStack},
+    power::{exit_once_logged_out, is_logging_out, LOGOUT_EXIT_CODE},
+    reload::{reload_on_sighup, watch_config_file},
+    remote::{remote_event_hook, Remote},
+    session::restore_session,
+    size_hints::track_size_hints,
+    sleep::lock_before_sleep,
+    struts::{track_existing_struts, track_struts},
 };
 
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
@@ -22,25 +40,77 @@ use tracing_subscriber::{self, fmt, prelude::*, EnvFilter};
 fn main() -> Result<()> {
     setup_logging()?;
 
-    let config = add_ewmh_hooks(Config {
-        startup_hook: Some(SpawnOnStartup::boxed(STARTUP_SCRIPT)),
-        default_layouts: layouts(),
-        manage_hook: Some(manage_hook()),
+    let settings = Settings::load().context("Load settings")?;
+
+    let mut config = add_ewmh_hooks(Config {
+        normal_border: settings.theme.normal_border,
+        focused_border: settings.theme.focused_border,
+        border_width: settings.theme.border_width,
+        default_layouts: layouts(&settings),
+        manage_hook: Some(manage_hook(&settings)),
         ..Config::default()
     });
+    // Startup hooks run in the reverse order to how they are composed and restoring the session
+    // needs to come last.
+    config.compose_or_set_startup_hook(restore_session);
+    config.compose_or_set_startup_hook(autostart);
+    config.compose_or_set_startup_hook(grab_key_bindings);
+    config.compose_or_set_startup_hook(track_existing_struts);
+    config.compose_or_set_event_hook(track_mouse_position);
+    config.compose_or_set_event_hook(key_bindings_event_hook);
+    config.compose_or_set_event_hook(track_size_hints);
+    config.compose_or_set_event_hook(configure_request_with_gravity);
+    config.compose_or_set_event_hook(track_struts);
+    config.compose_or_set_event_hook(remote_event_hook);
+    config.compose_or_set_event_hook(publish_on_property_change);
+    config.compose_or_set_refresh_hook(reflow_notifications);
+    config.compose_or_set_refresh_hook(publish_state);
+    config.compose_or_set_refresh_hook(exit_once_logged_out);
 
     let conn = RustConn::new().context("X conn")?;
-    let key_bindings =
-        parse_keybindings_with_xmodmap(raw_key_bindings()).context("Parse keybindings")?;
-
-    // let bar = status_bar().context("Create status bar")?;
 
     let mouse_bindings = mouse_bindings();
+    let key_bindings =
+        DynamicKeyBindings::try_new(&settings, mouse_bindings.keys().cloned().collect())
+            .context("Parse keybindings")?;
 
-    let wm = WindowManager::new(config, key_bindings, mouse_bindings, conn)
+    // Key bindings are handled by key_bindings_event_hook so that they can be swapped out when
+    // the config file is reloaded.
+    let mut wm = WindowManager::new(config, HashMap::new(), mouse_bindings, conn)
         .context("New window manager")?;
+    if settings.bar.enabled {
+        let bar = status_bar(&settings).context("Create status bar")?;
+        wm = bar.add_to(wm);
+    }
+    wm.add_extension(key_bindings);
+    wm.add_extension(Idle::<RustConn>::new(&settings.idle));
+    let lock_settings = settings.lock.clone();
+    wm.add_extension(settings);
+    wm.add_extension(LayoutParams::default());
+    wm.add_extension(MouseHandler::default());
+    wm.add_extension(NotificationStack::default());
+    wm.add_extension(EventStream::default());
+    wm.add_extension(Locker::default());
+
+    let (remote, inbox) = Remote::try_new().context("Remote connection")?;
+    wm.add_extension(inbox);
+    wm.add_extension(remote.clone());
+    watch_config_file(Settings::path(), remote.clone()).context("Watch config file")?;
+    reload_on_sighup(remote.clone()).context("Register SIGHUP handler")?;
+    watch_idle_time(remote.clone()).context("Start idle timer")?;
+    if lock_settings.before_sleep {
+        lock_before_sleep(&lock_settings, remote.clone()).context("Start sleep inhibitor")?;
+    }
+    // Another instance (e.g. under Xephyr) may already own the socket
+    if let Err(e) = ipc::socket_path().and_then(|path| ipc::serve(path, remote)) {
+        tracing::error!(%e, "unable to start the IPC server: running without it");
+    }
 
     wm.run().context("Window manager run")?;
+    if is_logging_out() {
+        std::process::exit(LOGOUT_EXIT_CODE);
+    }
+
     Ok(())
 }
 
@@ -48,12 +118,7 @@ fn setup_logging() -> Result<()> {
     color_eyre::install()?;
 
     // Create log directory if it doesn't exist
-    let config_home = PathBuf::from(
-        std::env::var("XDG_CONFIG_HOME")
-            .unwrap_or_else(|_| format!("{}/.config", std::env::var("HOME").unwrap())),
-    );
-    let penrose_home = config_home.join("penrose");
-    let log_home = penrose_home.join("logs");
+    let log_home = config::config_home().join("logs");
     std::fs::create_dir_all(&log_home)?;
 
     let log_file = RollingFileAppender::builder()
@@ -77,7 +142,8 @@ fn setup_logging() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use favilo_penrose::bindings::raw_key_bindings;
+    use penrose::core::bindings::parse_keybindings_with_xmodmap;
 
     #[test]
     fn bindings_parse_correctly_with_xmodmap() {
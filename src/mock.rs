@@ -0,0 +1,233 @@
+//! A hand written [XConn] for tests that serves window properties from memory and counts the
+//! round-trips that a real connection would have made to the X server.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+use penrose::{
+    core::bindings::{KeyCode, MouseState},
+    pure::geometry::{Point, Rect},
+    x::{
+        event::ClientMessage,
+        property::{WindowAttributes, WmState},
+        ClientAttr, ClientConfig, Prop, XConn, XEvent,
+    },
+    Error, Result, Xid,
+};
+
+pub const SCREEN: Rect = Rect::new(0, 0, 1920, 1080);
+pub const ROOT: u32 = 0x4a3;
+
+#[derive(Debug, Default)]
+pub struct MockConn {
+    props: HashMap<(Xid, String), Prop>,
+    pipelined: bool,
+    round_trips: Cell<usize>,
+    attributes: RefCell<HashMap<Xid, Vec<ClientAttr>>>,
+}
+
+impl MockConn {
+    pub fn with_prop(mut self, id: u32, name: &str, prop: Prop) -> Self {
+        self.props.insert((Xid::from(id), name.to_string()), prop);
+        self
+    }
+
+    /// Answer [MockConn::fetch] with a single round-trip, as a connection pipelining its
+    /// requests would.
+    pub fn pipelined(mut self) -> Self {
+        self.pipelined = true;
+        self
+    }
+
+    /// Several properties of a window, in one round-trip if pipelined and one each if not.
+    pub fn fetch(&self, id: Xid, names: &[&str]) -> Vec<Option<Prop>> {
+        if self.pipelined {
+            self.round_trip();
+        } else {
+            self.round_trips.set(self.round_trips.get() + names.len());
+        }
+
+        names.iter().map(|name| self.prop(id, name)).collect()
+    }
+
+    fn prop(&self, id: Xid, name: &str) -> Option<Prop> {
+        self.props.get(&(id, name.to_string())).cloned()
+    }
+
+    fn round_trip(&self) {
+        self.round_trips.set(self.round_trips.get() + 1);
+    }
+
+    /// The attributes set on a window, in the order they were set.
+    pub fn client_attributes(&self, id: Xid) -> Vec<ClientAttr> {
+        self.attributes
+            .borrow()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The number of round-trips made since the last call.
+    pub fn take_round_trips(&self) -> usize {
+        self.round_trips.replace(0)
+    }
+}
+
+fn unsupported<T>(method: &str) -> Result<T> {
+    Err(Error::Custom(format!("MockConn does not support {method}")))
+}
+
+#[allow(unused_variables)]
+impl XConn for MockConn {
+    fn root(&self) -> Xid {
+        Xid::from(ROOT)
+    }
+
+    fn screen_details(&self) -> Result<Vec<Rect>> {
+        Ok(vec![SCREEN])
+    }
+
+    fn cursor_position(&self) -> Result<Point> {
+        Ok(Point::default())
+    }
+
+    fn grab(&self, key_codes: &[KeyCode], mouse_states: &[MouseState]) -> Result<()> {
+        Ok(())
+    }
+
+    fn next_event(&self) -> Result<XEvent> {
+        unsupported("next_event")
+    }
+
+    fn flush(&self) {}
+
+    fn intern_atom(&self, atom: &str) -> Result<Xid> {
+        unsupported("intern_atom")
+    }
+
+    fn atom_name(&self, xid: Xid) -> Result<String> {
+        unsupported("atom_name")
+    }
+
+    fn client_geometry(&self, client: Xid) -> Result<Rect> {
+        unsupported("client_geometry")
+    }
+
+    fn existing_clients(&self) -> Result<Vec<Xid>> {
+        Ok(vec![])
+    }
+
+    fn map(&self, client: Xid) -> Result<()> {
+        Ok(())
+    }
+
+    fn unmap(&self, client: Xid) -> Result<()> {
+        Ok(())
+    }
+
+    fn kill(&self, client: Xid) -> Result<()> {
+        Ok(())
+    }
+
+    fn focus(&self, client: Xid) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_prop(&self, client: Xid, prop_name: &str) -> Result<Option<Prop>> {
+        self.round_trip();
+        Ok(self.prop(client, prop_name))
+    }
+
+    fn list_props(&self, client: Xid) -> Result<Vec<String>> {
+        Ok(self
+            .props
+            .keys()
+            .filter(|(id, _)| *id == client)
+            .map(|(_, name)| name.clone())
+            .collect())
+    }
+
+    fn get_wm_state(&self, client: Xid) -> Result<Option<WmState>> {
+        Ok(None)
+    }
+
+    fn get_window_attributes(&self, client: Xid) -> Result<WindowAttributes> {
+        unsupported("get_window_attributes")
+    }
+
+    fn set_wm_state(&self, client: Xid, wm_state: WmState) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_prop(&self, client: Xid, name: &str, val: Prop) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_prop(&self, client: Xid, prop_name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_client_attributes(&self, client: Xid, attrs: &[ClientAttr]) -> Result<()> {
+        self.attributes
+            .borrow_mut()
+            .entry(client)
+            .or_default()
+            .extend_from_slice(attrs);
+        Ok(())
+    }
+
+    fn set_client_config(&self, client: Xid, data: &[ClientConfig]) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_client_message(&self, msg: ClientMessage) -> Result<()> {
+        Ok(())
+    }
+
+    fn warp_pointer(&self, id: Xid, x: i16, y: i16) -> Result<()> {
+        Ok(())
+    }
+}
+
+// WM_NORMAL_HINTS is stored as the raw words of the property in a Prop::Cardinal
+mod conns {
+    use super::MockConn;
+    use crate::{
+        props::{PropConn, PropertySnapshot, SNAPSHOT_PROPS},
+        size_hints::{SizeHints, SizeHintsConn},
+    };
+    use penrose::{
+        x::{Atom, Prop, XConn},
+        Result, Xid,
+    };
+
+    fn decode(prop: Option<Prop>) -> Result<Option<SizeHints>> {
+        match prop {
+            Some(Prop::Cardinal(raw)) => SizeHints::try_from_raw(&raw).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    impl SizeHintsConn for MockConn {
+        fn size_hints(&self, id: Xid) -> Result<Option<SizeHints>> {
+            decode(self.get_prop(id, Atom::WmNormalHints.as_ref())?)
+        }
+    }
+
+    impl PropConn for MockConn {
+        fn snapshot(&self, id: Xid) -> Result<PropertySnapshot> {
+            let mut names = SNAPSHOT_PROPS.to_vec();
+            names.push(Atom::WmNormalHints.as_ref());
+            let mut values = self.fetch(id, &names);
+            let size_hints = decode(values.pop().flatten())?;
+            let props = SNAPSHOT_PROPS
+                .iter()
+                .map(|name| name.to_string())
+                .zip(values)
+                .collect();
+
+            Ok(PropertySnapshot::new(props, size_hints))
+        }
+    }
+}
@@ -1,8 +1,13 @@
 use penrose::{
     builtin::actions::floating::{toggle_floating_focused, MouseDragHandler, MouseResizeHandler},
-    core::bindings::{click_handler, MouseBindings, MouseState},
+    core::{
+        bindings::{click_handler, MouseBindings, MouseState},
+        ClientSet, State,
+    },
     map,
-    x::XConn,
+    pure::geometry::Point,
+    x::{XConn, XEvent},
+    x11rb::RustConn,
 };
 
 pub fn mouse_bindings<X>() -> MouseBindings<X>
@@ -22,3 +27,62 @@ where
         (Middle, vec![Shift, Meta]) => click_handler(toggle_floating_focused()),
     )
 }
+
+/// Tracks where the mouse pointer is, stored as a [State] extension.
+///
+/// The position is updated from pointer events as they arrive but X only reports those when
+/// the pointer crosses or moves over a window we are watching, so [MouseHandler::refresh]
+/// should be used to ask the X server directly when the position really matters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseHandler {
+    position: Option<Point>,
+}
+
+impl MouseHandler {
+    /// The last known position of the pointer in root window coordinates.
+    pub fn current_mouse_position(&self) -> Option<Point> {
+        self.position
+    }
+
+    /// Update the position from an event if it carries the pointer location.
+    pub fn track(&mut self, event: &XEvent) {
+        let position = match event {
+            XEvent::Enter(p) | XEvent::Leave(p) => p.abs,
+            XEvent::MouseEvent(e) => e.data.rpt,
+            XEvent::MotionNotify(e) => e.data.rpt,
+            _ => return,
+        };
+
+        self.position = Some(position);
+    }
+
+    /// Query the X server for the current pointer position, keeping the last tracked position
+    /// if that fails.
+    pub fn refresh<X: XConn>(&mut self, x: &X) -> Option<Point> {
+        match x.cursor_position() {
+            Ok(position) => self.position = Some(position),
+            Err(e) => tracing::warn!(%e, "unable to query the pointer position"),
+        }
+
+        self.position
+    }
+}
+
+/// An event hook keeping the [MouseHandler] extension up to date.
+pub fn track_mouse_position(
+    event: &XEvent,
+    state: &mut State<RustConn>,
+    _: &RustConn,
+) -> penrose::Result<bool> {
+    state.extension::<MouseHandler>()?.borrow_mut().track(event);
+
+    Ok(true)
+}
+
+/// The index of the screen containing the given point, if any.
+pub fn screen_at(client_set: &ClientSet, point: Point) -> Option<usize> {
+    client_set
+        .screens()
+        .find(|s| s.geometry().contains_point(point))
+        .map(|s| s.index())
+}
@@ -0,0 +1,300 @@
+//! Stacking notification windows in a corner of the screen.
+use penrose::{
+    core::{hooks::ManageHook, ClientSet, State},
+    pure::geometry::{Rect, RelativeRect},
+    x::{XConn, XConnExt},
+    x11rb::RustConn,
+    Xid,
+};
+
+use crate::{
+    config::{Corner, NotificationSettings, Settings},
+    props::PropConn,
+    size_hints::{Size, SizeHints},
+    struts::Struts,
+};
+
+/// The notifications currently on screen, stored as a [State] extension.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NotificationStack {
+    // Oldest first
+    toasts: Vec<(Xid, Size)>,
+}
+
+impl NotificationStack {
+    /// Add a notification to the end of the stack, or update its size if it is already there.
+    pub fn push(&mut self, id: Xid, size: Size) {
+        match self.toasts.iter_mut().find(|(t, _)| *t == id) {
+            Some((_, s)) => *s = size,
+            None => self.toasts.push((id, size)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.toasts.is_empty()
+    }
+
+    /// Drop any notifications that are no longer managed, returning whether there were any.
+    pub fn retain_managed(&mut self, client_set: &ClientSet) -> bool {
+        let before = self.toasts.len();
+        self.toasts.retain(|(id, _)| client_set.contains(id));
+
+        self.toasts.len() != before
+    }
+
+    /// Where each notification should be placed within `area`.
+    pub fn positions(&self, area: Rect, settings: &NotificationSettings) -> Vec<(Xid, Rect)> {
+        let pad = settings.padding_px as i32;
+        let mut offset = pad;
+
+        self.toasts
+            .iter()
+            .map(|&(id, Size { w, h })| {
+                let x = match settings.corner {
+                    Corner::TopLeft | Corner::BottomLeft => area.x + pad,
+                    Corner::TopRight | Corner::BottomRight => {
+                        area.x + area.w as i32 - pad - w as i32
+                    }
+                };
+                let y = match settings.corner {
+                    Corner::TopLeft | Corner::TopRight => area.y + offset,
+                    Corner::BottomLeft | Corner::BottomRight => {
+                        area.y + area.h as i32 - offset - h as i32
+                    }
+                };
+                offset += h as i32 + pad;
+
+                (id, Rect::new(x, y, w, h))
+            })
+            .collect()
+    }
+
+    /// Move every notification to the configured screen and float it in its place in the stack,
+    /// keeping clear of any docks in `struts` and leaving `bar_px` free at the top of the screen
+    /// for the status bar.
+    pub fn reflow(
+        &self,
+        client_set: &mut ClientSet,
+        settings: &NotificationSettings,
+        struts: &Struts,
+        bar_px: u32,
+    ) {
+        let screen = settings
+            .screen
+            .and_then(|i| client_set.screens().find(|s| s.index() == i))
+            .unwrap_or_else(|| client_set.current_screen());
+        let tag = screen.workspace.tag().to_string();
+        let geometry = screen.geometry();
+        let mut area = struts.insets(geometry).apply(geometry);
+        area.y += bar_px as i32;
+        area.h = area.h.saturating_sub(bar_px);
+
+        for (id, r) in self.positions(area, settings) {
+            if client_set.tag_for_client(&id) != Some(tag.as_str()) {
+                client_set.move_client_to_tag(&id, &tag);
+            }
+            if let Err(e) = client_set.float(id, r) {
+                tracing::warn!(%e, %id, "unable to position notification");
+            }
+        }
+    }
+}
+
+fn reflow_in<X: XConn>(state: &mut State<X>) -> penrose::Result<()> {
+    let settings = state.extension::<Settings>()?;
+    let settings = settings.borrow();
+    let bar_px = if settings.bar.enabled {
+        settings.bar.height_px
+    } else {
+        0
+    };
+    state.extension::<NotificationStack>()?.borrow().reflow(
+        &mut state.client_set,
+        &settings.notifications,
+        &Struts::global(),
+        bar_px,
+    );
+
+    Ok(())
+}
+
+/// A [ManageHook] adding a client to the [NotificationStack] at the size it asked for, falling
+/// back to the given fractions of the screen size.
+pub struct StackNotification {
+    pub(crate) width: f64,
+    pub(crate) height: f64,
+}
+
+impl StackNotification {
+    pub fn new(width: f64, height: f64) -> Self {
+        assert!(width > 0.0 && width <= 1.0);
+        assert!(height > 0.0 && height <= 1.0);
+        Self { width, height }
+    }
+
+    /// The size of a notification with the given hints on a screen.
+    pub fn size(&self, hints: Option<&SizeHints>, screen: Rect) -> Size {
+        let fallback = RelativeRect::new(0.0, 0.0, self.width, self.height).applied_to(&screen);
+        let r = match hints {
+            Some(hints) => {
+                let requested = hints.size.map(|s| Rect::new(0, 0, s.w, s.h));
+                hints.constrain(requested.unwrap_or(fallback))
+            }
+            None => fallback,
+        };
+
+        Size::new(r.w, r.h)
+    }
+}
+
+impl Default for StackNotification {
+    fn default() -> Self {
+        Self {
+            width: 0.15,
+            height: 0.05,
+        }
+    }
+}
+
+impl<X: PropConn> ManageHook<X> for StackNotification {
+    fn call(&mut self, client: Xid, state: &mut State<X>, x: &X) -> penrose::Result<()> {
+        let hints = x.cached_size_hints(client).ok().flatten();
+        let size = self.size(hints.as_ref(), state.client_set.current_screen().geometry());
+        tracing::debug!(%client, ?size, "stacking notification");
+
+        state
+            .extension::<NotificationStack>()?
+            .borrow_mut()
+            .push(client, size);
+
+        reflow_in(state)
+    }
+}
+
+/// A refresh hook closing the gaps left in the [NotificationStack] when notifications go away.
+pub fn reflow_notifications(state: &mut State<RustConn>, x: &RustConn) -> penrose::Result<()> {
+    let removed = state
+        .extension::<NotificationStack>()?
+        .borrow_mut()
+        .retain_managed(&state.client_set);
+
+    if removed {
+        reflow_in(state)?;
+        x.refresh(state)?;
+    }
+
+    Ok(())
+}
+
+/// Re-position the [NotificationStack] after the settings have changed.
+pub fn reapply_notification_settings(state: &mut State<RustConn>) -> penrose::Result<()> {
+    if state.extension::<NotificationStack>()?.borrow().is_empty() {
+        return Ok(());
+    }
+
+    reflow_in(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{layouts::layouts, struts::Strut};
+    use penrose::pure::StackSet;
+
+    #[test]
+    fn notifications_stack_and_reflow_on_the_configured_screen() {
+        let screens = [Rect::new(0, 0, 1920, 1080), Rect::new(1920, 0, 1000, 800)];
+        let mut cs: ClientSet =
+            StackSet::try_new(layouts(&Settings::default()), ["1", "2", "3"], screens).unwrap();
+        let settings = NotificationSettings {
+            corner: Corner::BottomRight,
+            padding_px: 10,
+            screen: Some(1),
+        };
+
+        let mut stack = NotificationStack::default();
+        for (id, h) in [(1, 100), (2, 50), (3, 80)] {
+            cs.insert(Xid::from(id));
+            stack.push(Xid::from(id), Size::new(300, h));
+        }
+        stack.reflow(&mut cs, &settings, &Struts::default(), 20);
+
+        // Everything is moved to the workspace on screen 1 without changing focus
+        assert_eq!(cs.current_tag(), "1");
+        for id in 1..=3 {
+            assert_eq!(cs.tag_for_client(&Xid::from(id)), Some("2"));
+            assert!(cs.is_floating(&Xid::from(id)));
+        }
+
+        // The second notification closes and the third moves down to take its place
+        cs.remove_client(&Xid::from(2));
+        assert!(stack.retain_managed(&cs));
+        assert!(!stack.retain_managed(&cs));
+        stack.reflow(&mut cs, &settings, &Struts::default(), 20);
+
+        cs.focus_screen(1);
+        let rects: Vec<_> = [1, 3]
+            .into_iter()
+            .map(|id| cs.sink(&Xid::from(id)).unwrap())
+            .collect();
+        assert_eq!(
+            rects,
+            vec![
+                Rect::new(2610, 690, 300, 100),
+                Rect::new(2610, 600, 300, 80)
+            ]
+        );
+    }
+
+    #[test]
+    fn notifications_keep_clear_of_docks() {
+        let screens = [Rect::new(0, 0, 1920, 1080)];
+        let mut cs: ClientSet =
+            StackSet::try_new(layouts(&Settings::default()), ["1", "2"], screens).unwrap();
+        let settings = NotificationSettings {
+            corner: Corner::BottomRight,
+            padding_px: 10,
+            screen: None,
+        };
+        let struts = Struts::default();
+        struts.set_root(Rect::new(0, 0, 1920, 1080));
+        struts.insert(Xid::from(10), Strut::try_from_raw(&[0, 0, 0, 40]));
+
+        let mut stack = NotificationStack::default();
+        cs.insert(Xid::from(1));
+        stack.push(Xid::from(1), Size::new(300, 100));
+        stack.reflow(&mut cs, &settings, &struts, 0);
+
+        assert_eq!(cs.sink(&Xid::from(1)), Some(Rect::new(1610, 930, 300, 100)));
+    }
+
+    #[test]
+    fn each_corner_stacks_away_from_the_edge() {
+        let area = Rect::new(0, 20, 1000, 500);
+        let mut stack = NotificationStack::default();
+        stack.push(Xid::from(1), Size::new(200, 50));
+        stack.push(Xid::from(2), Size::new(100, 40));
+
+        let cases = [
+            (Corner::TopLeft, [(5, 25), (5, 80)]),
+            (Corner::TopRight, [(795, 25), (895, 80)]),
+            (Corner::BottomLeft, [(5, 465), (5, 420)]),
+            (Corner::BottomRight, [(795, 465), (895, 420)]),
+        ];
+
+        for (corner, expected) in cases {
+            let settings = NotificationSettings {
+                corner,
+                padding_px: 5,
+                screen: None,
+            };
+            let points: Vec<_> = stack
+                .positions(area, &settings)
+                .into_iter()
+                .map(|(_, r)| (r.x, r.y))
+                .collect();
+
+            assert_eq!(points, expected, "{corner:?}");
+        }
+    }
+}
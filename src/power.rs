@@ -0,0 +1,528 @@
+//! A keyboard driven menu for locking, suspending, logging out, restarting and shutting down.
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use penrose::{
+    builtin::actions::exit,
+    core::State,
+    pure::geometry::Rect,
+    util,
+    x::{Atom, WinType, XConn},
+    x11rb::RustConn,
+    Error, Result,
+};
+use penrose_ui::Draw;
+use serde::{Deserialize, Serialize};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{ConnectionExt, GrabMode, GrabStatus},
+        Event,
+    },
+    rust_connection::RustConnection,
+    CURRENT_TIME,
+};
+
+use crate::{
+    config::{Settings, Theme},
+    lock::lock_screen,
+    remote::{Command, Remote},
+    session::save_and_exit,
+    sleep::{suspend, SleepMode},
+};
+
+/// How long clients are given to close when logging out.
+pub const LOGOUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The exit code used after logging out, checked for by `bin/run-penrose.sh`.
+pub const LOGOUT_EXIT_CODE: i32 = 64;
+
+// The keyboard is still grabbed by the key binding that opened the menu until it is released
+const GRAB_TIMEOUT: Duration = Duration::from_secs(1);
+const PADDING: u32 = 10;
+// The size of the menu in multiples of the font size
+const WIDTH_EMS: u32 = 24;
+const LINE_HEIGHT_EMS: u32 = 3;
+
+static LOGGING_OUT: AtomicBool = AtomicBool::new(false);
+static MENU_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether we are exiting because the user logged out, rather than to be restarted.
+pub fn is_logging_out() -> bool {
+    LOGGING_OUT.load(Ordering::SeqCst)
+}
+
+/// An entry in the power menu. These can also be run directly as the `power <action>` key
+/// action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PowerAction {
+    Lock,
+    Suspend,
+    SuspendThenHibernate,
+    Logout,
+    Restart,
+    Reboot,
+    Shutdown,
+}
+
+impl PowerAction {
+    /// Every action, in the order they are listed in the menu.
+    pub const ALL: [Self; 7] = [
+        Self::Lock,
+        Self::Suspend,
+        Self::SuspendThenHibernate,
+        Self::Logout,
+        Self::Restart,
+        Self::Reboot,
+        Self::Shutdown,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Lock => "Lock",
+            Self::Suspend => "Suspend",
+            Self::SuspendThenHibernate => "Suspend then hibernate",
+            Self::Logout => "Log out",
+            Self::Restart => "Restart penrose",
+            Self::Reboot => "Reboot",
+            Self::Shutdown => "Shut down",
+        }
+    }
+
+    /// The key that picks this entry from the menu.
+    pub fn hotkey(self) -> char {
+        match self {
+            Self::Lock => 'l',
+            Self::Suspend => 's',
+            Self::SuspendThenHibernate => 'h',
+            Self::Logout => 'o',
+            Self::Restart => 'w',
+            Self::Reboot => 'r',
+            Self::Shutdown => 'p',
+        }
+    }
+
+    /// Whether the menu asks before running this action.
+    pub fn needs_confirmation(self) -> bool {
+        matches!(self, Self::Logout | Self::Reboot | Self::Shutdown)
+    }
+
+    pub fn run(self, state: &mut State<RustConn>, x: &RustConn) -> Result<()> {
+        tracing::info!(action = %self, "running power action");
+        match self {
+            Self::Lock => {
+                lock_screen(state)?;
+            }
+            Self::Suspend => suspend(state, SleepMode::Suspend)?,
+            Self::SuspendThenHibernate => suspend(state, SleepMode::SuspendThenHibernate)?,
+            Self::Logout => logout(state, x)?,
+            Self::Restart => save_and_exit().call(state, x)?,
+            Self::Reboot => util::spawn("systemctl reboot")?,
+            Self::Shutdown => util::spawn("systemctl poweroff")?,
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for PowerAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.to_string() == s.trim())
+            .ok_or_else(|| {
+                format!(
+                    "unknown power action '{s}': expected one of lock, suspend, \
+                     suspend-then-hibernate, logout, restart, reboot, shutdown"
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for PowerAction {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PowerAction> for String {
+    fn from(action: PowerAction) -> Self {
+        action.to_string()
+    }
+}
+
+impl fmt::Display for PowerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Lock => "lock",
+            Self::Suspend => "suspend",
+            Self::SuspendThenHibernate => "suspend-then-hibernate",
+            Self::Logout => "logout",
+            Self::Restart => "restart",
+            Self::Reboot => "reboot",
+            Self::Shutdown => "shutdown",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// The keys understood by the [PowerMenu].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    Select,
+    Cancel,
+    Char(char),
+}
+
+impl MenuKey {
+    fn from_keysym(keysym: u32) -> Option<Self> {
+        match keysym {
+            0xff1b => Some(Self::Cancel),          // Escape
+            0xff0d | 0xff8d => Some(Self::Select), // Return, KP_Enter
+            0xff52 => Some(Self::Up),              // Up
+            0xff54 | 0xff09 => Some(Self::Down),   // Down, Tab
+            0x20..=0x7e => Some(Self::Char(keysym as u8 as char)),
+            _ => None,
+        }
+    }
+}
+
+/// What the menu should do after a key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuOutcome {
+    Open,
+    Closed,
+    Chosen(PowerAction),
+}
+
+/// The state of an open power menu.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PowerMenu {
+    selected: usize,
+    confirming: Option<PowerAction>,
+}
+
+impl PowerMenu {
+    pub fn handle(&mut self, key: MenuKey) -> MenuOutcome {
+        if let Some(action) = self.confirming {
+            return match key {
+                MenuKey::Select | MenuKey::Char('y') => MenuOutcome::Chosen(action),
+                MenuKey::Cancel | MenuKey::Char('n') => {
+                    self.confirming = None;
+                    MenuOutcome::Open
+                }
+                _ => MenuOutcome::Open,
+            };
+        }
+
+        let n = PowerAction::ALL.len();
+        match key {
+            MenuKey::Up | MenuKey::Char('k') => self.selected = (self.selected + n - 1) % n,
+            MenuKey::Down | MenuKey::Char('j') => self.selected = (self.selected + 1) % n,
+            MenuKey::Cancel => return MenuOutcome::Closed,
+            MenuKey::Select => return self.choose(PowerAction::ALL[self.selected]),
+            MenuKey::Char(c) => {
+                if let Some(i) = PowerAction::ALL.iter().position(|a| a.hotkey() == c) {
+                    self.selected = i;
+                    return self.choose(PowerAction::ALL[i]);
+                }
+            }
+        }
+
+        MenuOutcome::Open
+    }
+
+    fn choose(&mut self, action: PowerAction) -> MenuOutcome {
+        if action.needs_confirmation() {
+            self.confirming = Some(action);
+            MenuOutcome::Open
+        } else {
+            MenuOutcome::Chosen(action)
+        }
+    }
+
+    /// The lines to draw, along with whether each one is highlighted.
+    pub fn lines(&self) -> Vec<(String, bool)> {
+        match self.confirming {
+            Some(action) => vec![(format!("{}? (y/n)", action.label()), true)],
+            None => PowerAction::ALL
+                .iter()
+                .enumerate()
+                .map(|(i, a)| (format!("{}  {}", a.hotkey(), a.label()), i == self.selected))
+                .collect(),
+        }
+    }
+}
+
+/// Open the power menu on the focused screen, unless it is already open.
+pub fn show_power_menu(state: &mut State<RustConn>, _: &RustConn) -> Result<()> {
+    if MENU_OPEN.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let theme = state.extension::<Settings>()?.borrow().theme.clone();
+    let remote = state.extension::<Remote>()?.borrow().clone();
+    let screen = state.client_set.current_screen().geometry();
+
+    let res = thread::Builder::new()
+        .name("power-menu".to_string())
+        .spawn(move || {
+            match run_menu(&theme, screen) {
+                Ok(Some(action)) => remote.send(Command::Power(action)),
+                Ok(None) => (),
+                Err(e) => tracing::error!(%e, "error running the power menu"),
+            }
+            MENU_OPEN.store(false, Ordering::SeqCst);
+        });
+
+    if let Err(e) = res {
+        MENU_OPEN.store(false, Ordering::SeqCst);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+fn run_menu(theme: &Theme, screen: Rect) -> penrose_ui::Result<Option<PowerAction>> {
+    let keyboard = Keyboard::grab()?;
+
+    let em = theme.point_size as u32;
+    let line_h = LINE_HEIGHT_EMS * em;
+    let r = Rect::new(
+        0,
+        0,
+        WIDTH_EMS * em,
+        line_h * PowerAction::ALL.len() as u32 + 2 * PADDING,
+    );
+    let r = r.centered_in(&screen).unwrap_or(screen);
+
+    let mut drw = Draw::new(&theme.font, theme.point_size, theme.black)?;
+    let win = drw.new_window(WinType::InputOutput(Atom::NetWindowTypeDialog), r, false)?;
+
+    let mut menu = PowerMenu::default();
+    let res = loop {
+        if let Err(e) = draw_menu(&mut drw, win, &menu, theme, r, line_h) {
+            break Err(e);
+        }
+
+        match keyboard.next_key().map(|key| menu.handle(key)) {
+            Ok(MenuOutcome::Open) => (),
+            Ok(MenuOutcome::Closed) => break Ok(None),
+            Ok(MenuOutcome::Chosen(action)) => break Ok(Some(action)),
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    drw.destroy_window_and_surface(win)?;
+
+    res
+}
+
+fn draw_menu(
+    drw: &mut Draw,
+    win: penrose::Xid,
+    menu: &PowerMenu,
+    theme: &Theme,
+    r: Rect,
+    line_h: u32,
+) -> penrose_ui::Result<()> {
+    let mut ctx = drw.context_for(win)?;
+    ctx.fill_bg(Rect::new(0, 0, r.w, r.h))?;
+
+    for (i, (text, selected)) in menu.lines().into_iter().enumerate() {
+        let y = PADDING + i as u32 * line_h;
+        if selected {
+            ctx.fill_rect(Rect::new(0, y as i32, r.w, line_h), theme.blue)?;
+        }
+
+        let (_, text_h) = ctx.text_extent(&text)?;
+        ctx.set_offset(0, y as i32);
+        ctx.draw_text(
+            &text,
+            line_h.saturating_sub(text_h) / 2,
+            (PADDING, PADDING),
+            theme.white,
+        )?;
+        ctx.reset_offset();
+    }
+
+    ctx.flush();
+    drw.flush(win)
+}
+
+// An active grab of the keyboard on a connection of its own, released when dropped.
+struct Keyboard {
+    conn: RustConnection,
+    min_keycode: u8,
+    keysyms_per_keycode: u8,
+    keysyms: Vec<u32>,
+}
+
+impl Keyboard {
+    fn grab() -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let start = Instant::now();
+        loop {
+            let status = conn
+                .grab_keyboard(false, root, CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)?
+                .reply()?
+                .status;
+            if status == GrabStatus::SUCCESS {
+                break;
+            }
+            if start.elapsed() > GRAB_TIMEOUT {
+                return Err(Error::Custom(format!(
+                    "unable to grab the keyboard: {status:?}"
+                )));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (min, max) = (conn.setup().min_keycode, conn.setup().max_keycode);
+        let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+
+        Ok(Self {
+            conn,
+            min_keycode: min,
+            keysyms_per_keycode: mapping.keysyms_per_keycode,
+            keysyms: mapping.keysyms,
+        })
+    }
+
+    // The unshifted keysym for a key code
+    fn keysym(&self, code: u8) -> u32 {
+        let i = code.saturating_sub(self.min_keycode) as usize * self.keysyms_per_keycode as usize;
+
+        self.keysyms.get(i).copied().unwrap_or(0)
+    }
+
+    fn next_key(&self) -> Result<MenuKey> {
+        loop {
+            if let Event::KeyPress(e) = self.conn.wait_for_event()? {
+                if let Some(key) = MenuKey::from_keysym(self.keysym(e.detail)) {
+                    return Ok(key);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        let _ = self.conn.ungrab_keyboard(CURRENT_TIME);
+        let _ = self.conn.flush();
+    }
+}
+
+/// Ask every client to close, exiting once they have or after [LOGOUT_TIMEOUT].
+pub fn logout(state: &mut State<RustConn>, x: &RustConn) -> Result<()> {
+    if LOGGING_OUT.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let clients: Vec<_> = state.client_set.clients().copied().collect();
+    tracing::info!(n = clients.len(), "logging out: closing clients");
+    for client in clients {
+        if let Err(e) = x.kill(client) {
+            tracing::warn!(%e, %client, "unable to close client");
+        }
+    }
+
+    let remote = state.extension::<Remote>()?.borrow().clone();
+    thread::Builder::new()
+        .name("logout-timeout".to_string())
+        .spawn(move || {
+            thread::sleep(LOGOUT_TIMEOUT);
+            remote.send(Command::FinishLogout);
+        })?;
+
+    exit_once_logged_out(state, x)
+}
+
+/// A refresh hook exiting once the last client has closed while logging out.
+pub fn exit_once_logged_out(state: &mut State<RustConn>, x: &RustConn) -> Result<()> {
+    if is_logging_out() && state.client_set.clients().next().is_none() {
+        return finish_logout(state, x);
+    }
+
+    Ok(())
+}
+
+/// Exit without waiting on any clients that are still open.
+pub fn finish_logout(state: &mut State<RustConn>, x: &RustConn) -> Result<()> {
+    let remaining = state.client_set.clients().count();
+    tracing::info!(%remaining, "logged out");
+
+    exit().call(state, x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_picked_with_the_arrows_or_their_hotkey() {
+        let mut menu = PowerMenu::default();
+        assert_eq!(menu.lines()[0], ("l  Lock".to_string(), true));
+
+        assert_eq!(menu.handle(MenuKey::Up), MenuOutcome::Open);
+        assert_eq!(menu.lines()[6], ("p  Shut down".to_string(), true));
+        assert_eq!(menu.handle(MenuKey::Char('j')), MenuOutcome::Open);
+        assert_eq!(menu.handle(MenuKey::Down), MenuOutcome::Open);
+        assert_eq!(
+            menu.handle(MenuKey::Select),
+            MenuOutcome::Chosen(PowerAction::Suspend)
+        );
+
+        let mut menu = PowerMenu::default();
+        assert_eq!(
+            menu.handle(MenuKey::Char('h')),
+            MenuOutcome::Chosen(PowerAction::SuspendThenHibernate)
+        );
+        assert_eq!(menu.handle(MenuKey::Char('x')), MenuOutcome::Open);
+        assert_eq!(menu.handle(MenuKey::Cancel), MenuOutcome::Closed);
+    }
+
+    #[test]
+    fn destructive_entries_ask_first() {
+        let mut menu = PowerMenu::default();
+
+        assert_eq!(menu.handle(MenuKey::Char('r')), MenuOutcome::Open);
+        assert_eq!(menu.lines(), vec![("Reboot? (y/n)".to_string(), true)]);
+        // Hotkeys do nothing while confirming
+        assert_eq!(menu.handle(MenuKey::Char('l')), MenuOutcome::Open);
+        assert_eq!(menu.handle(MenuKey::Char('n')), MenuOutcome::Open);
+        assert_eq!(menu.lines().len(), PowerAction::ALL.len());
+
+        assert_eq!(menu.handle(MenuKey::Char('o')), MenuOutcome::Open);
+        assert_eq!(menu.handle(MenuKey::Cancel), MenuOutcome::Open);
+        assert_eq!(menu.handle(MenuKey::Select), MenuOutcome::Open);
+        assert_eq!(
+            menu.handle(MenuKey::Char('y')),
+            MenuOutcome::Chosen(PowerAction::Logout)
+        );
+    }
+
+    #[test]
+    fn actions_round_trip_through_display() {
+        for action in PowerAction::ALL {
+            assert_eq!(action.to_string().parse(), Ok(action));
+        }
+        assert!("hibernate".parse::<PowerAction>().is_err());
+    }
+}
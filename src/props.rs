@@ -0,0 +1,300 @@
+//! A snapshot of the properties of a window taken once while it is being managed, so that
+//! each rule and hook doesn't go back to the X server for them.
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::OnceLock};
+
+use penrose::{
+    core::{hooks::ManageHook, State},
+    x::{Atom, Prop, XConn},
+    x11rb::RustConn,
+    Error, Result, Xid,
+};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, GetPropertyReply};
+
+use crate::size_hints::{SizeHints, SizeHintsConn};
+
+/// The properties stored in a [PropertySnapshot] along with `WM_NORMAL_HINTS`.
+pub const SNAPSHOT_PROPS: &[&str] = &[
+    "WM_NAME",
+    "_NET_WM_NAME",
+    "WM_CLASS",
+    "WM_WINDOW_ROLE",
+    "WM_TRANSIENT_FOR",
+    "_NET_WM_WINDOW_TYPE",
+    "_NET_WM_PID",
+    "_NET_WM_STRUT_PARTIAL",
+];
+
+thread_local! {
+    static SNAPSHOTS: RefCell<HashMap<Xid, Rc<PropertySnapshot>>> = RefCell::default();
+}
+
+/// The properties of a single window at the point it was managed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PropertySnapshot {
+    // Only the properties that were fetched have entries, with None if they were not set
+    props: HashMap<String, Option<Prop>>,
+    size_hints: Option<SizeHints>,
+}
+
+impl PropertySnapshot {
+    pub fn new(props: HashMap<String, Option<Prop>>, size_hints: Option<SizeHints>) -> Self {
+        Self { props, size_hints }
+    }
+
+    /// The value of a property, or `None` if it was not part of the snapshot.
+    pub fn prop(&self, name: &str) -> Option<Option<&Prop>> {
+        self.props.get(name).map(Option::as_ref)
+    }
+
+    pub fn size_hints(&self) -> Option<&SizeHints> {
+        self.size_hints.as_ref()
+    }
+}
+
+fn current_snapshot(id: Xid) -> Option<Rc<PropertySnapshot>> {
+    SNAPSHOTS.with(|s| s.borrow().get(&id).cloned())
+}
+
+// Makes the snapshot of a window visible to [PropConn] until dropped.
+struct Installed(Xid);
+
+impl Installed {
+    fn new(id: Xid, snapshot: PropertySnapshot) -> Self {
+        SNAPSHOTS.with(|s| s.borrow_mut().insert(id, Rc::new(snapshot)));
+        Self(id)
+    }
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        SNAPSHOTS.with(|s| s.borrow_mut().remove(&self.0));
+    }
+}
+
+/// An [XConn] that can take a [PropertySnapshot] of a window and serve properties from it.
+pub trait PropConn: SizeHintsConn {
+    /// Fetch the [SNAPSHOT_PROPS] and size hints of a window.
+    ///
+    /// The default implementation makes one request per property. Connections that are able
+    /// to should pipeline the requests instead.
+    fn snapshot(&self, id: Xid) -> Result<PropertySnapshot> {
+        let props = SNAPSHOT_PROPS
+            .iter()
+            .map(|&name| Ok((name.to_string(), self.get_prop(id, name)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(PropertySnapshot::new(props, self.size_hints(id)?))
+    }
+
+    /// A property of a window, from its snapshot if one is being managed.
+    fn cached_prop(&self, id: Xid, name: &str) -> Result<Option<Prop>> {
+        match current_snapshot(id) {
+            Some(s) => match s.prop(name) {
+                Some(prop) => Ok(prop.cloned()),
+                None => self.get_prop(id, name),
+            },
+            None => self.get_prop(id, name),
+        }
+    }
+
+    /// The size hints of a window, from its snapshot if one is being managed.
+    fn cached_size_hints(&self, id: Xid) -> Result<Option<SizeHints>> {
+        match current_snapshot(id) {
+            Some(s) => Ok(s.size_hints().cloned()),
+            None => self.size_hints(id),
+        }
+    }
+}
+
+impl PropConn for RustConn {
+    fn snapshot(&self, id: Xid) -> Result<PropertySnapshot> {
+        static ATOMS: OnceLock<Vec<u32>> = OnceLock::new();
+
+        let conn = self.connection();
+        let atoms = match ATOMS.get() {
+            Some(atoms) => atoms,
+            None => {
+                // Not all of these are known to penrose so they are interned together the first
+                // time round rather than one at a time
+                let cookies = SNAPSHOT_PROPS
+                    .iter()
+                    .map(|name| Ok(conn.intern_atom(false, name.as_bytes())?))
+                    .collect::<Result<Vec<_>>>()?;
+                let atoms = cookies
+                    .into_iter()
+                    .map(|c| Ok(c.reply()?.atom))
+                    .collect::<Result<_>>()?;
+
+                ATOMS.get_or_init(|| atoms)
+            }
+        };
+        let hints_atom = *self.intern_atom(Atom::WmNormalHints.as_ref())?;
+        let utf8_string = *self.intern_atom(Atom::UTF8String.as_ref())?;
+
+        // First send all requests...
+        let hints_cookie =
+            conn.get_property(false, *id, hints_atom, AtomEnum::WM_SIZE_HINTS, 0, 1024)?;
+        let cookies = atoms
+            .iter()
+            .map(|&atom| Ok(conn.get_property(false, *id, atom, AtomEnum::ANY, 0, 1024)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        // ...then wait for the replies, so that there is only a single round-trip
+        let mut props = HashMap::new();
+        for (&name, cookie) in SNAPSHOT_PROPS.iter().zip(cookies) {
+            let r = cookie.reply()?;
+            match decode(self, id, name, r, utf8_string)? {
+                Decoded::Prop(p) => {
+                    props.insert(name.to_string(), p);
+                }
+                // Left out of the snapshot so that it is fetched (and decoded) by penrose
+                Decoded::Unknown => (),
+            }
+        }
+
+        let r = hints_cookie.reply()?;
+        let size_hints = match r.type_ {
+            0 => None,
+            _ => Some(SizeHints::try_from_raw(
+                &r.value32()
+                    .ok_or_else(|| invalid(id, Atom::WmNormalHints.as_ref(), "WM_SIZE_HINTS"))?
+                    .collect::<Vec<_>>(),
+            )?),
+        };
+
+        Ok(PropertySnapshot::new(props, size_hints))
+    }
+}
+
+enum Decoded {
+    Prop(Option<Prop>),
+    Unknown,
+}
+
+fn invalid(id: Xid, prop: &str, ty: &str) -> Error {
+    Error::InvalidPropertyData {
+        id,
+        prop: prop.to_owned(),
+        ty: ty.to_owned(),
+    }
+}
+
+// Decode the property types that penrose decodes in `get_prop` for the properties in
+// SNAPSHOT_PROPS, in the same way.
+fn decode(
+    x: &RustConn,
+    id: Xid,
+    name: &str,
+    r: GetPropertyReply,
+    utf8_string: u32,
+) -> Result<Decoded> {
+    let ty = r.type_;
+    let prop = if ty == 0 {
+        None
+    } else if ty == u32::from(AtomEnum::STRING) || ty == utf8_string {
+        if r.format != 8 {
+            return Err(invalid(id, name, "STRING"));
+        }
+        Some(Prop::UTF8String(
+            String::from_utf8(r.value)?
+                .trim_matches('\0')
+                .split('\0')
+                .map(|s| s.to_string())
+                .collect(),
+        ))
+    } else if ty == u32::from(AtomEnum::ATOM) {
+        Some(Prop::Atom(
+            r.value32()
+                .ok_or_else(|| invalid(id, name, "ATOM"))?
+                .map(|a| x.atom_name(Xid::from(a)))
+                .collect::<Result<_>>()?,
+        ))
+    } else if ty == u32::from(AtomEnum::CARDINAL) {
+        Some(Prop::Cardinal(
+            r.value32()
+                .ok_or_else(|| invalid(id, name, "CARDINAL"))?
+                .collect(),
+        ))
+    } else if ty == u32::from(AtomEnum::WINDOW) {
+        Some(Prop::Window(
+            r.value32()
+                .ok_or_else(|| invalid(id, name, "WINDOW"))?
+                .map(Xid::from)
+                .collect(),
+        ))
+    } else {
+        return Ok(Decoded::Unknown);
+    };
+
+    Ok(Decoded::Prop(prop))
+}
+
+/// A [ManageHook] taking a [PropertySnapshot] of each new window and running the wrapped hooks
+/// against it.
+///
+/// If the snapshot can not be taken the hooks are run anyway, fetching properties as they go.
+pub struct SnapshotProps<X: XConn>(pub Vec<Box<dyn ManageHook<X>>>);
+
+impl<X: PropConn> ManageHook<X> for SnapshotProps<X> {
+    fn call(&mut self, client: Xid, state: &mut State<X>, x: &X) -> Result<()> {
+        let _installed = match x.snapshot(client) {
+            Ok(snapshot) => Some(Installed::new(client, snapshot)),
+            Err(e) => {
+                tracing::warn!(%e, %client, "unable to snapshot window properties");
+                None
+            }
+        };
+
+        self.0.call(client, state, x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hooks::{StrProp, StrQuery, WindowType},
+        mock::MockConn,
+    };
+    use penrose::x::Query;
+
+    #[test]
+    fn queries_are_answered_from_the_snapshot() {
+        let id = Xid::from(1);
+        let x = MockConn::default()
+            .with_prop(
+                1,
+                "WM_CLASS",
+                Prop::UTF8String(vec!["zoom".into(), "zoom".into()]),
+            )
+            .with_prop(
+                1,
+                "_GTK_APPLICATION_ID",
+                Prop::UTF8String(vec!["us.zoom".into()]),
+            )
+            .pipelined();
+        let class = StrQuery::new(StrProp::ClassName, "zoom");
+        let dialog = WindowType("_NET_WM_WINDOW_TYPE_DIALOG".into());
+
+        assert!(class.run(id, &x).unwrap());
+        assert!(!dialog.run(id, &x).unwrap());
+        assert_eq!(x.take_round_trips(), 2);
+
+        let installed = Installed::new(id, x.snapshot(id).unwrap());
+        assert_eq!(x.take_round_trips(), 1);
+        for _ in 0..3 {
+            assert!(class.run(id, &x).unwrap());
+            assert!(!dialog.run(id, &x).unwrap());
+        }
+        assert_eq!(x.take_round_trips(), 0);
+
+        // Anything not in the snapshot is still fetched
+        let app_id = StrQuery::new(StrProp::Prop("_GTK_APPLICATION_ID".into()), "us.zoom");
+        assert!(app_id.run(id, &x).unwrap());
+        assert_eq!(x.take_round_trips(), 1);
+
+        drop(installed);
+        assert!(class.run(id, &x).unwrap());
+        assert_eq!(x.take_round_trips(), 1);
+    }
+}
@@ -0,0 +1,136 @@
+//! Re-applying the config file to a running window manager on the `reload` key action,
+//! `SIGHUP` or the config file being saved.
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use penrose::{
+    core::State,
+    util,
+    x::{ClientConfig, XConn, XConnExt},
+    x11rb::RustConn,
+};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+use crate::{
+    bindings::DynamicKeyBindings,
+    config::Settings,
+    hooks::manage_hook,
+    idle::reapply_idle_settings,
+    layouts::{layouts, LayoutParams},
+    notifications::reapply_notification_settings,
+    remote::{Command, Remote},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Load the config file and apply it to the running window manager.
+///
+/// Errors in the config file are logged and shown as a notification rather than being returned
+/// so that a typo never takes down the session.
+pub fn reload(state: &mut State<RustConn>, x: &RustConn) -> penrose::Result<()> {
+    tracing::info!("reloading config");
+    match Settings::load() {
+        Ok(settings) => apply_settings(settings, state, x),
+        Err(e) => {
+            report_error(&format!("{e:#}"));
+            Ok(())
+        }
+    }
+}
+
+/// Apply the given settings to the running window manager, replacing the ones stored in the
+/// [State] extension.
+pub fn apply_settings(
+    settings: Settings,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<()> {
+    let previous = state.extension::<Settings>()?.replace(settings.clone());
+
+    let theme = &settings.theme;
+    state.config.normal_border = theme.normal_border;
+    state.config.focused_border = theme.focused_border;
+    state.config.border_width = theme.border_width;
+    for &client in state.client_set.clients() {
+        x.set_client_border_color(client, theme.normal_border)?;
+        x.set_client_config(client, &[ClientConfig::BorderPx(theme.border_width)])?;
+    }
+
+    {
+        let dynamic = state.extension::<DynamicKeyBindings>()?;
+        let mut dynamic = dynamic.borrow_mut();
+        match dynamic.update(&settings) {
+            Ok(()) => dynamic.grab(x)?,
+            Err(e) => report_error(&format!("unable to parse key bindings: {e}")),
+        }
+    }
+
+    state.config.manage_hook = Some(manage_hook(&settings));
+
+    if previous.layout != settings.layout
+        || previous.bar.enabled != settings.bar.enabled
+        || previous.bar.height_px != settings.bar.height_px
+    {
+        state.config.default_layouts = layouts(&settings);
+        state.extension::<LayoutParams>()?.borrow_mut().clear();
+        for ws in state.client_set.workspaces_mut() {
+            let name = ws.layout_name();
+            ws.set_available_layouts(layouts(&settings));
+            ws.set_layout_by_name(&name);
+        }
+    }
+
+    reapply_notification_settings(state)?;
+    reapply_idle_settings(state)?;
+
+    x.refresh(state)
+}
+
+fn report_error(msg: &str) {
+    tracing::error!(%msg, "unable to reload config");
+    if let Err(e) = util::notify(&format!("penrose: config not reloaded\n{msg}")) {
+        tracing::warn!(%e, "unable to send notification");
+    }
+}
+
+/// Spawn a background thread requesting a reload every time the config file is modified.
+pub fn watch_config_file(path: PathBuf, remote: Remote) -> std::io::Result<()> {
+    let modified = move || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+    thread::Builder::new()
+        .name("config-watcher".to_string())
+        .spawn(move || {
+            let mut last: Option<SystemTime> = modified();
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                let current = modified();
+                if current != last {
+                    tracing::debug!(?current, "config file changed");
+                    last = current;
+                    remote.send(Command::Reload);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Spawn a background thread requesting a reload every time the window manager receives a
+/// `SIGHUP`.
+pub fn reload_on_sighup(remote: Remote) -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::Builder::new()
+        .name("sighup".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                tracing::info!("received SIGHUP");
+                remote.send(Command::Reload);
+            }
+        })?;
+
+    Ok(())
+}
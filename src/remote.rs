@@ -0,0 +1,220 @@
+//! Handing work to the window manager from other threads by queueing a [Command] and waking
+//! up the main event loop.
+use std::{
+    fmt,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use penrose::{core::State, x::XEvent, x11rb::RustConn};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{ClientMessageEvent, ConnectionExt, EventMask},
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    idle::idle_tick,
+    ipc::{handle_request, EventStream, Request, Response, WmState},
+    lock::lock_screen,
+    power::{finish_logout, PowerAction},
+    reload::reload,
+};
+
+/// The client message type used to wake up the main event loop.
+pub const WAKE_ATOM: &str = "_FAVILO_PENROSE_WAKE";
+
+/// How long to wait for the window manager to respond to a [Request].
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Work that can be requested from outside of the main event loop.
+#[derive(Debug)]
+pub enum Command {
+    /// Reload the config file and re-apply it to the running window manager
+    Reload,
+    /// Run an IPC request, sending back the response
+    Ipc(Request, Sender<Response>),
+    /// Send the current state, and every subsequent change to it, as JSON lines
+    Subscribe(Sender<String>),
+    /// Check how long the user has been idle, see [crate::idle]
+    IdleTick,
+    /// Lock the screen, sending back whether a new locker was started
+    Lock(Sender<bool>),
+    /// Run an entry chosen from the power menu
+    Power(PowerAction),
+    /// Stop waiting for clients to close and finish logging out
+    FinishLogout,
+}
+
+type WakeFn = dyn Fn() -> penrose::Result<()> + Send + Sync;
+
+/// A cloneable handle for sending [Command]s to the running window manager.
+#[derive(Clone)]
+pub struct Remote {
+    tx: Sender<Command>,
+    wake: Arc<WakeFn>,
+}
+
+impl fmt::Debug for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Remote").finish()
+    }
+}
+
+/// The receiving end of a [Remote], stored as a [State] extension.
+#[derive(Debug)]
+pub struct Inbox(Mutex<Receiver<Command>>);
+
+impl Inbox {
+    /// Take all of the currently queued commands.
+    pub fn drain(&self) -> Vec<Command> {
+        self.0.lock().unwrap().try_iter().collect()
+    }
+
+    /// Wait for the next command to be queued.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Command> {
+        self.0.lock().unwrap().recv_timeout(timeout).ok()
+    }
+}
+
+impl Remote {
+    /// Create a new [Remote] that wakes the window manager up using a client message, along with
+    /// the [Inbox] that needs adding to the window manager.
+    pub fn try_new() -> penrose::Result<(Self, Inbox)> {
+        let waker = Waker::try_new()?;
+
+        Ok(Self::new(move || waker.wake()))
+    }
+
+    /// Create a new [Remote] that calls `wake` after queuing each command.
+    pub fn new(wake: impl Fn() -> penrose::Result<()> + Send + Sync + 'static) -> (Self, Inbox) {
+        let (tx, rx) = channel();
+        let remote = Self {
+            tx,
+            wake: Arc::new(wake),
+        };
+
+        (remote, Inbox(Mutex::new(rx)))
+    }
+
+    /// Run a [Request] in the main event loop, blocking until it has been handled.
+    pub fn request(&self, request: Request) -> Response {
+        let (tx, rx) = channel();
+        self.send(Command::Ipc(request, tx));
+
+        match rx.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => Response::Error {
+                message: "timed out waiting for the window manager".to_string(),
+            },
+            Err(RecvTimeoutError::Disconnected) => Response::Error {
+                message: "window manager is no longer running".to_string(),
+            },
+        }
+    }
+
+    /// Queue a command and wake up the main event loop so that it gets run.
+    pub fn send(&self, cmd: Command) {
+        tracing::debug!(?cmd, "sending remote command");
+        if self.tx.send(cmd).is_err() {
+            tracing::warn!("window manager is no longer running: dropping command");
+            return;
+        }
+
+        if let Err(e) = (self.wake)() {
+            tracing::error!(%e, "unable to wake the window manager");
+        }
+    }
+}
+
+// A second connection to the X server used purely to send wake up messages to the root window.
+#[derive(Debug)]
+struct Waker {
+    conn: RustConnection,
+    root: u32,
+    atom: u32,
+}
+
+impl Waker {
+    fn try_new() -> penrose::Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        let atom = conn.intern_atom(false, WAKE_ATOM.as_bytes())?.reply()?.atom;
+
+        Ok(Self { conn, root, atom })
+    }
+
+    fn wake(&self) -> penrose::Result<()> {
+        let event = ClientMessageEvent::new(32, self.root, self.atom, [0u32; 5]);
+        let mask = EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY;
+        self.conn.send_event(false, self.root, mask, event)?;
+        self.conn.flush()?;
+
+        Ok(())
+    }
+}
+
+/// An event hook that runs any queued [Command]s when the main event loop is woken up by a
+/// [Remote].
+pub fn remote_event_hook(
+    event: &XEvent,
+    state: &mut State<RustConn>,
+    x: &RustConn,
+) -> penrose::Result<bool> {
+    match event {
+        XEvent::ClientMessage(m) if m.dtype == WAKE_ATOM => {
+            let pending = state.extension::<Inbox>()?.borrow().drain();
+
+            for cmd in pending {
+                if let Err(e) = run_command(cmd, state, x) {
+                    tracing::error!(%e, "error running remote command");
+                }
+            }
+
+            Ok(false)
+        }
+
+        _ => Ok(true),
+    }
+}
+
+fn run_command(cmd: Command, state: &mut State<RustConn>, x: &RustConn) -> penrose::Result<()> {
+    match cmd {
+        Command::Reload => reload(state, x),
+
+        Command::Ipc(request, reply) => {
+            tracing::debug!(?request, "running IPC request");
+            let response = handle_request(request, state, x);
+            // The client may have given up waiting on us
+            let _ = reply.send(response);
+
+            Ok(())
+        }
+
+        Command::Subscribe(tx) => {
+            let current = WmState::capture(state, x);
+            state
+                .extension::<EventStream>()?
+                .borrow_mut()
+                .subscribe(tx, current);
+
+            Ok(())
+        }
+
+        Command::IdleTick => idle_tick(state, x),
+
+        Command::Lock(reply) => {
+            let started = lock_screen(state)?;
+            let _ = reply.send(started);
+
+            Ok(())
+        }
+
+        Command::Power(action) => action.run(state, x),
+
+        Command::FinishLogout => finish_logout(state, x),
+    }
+}
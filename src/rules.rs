@@ -0,0 +1,606 @@
+//! Declarative window rules loaded from the `[[rules]]` array of the config file, compiled
+//! down to the same queries and manage hooks as the hand written ones in [crate::hooks].
+use std::fmt;
+
+use penrose::{
+    core::hooks::ManageHook,
+    extensions::hooks::manage::{DefaultTiled, FloatingFixed, FloatingRelative},
+    pure::geometry::Rect,
+    x::{Atom, Query},
+};
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::{
+    config::DialogSettings,
+    hooks::{
+        Always, AnyWindowType, BoxedQuery, ConstrainedSizeHints, FloatingSuggestedCentered,
+        IgnoreWindow, IsTransient, MoveToTag, StaticSizeHints, StrMatch, StrProp, StrQuery,
+    },
+    notifications::StackNotification,
+    props::PropConn,
+    struts::ManageDock,
+};
+
+const DEFAULT_RULES: &str = include_str!("default_rules.toml");
+
+/// The built in rules that run before any rules from the user's config file.
+pub fn default_rules() -> Vec<Rule> {
+    #[derive(Deserialize)]
+    struct DefaultRules {
+        rules: Vec<Rule>,
+    }
+
+    let defaults: DefaultRules = toml::from_str(DEFAULT_RULES).expect("default rules are valid");
+
+    defaults.rules
+}
+
+/// The rules floating dialogs (and the like) over their parent window, or tiling them for the
+/// classes that have asked for that.
+pub fn dialog_rules(settings: &DialogSettings) -> Vec<Rule> {
+    if !settings.float {
+        return vec![];
+    }
+
+    let dialog = Matcher {
+        any: vec![
+            Matcher {
+                window_type: Some(OneOrMany::Many(vec![
+                    "dialog".to_string(),
+                    "splash".to_string(),
+                    "utility".to_string(),
+                ])),
+                ..Default::default()
+            },
+            Matcher {
+                transient: Some(true),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    let centered = FloatingSuggestedCentered::default();
+    let mut rules = vec![Rule {
+        matcher: dialog.clone(),
+        actions: OneOrMany::One(RuleAction::FloatCentered {
+            width: centered.width,
+            height: centered.height,
+        }),
+    }];
+
+    if !settings.tiled_classes.is_empty() {
+        let classes = settings
+            .tiled_classes
+            .iter()
+            .map(|c| StrMatch::from(c.as_str()));
+        rules.push(Rule {
+            matcher: Matcher {
+                class: Some(OneOrMany::Many(classes.collect())),
+                all: vec![dialog],
+                ..Default::default()
+            },
+            actions: OneOrMany::One(RuleAction::Tile),
+        });
+    }
+
+    rules
+}
+
+/// A single window rule: when `match` holds for a newly managed window, every `action` is run.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(rename = "match")]
+    pub matcher: Matcher,
+    #[serde(rename = "action")]
+    pub actions: OneOrMany<RuleAction>,
+}
+
+impl Rule {
+    /// Compile this rule into a [ManageHook] that runs its actions if its matcher holds.
+    pub fn compile<X: PropConn + 'static>(&self) -> Box<dyn ManageHook<X>> {
+        let actions: Vec<Box<dyn ManageHook<X>>> =
+            self.actions.iter().map(RuleAction::compile).collect();
+
+        Box::new((self.matcher.compile(), actions))
+    }
+}
+
+/// Either a single value or a list of values.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            Self::One(t) => std::slice::from_ref(t).iter(),
+            Self::Many(ts) => ts.iter(),
+        }
+    }
+}
+
+/// Predicates on the properties of a window.
+///
+/// Every predicate that is set must hold for the matcher to hold. Fields that accept a list of
+/// strings hold if any of the strings match.
+///
+/// String properties are compared exactly by default. Other comparisons are written as a single
+/// entry table: `{ regex = "^Zoom" }`, `{ glob = "*.pdf" }`, `{ prefix = "Zoom" }`,
+/// `{ suffix = "- Firefox" }` or `{ ignore-case = "zoom" }`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matcher {
+    /// The second string of `WM_CLASS`
+    pub class: Option<OneOrMany<StrMatch>>,
+    /// The first string of `WM_CLASS`
+    pub instance: Option<OneOrMany<StrMatch>>,
+    /// `WM_NAME` falling back to `_NET_WM_NAME`
+    pub title: Option<OneOrMany<StrMatch>>,
+    /// `_NET_WM_NAME`
+    pub net_wm_name: Option<OneOrMany<StrMatch>>,
+    /// `WM_NAME`
+    pub wm_name: Option<OneOrMany<StrMatch>>,
+    /// `WM_WINDOW_ROLE`
+    pub role: Option<OneOrMany<StrMatch>>,
+    /// The name of the process that owns the window, found from `_NET_WM_PID`
+    pub process: Option<OneOrMany<StrMatch>>,
+    /// The suffix of a `_NET_WM_WINDOW_TYPE_*` atom, e.g. "dialog" or "dock". Windows can have
+    /// several types and the matcher holds if any of them are listed.
+    pub window_type: Option<OneOrMany<String>>,
+    /// Whether the window is transient for another window (`WM_TRANSIENT_FOR`)
+    pub transient: Option<bool>,
+    pub size_hints: Option<SizeHintsMatch>,
+    /// Holds if all of the nested matchers hold
+    pub all: Vec<Matcher>,
+    /// Holds if any of the nested matchers hold
+    pub any: Vec<Matcher>,
+    /// Holds if the nested matcher does not hold
+    pub not: Option<Box<Matcher>>,
+}
+
+/// Predicates on `WM_NORMAL_HINTS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SizeHintsMatch {
+    /// The minimum and maximum size are the same so the window can not be resized
+    Constrained,
+    /// The window has static gravity and a user specified size
+    Static,
+}
+
+impl Matcher {
+    /// Compile this matcher into a single [Query].
+    pub fn compile<X: PropConn + 'static>(&self) -> BoxedQuery<X> {
+        let mut queries: Vec<BoxedQuery<X>> = Vec::new();
+
+        let str_props = [
+            (&self.class, StrProp::ClassName),
+            (&self.instance, StrProp::AppName),
+            (&self.title, StrProp::Title),
+            (
+                &self.net_wm_name,
+                StrProp::Prop(Atom::NetWmName.as_ref().to_string()),
+            ),
+            (
+                &self.wm_name,
+                StrProp::Prop(Atom::WmName.as_ref().to_string()),
+            ),
+            (&self.role, StrProp::Role),
+            (&self.process, StrProp::ProcessName),
+        ];
+
+        for (patterns, prop) in str_props {
+            if let Some(patterns) = patterns {
+                queries.push(BoxedQuery::new(StrQuery::any(
+                    prop,
+                    patterns.iter().cloned(),
+                )));
+            }
+        }
+
+        if let Some(types) = &self.window_type {
+            queries.push(BoxedQuery::new(AnyWindowType::named(types.iter())));
+        }
+
+        match self.transient {
+            Some(true) => queries.push(BoxedQuery::new(IsTransient)),
+            Some(false) => queries.push(BoxedQuery::new(IsTransient.not())),
+            None => (),
+        }
+
+        match self.size_hints {
+            Some(SizeHintsMatch::Constrained) => {
+                queries.push(BoxedQuery::new(ConstrainedSizeHints))
+            }
+            Some(SizeHintsMatch::Static) => queries.push(BoxedQuery::new(StaticSizeHints)),
+            None => (),
+        }
+
+        queries.extend(self.all.iter().map(Matcher::compile));
+
+        if let Some(q) = self
+            .any
+            .iter()
+            .map(Matcher::compile)
+            .reduce(|a, b| BoxedQuery::new(a.or(b)))
+        {
+            queries.push(q);
+        }
+
+        if let Some(not) = &self.not {
+            queries.push(BoxedQuery::new(not.compile().not()));
+        }
+
+        queries
+            .into_iter()
+            .reduce(|a, b| BoxedQuery::new(a.and(b)))
+            .unwrap_or_else(|| BoxedQuery::new(Always))
+    }
+}
+
+/// What to do with a window matched by a [Rule].
+///
+/// Actions without parameters (or with sensible defaults) can be written as a bare string,
+/// e.g. `"tile"` or `"float-centered"`. The rest are written as a single entry table, e.g.
+/// `{ send-to-tag = "3" }` or `{ float-centered = { width = 0.5, height = 0.5 } }`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleAction {
+    /// Float at the size suggested by the client, centered on the current screen
+    FloatCentered { width: f64, height: f64 },
+    /// Float at a position relative to the current screen
+    FloatRelative { x: f64, y: f64, w: f64, h: f64 },
+    /// Float at a fixed position
+    FloatFixed { x: i32, y: i32, w: u32, h: u32 },
+    /// Leave the window tiled
+    Tile,
+    /// Stop managing the window
+    Ignore,
+    /// Show the window unmanaged, keeping tiled windows clear of the space it reserves
+    Dock,
+    /// Move the window to the given tag
+    SendToTag(String),
+    /// Stack the window with any other notifications in the corner of the screen, at the size
+    /// it asks for or the given fractions of the screen size if it doesn't
+    Notification { width: f64, height: f64 },
+}
+
+impl RuleAction {
+    const NAMES: &'static [&'static str] = &[
+        "float-centered",
+        "float-relative",
+        "float-fixed",
+        "tile",
+        "ignore",
+        "dock",
+        "send-to-tag",
+        "notification",
+    ];
+
+    /// Compile this action into a [ManageHook].
+    pub fn compile<X: PropConn + 'static>(&self) -> Box<dyn ManageHook<X>> {
+        match self.clone() {
+            Self::FloatCentered { width, height } => {
+                FloatingSuggestedCentered::new(width, height).boxed()
+            }
+            Self::FloatRelative { x, y, w, h } => FloatingRelative::new(x, y, w, h).boxed(),
+            Self::FloatFixed { x, y, w, h } => FloatingFixed(Rect::new(x, y, w, h)).boxed(),
+            Self::Tile => DefaultTiled.boxed(),
+            Self::Ignore => IgnoreWindow.boxed(),
+            Self::Dock => ManageDock.boxed(),
+            Self::SendToTag(tag) => MoveToTag(tag).boxed(),
+            Self::Notification { width, height } => StackNotification::new(width, height).boxed(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // The table form of each action, deserialized via serde's externally tagged enums
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+        enum Table {
+            FloatCentered(Size),
+            FloatRelative(Relative),
+            FloatFixed(Fixed),
+            Tile {},
+            Ignore {},
+            Dock {},
+            SendToTag(String),
+            Notification(Size),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Size {
+            width: f64,
+            height: f64,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Relative {
+            x: f64,
+            y: f64,
+            w: f64,
+            h: f64,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Fixed {
+            x: i32,
+            y: i32,
+            w: u32,
+            h: u32,
+        }
+
+        struct ActionVisitor;
+
+        impl<'de> Visitor<'de> for ActionVisitor {
+            type Value = RuleAction;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an action name or a single entry table")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<RuleAction, E> {
+                let centered = FloatingSuggestedCentered::default();
+                let notification = StackNotification::default();
+
+                match name {
+                    "float-centered" => Ok(RuleAction::FloatCentered {
+                        width: centered.width,
+                        height: centered.height,
+                    }),
+                    "tile" => Ok(RuleAction::Tile),
+                    "ignore" => Ok(RuleAction::Ignore),
+                    "dock" => Ok(RuleAction::Dock),
+                    "notification" => Ok(RuleAction::Notification {
+                        width: notification.width,
+                        height: notification.height,
+                    }),
+                    "float-relative" | "float-fixed" | "send-to-tag" => Err(E::custom(format!(
+                        "'{name}' requires parameters: write it as {{ {name} = ... }}"
+                    ))),
+                    _ => Err(E::unknown_variant(name, RuleAction::NAMES)),
+                }
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<RuleAction, A::Error> {
+                let action = match Table::deserialize(MapAccessDeserializer::new(map))? {
+                    Table::FloatCentered(Size { width, height }) => {
+                        check_ratios(&[width, height])?;
+                        RuleAction::FloatCentered { width, height }
+                    }
+                    Table::FloatRelative(Relative { x, y, w, h }) => {
+                        RuleAction::FloatRelative { x, y, w, h }
+                    }
+                    Table::FloatFixed(Fixed { x, y, w, h }) => {
+                        RuleAction::FloatFixed { x, y, w, h }
+                    }
+                    Table::Tile {} => RuleAction::Tile,
+                    Table::Ignore {} => RuleAction::Ignore,
+                    Table::Dock {} => RuleAction::Dock,
+                    Table::SendToTag(tag) => RuleAction::SendToTag(tag),
+                    Table::Notification(Size { width, height }) => {
+                        check_ratios(&[width, height])?;
+                        RuleAction::Notification { width, height }
+                    }
+                };
+
+                Ok(action)
+            }
+        }
+
+        fn check_ratios<E: de::Error>(ratios: &[f64]) -> Result<(), E> {
+            match ratios.iter().find(|r| !(**r > 0.0 && **r <= 1.0)) {
+                Some(r) => Err(E::custom(format!(
+                    "sizes are fractions of the screen and must be in the range (0.0, 1.0]: got {r}"
+                ))),
+                None => Ok(()),
+            }
+        }
+
+        deserializer.deserialize_any(ActionVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for StrMatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+        enum Table {
+            Regex(String),
+            Glob(String),
+            Prefix(String),
+            Suffix(String),
+            IgnoreCase(String),
+        }
+
+        struct StrMatchVisitor;
+
+        impl<'de> Visitor<'de> for StrMatchVisitor {
+            type Value = StrMatch;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a string or a single entry table")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<StrMatch, E> {
+                Ok(StrMatch::from(s))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<StrMatch, A::Error> {
+                let pattern = match Table::deserialize(MapAccessDeserializer::new(map))? {
+                    Table::Regex(s) => StrMatch::regex(&s).map_err(de::Error::custom)?,
+                    Table::Glob(s) => StrMatch::glob(&s).map_err(de::Error::custom)?,
+                    Table::Prefix(s) => StrMatch::Prefix(s),
+                    Table::Suffix(s) => StrMatch::Suffix(s),
+                    Table::IgnoreCase(s) => StrMatch::ignore_case(&s),
+                };
+
+                Ok(pattern)
+            }
+        }
+
+        deserializer.deserialize_any(StrMatchVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_rules(raw: &str) -> Result<Vec<Rule>, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Rules {
+            rules: Vec<Rule>,
+        }
+
+        toml::from_str::<Rules>(raw).map(|r| r.rules)
+    }
+
+    #[test]
+    fn default_rules_parse() {
+        let rules = default_rules();
+
+        assert_eq!(rules.len(), 7);
+        assert_eq!(
+            rules[0].actions,
+            OneOrMany::One(RuleAction::Notification {
+                width: 0.15,
+                height: 0.05,
+            })
+        );
+    }
+
+    #[test]
+    fn dialogs_can_be_tiled_per_class() {
+        let mut settings = DialogSettings::default();
+        assert_eq!(dialog_rules(&settings).len(), 1);
+
+        settings.tiled_classes = vec!["Gimp".to_string()];
+        let rules = dialog_rules(&settings);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].actions, OneOrMany::One(RuleAction::Tile));
+        assert_eq!(
+            rules[1].matcher.class,
+            Some(OneOrMany::Many(vec![StrMatch::from("Gimp")]))
+        );
+
+        settings.float = false;
+        assert!(dialog_rules(&settings).is_empty());
+    }
+
+    #[test]
+    fn actions_can_be_bare_names_or_tables() {
+        let rules = parse_rules(
+            r#"
+            [[rules]]
+            match = { class = "mpv" }
+            action = ["float-centered", { send-to-tag = "5" }, { float-fixed = { x = 1, y = 2, w = 3, h = 4 } }]
+            "#,
+        )
+        .unwrap();
+
+        let actions: Vec<_> = rules[0].actions.iter().cloned().collect();
+        assert_eq!(
+            actions,
+            vec![
+                RuleAction::FloatCentered {
+                    width: 0.25,
+                    height: 0.25
+                },
+                RuleAction::SendToTag("5".to_string()),
+                RuleAction::FloatFixed {
+                    x: 1,
+                    y: 2,
+                    w: 3,
+                    h: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matchers_nest() {
+        let rules = parse_rules(
+            r#"
+            [[rules]]
+            action = "tile"
+
+            [rules.match]
+            class = ["kitty", "Alacritty"]
+            any = [{ role = "main" }, { not = { window_type = "dialog" } }]
+            all = [{ transient = false, process = { prefix = "kitty" } }]
+            "#,
+        )
+        .unwrap();
+
+        let m = &rules[0].matcher;
+        assert_eq!(m.class.as_ref().map(|c| c.iter().count()), Some(2));
+        assert_eq!(m.any.len(), 2);
+        assert!(m.any[1].not.is_some());
+        assert_eq!(m.all[0].transient, Some(false));
+        assert!(m.all[0].process.is_some());
+    }
+
+    #[test]
+    fn strings_can_be_matched_by_pattern() {
+        let rules = parse_rules(
+            r#"
+            [[rules]]
+            match = { class = { ignore-case = "Zoom" }, title = ["", { regex = "^Zoom( .*)?$" }, { glob = "*Chat" }] }
+            action = "tile"
+            "#,
+        )
+        .unwrap();
+
+        let m = &rules[0].matcher;
+        assert_eq!(
+            m.class,
+            Some(OneOrMany::One(StrMatch::IgnoreCase("zoom".to_string())))
+        );
+        let titles: Vec<_> = m.title.iter().flat_map(|t| t.iter()).collect();
+        assert_eq!(titles[0], &StrMatch::from(""));
+        assert!(titles[1].is_match("Zoom Workplace"));
+        assert!(titles[2].is_match("Meeting Chat"));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let cases = [
+            r#"action = "explode""#,
+            r#"action = "send-to-tag""#,
+            r#"action = { float-centered = { width = 2.0, height = 0.5 } }"#,
+        ];
+
+        for action in cases {
+            let raw = format!("[[rules]]\nmatch = {{ class = \"x\" }}\n{action}");
+            assert!(parse_rules(&raw).is_err(), "{action}");
+        }
+
+        assert!(parse_rules("[[rules]]\nmatch = { klass = \"x\" }\naction = \"tile\"").is_err());
+        assert!(
+            parse_rules("[[rules]]\nmatch = { title = { regex = \"(\" } }\naction = \"tile\"")
+                .is_err()
+        );
+        assert!(
+            parse_rules("[[rules]]\nmatch = { title = { rgx = \"x\" } }\naction = \"tile\"")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Saving and restoring window manager state across the restarts done by `bin/run-penrose.sh`.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Context, Result};
+use penrose::{
+    builtin::actions::exit,
+    core::{bindings::KeyEventHandler, State},
+    pure::{geometry::Rect, Stack, StackSet},
+    x::{XConn, XConnExt},
+    x11rb::RustConn,
+    Xid,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{state_home, LayoutSettings, Settings},
+    layouts::{LayoutParams, MainParams},
+};
+
+/// Set by `bin/run-penrose.sh` when the window manager is being restarted.
+pub const RESTARTED_VAR: &str = "RESTARTED";
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// A snapshot of the client set that can be written to disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// The tag shown on each screen, ordered by screen index.
+    pub screens: Vec<String>,
+    pub focused_screen: usize,
+    pub workspaces: Vec<WorkspaceSession>,
+}
+
+/// The saved state of a single workspace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceSession {
+    pub tag: String,
+    pub layout: String,
+    /// Clients in stack order from head to tail.
+    pub clients: Vec<Xid>,
+    pub focus: Option<Xid>,
+    /// Floating clients along with their absolute screen position.
+    #[serde(default)]
+    pub floating: Vec<(Xid, Rect)>,
+    /// Layouts that have had their main area adjusted.
+    #[serde(default)]
+    pub layout_params: Vec<(String, MainParams)>,
+}
+
+impl Session {
+    /// The default location of the state file: `$XDG_STATE_HOME/penrose/state.json`.
+    pub fn path() -> PathBuf {
+        state_home().join(STATE_FILE_NAME)
+    }
+
+    /// Take a snapshot of the given client set. `floating_rect` is used to look up the current
+    /// position of floating clients.
+    pub fn capture(
+        cs: &StackSet<Xid>,
+        params: &LayoutParams,
+        floating_rect: impl Fn(Xid) -> Option<Rect>,
+    ) -> Self {
+        let mut screens: Vec<_> = cs
+            .screens()
+            .map(|s| (s.index(), s.workspace.tag().to_string()))
+            .collect();
+        screens.sort_by_key(|&(i, _)| i);
+
+        let workspaces = cs
+            .workspaces()
+            .map(|ws| WorkspaceSession {
+                tag: ws.tag().to_string(),
+                layout: ws.layout_name(),
+                clients: ws.clients().copied().collect(),
+                focus: ws.focus().copied(),
+                floating: ws
+                    .clients()
+                    .filter(|&c| cs.is_floating(c))
+                    .flat_map(|&c| floating_rect(c).map(|r| (c, r)))
+                    .collect(),
+                layout_params: params
+                    .for_tag(ws.tag())
+                    .map(|(layout, p)| (layout.to_string(), p))
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            screens: screens.into_iter().map(|(_, tag)| tag).collect(),
+            focused_screen: cs.current_screen().index(),
+            workspaces,
+        }
+    }
+
+    /// Put every client that is still managed back where it was when this session was captured.
+    ///
+    /// Clients that are no longer present are dropped, and clients that were not part of the
+    /// session are left on whichever workspace they are currently on, after the restored ones.
+    pub fn restore(
+        &self,
+        cs: &mut StackSet<Xid>,
+        params: &mut LayoutParams,
+        settings: &LayoutSettings,
+    ) {
+        for saved in self.workspaces.iter() {
+            if !cs.contains_tag(&saved.tag) {
+                tracing::warn!(tag = %saved.tag, "saved tag no longer exists: skipping");
+                continue;
+            }
+
+            let clients: Vec<Xid> = saved
+                .clients
+                .iter()
+                .filter(|&c| cs.contains(c))
+                .copied()
+                .collect();
+            for client in clients.iter() {
+                cs.move_client_to_tag(client, &saved.tag);
+            }
+
+            // Floating positions can only be set for clients on a screen
+            cs.focus_tag(&saved.tag);
+            cs.modify(|current| {
+                let others = current
+                    .into_iter()
+                    .flat_map(|s| s.flatten())
+                    .filter(|c| !clients.contains(c));
+                let mut stack = Stack::try_from_iter(clients.iter().copied().chain(others))?;
+                if let Some(focus) = saved.focus.filter(|f| clients.contains(f)) {
+                    stack.focus_element(&focus);
+                }
+
+                Some(stack)
+            });
+
+            for &(client, r) in saved.floating.iter() {
+                if clients.contains(&client) {
+                    if let Err(e) = cs.float(client, r) {
+                        tracing::warn!(%e, %client, "unable to restore floating position");
+                    }
+                }
+            }
+
+            let ws = cs.current_workspace_mut();
+            params.restore(ws, &saved.layout_params, settings);
+            ws.set_layout_by_name(&saved.layout);
+        }
+
+        let n_screens = cs.screens().count();
+        for (index, tag) in self.screens.iter().enumerate().take(n_screens) {
+            cs.focus_screen(index);
+            cs.pull_tag_to_screen(tag);
+        }
+        cs.focus_screen(self.focused_screen.min(n_screens - 1));
+    }
+
+    /// Write this session to the given path, creating any missing parent directories.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create {}", dir.display()))?;
+        }
+
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json).with_context(|| format!("unable to write {}", path.display()))
+    }
+
+    /// Read a previously saved session from the given path.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+
+        serde_json::from_str(&raw)
+            .with_context(|| format!("invalid session file {}", path.display()))
+    }
+}
+
+/// Save the current session and then exit, ready to be restored when `bin/run-penrose.sh`
+/// starts us back up.
+pub fn save_and_exit() -> Box<dyn KeyEventHandler<RustConn>> {
+    let mut exit = exit();
+
+    Box::new(move |state: &mut State<RustConn>, x: &RustConn| {
+        let params = state.extension::<LayoutParams>()?;
+        let session = Session::capture(&state.client_set, &params.borrow(), |c| {
+            x.client_geometry(c).ok()
+        });
+
+        if let Err(e) = session.save(Session::path()) {
+            tracing::error!(%e, "unable to save session");
+        }
+
+        exit.call(state, x)
+    })
+}
+
+/// A startup hook restoring the session saved by [save_and_exit] if we are being restarted.
+///
+/// Surviving windows are left for penrose to manage so that they go through the manage hook like
+/// any other client: the saved layout is then applied by a one-shot refresh hook. This needs to
+/// be the last startup hook to run so that the next refresh is the one penrose does once it has managed
+/// the existing clients.
+///
+/// The saved session is removed once it has been read so that it is only ever restored once.
+pub fn restore_session(state: &mut State<RustConn>, _: &RustConn) -> penrose::Result<()> {
+    let path = Session::path();
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let session = if std::env::var_os(RESTARTED_VAR).is_some() {
+        Some(Session::load(&path))
+    } else {
+        tracing::info!("not restarting: ignoring saved session");
+        None
+    };
+
+    if let Err(e) = fs::remove_file(&path) {
+        tracing::warn!(%e, ?path, "unable to remove saved session");
+    }
+
+    match session {
+        Some(Ok(session)) => {
+            let mut pending = Some(session);
+            state.config.compose_or_set_refresh_hook(
+                move |state: &mut State<RustConn>, x: &RustConn| {
+                    let Some(session) = pending.take() else {
+                        return Ok(());
+                    };
+
+                    tracing::info!("restoring saved session");
+                    let settings = state.extension::<Settings>()?.borrow().layout;
+                    let params = state.extension::<LayoutParams>()?;
+                    session.restore(&mut state.client_set, &mut params.borrow_mut(), &settings);
+
+                    x.refresh(state)
+                },
+            );
+
+            Ok(())
+        }
+        Some(Err(e)) => {
+            tracing::error!(%e, "unable to restore saved session");
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bindings::LayoutMessage, config::Settings, layouts::layouts};
+
+    fn stack_set() -> StackSet<Xid> {
+        let screens = [Rect::new(0, 0, 1920, 1080), Rect::new(1920, 0, 1920, 1080)];
+        StackSet::try_new(layouts(&Settings::default()), ["1", "2", "3"], screens).unwrap()
+    }
+
+    #[test]
+    fn sessions_round_trip_through_the_client_set() {
+        let settings = Settings::default().layout;
+        let mut cs = stack_set();
+        for id in 1..=3 {
+            cs.insert(Xid::from(id));
+        }
+        cs.float(Xid::from(3), Rect::new(100, 100, 400, 300))
+            .unwrap();
+        cs.move_client_to_tag(&Xid::from(2), "3");
+        cs.focus_tag("3");
+        cs.insert(Xid::from(4));
+        cs.current_workspace_mut().next_layout();
+
+        let mut params = LayoutParams::default();
+        params.record(
+            "3",
+            &cs.current_workspace().layout_name(),
+            LayoutMessage::ExpandMain,
+            &settings,
+        );
+
+        let floating = |c| (c == Xid::from(3)).then(|| Rect::new(100, 100, 400, 300));
+        let session = Session::capture(&cs, &params, floating);
+        let json = serde_json::to_string(&session).unwrap();
+        let session: Session = serde_json::from_str(&json).unwrap();
+
+        // Client 4 has gone away while we were restarting and client 5 is new. Penrose manages
+        // everything that is left on the first tag before the session is restored.
+        let mut restored = stack_set();
+        for id in [1, 2, 3, 5] {
+            restored.insert(Xid::from(id));
+        }
+        let mut restored_params = LayoutParams::default();
+        session.restore(&mut restored, &mut restored_params, &settings);
+
+        assert_eq!(restored.tag_for_client(&Xid::from(1)), Some("1"));
+        assert_eq!(restored.tag_for_client(&Xid::from(2)), Some("3"));
+        assert_eq!(restored.tag_for_client(&Xid::from(3)), Some("1"));
+        assert_eq!(restored.tag_for_client(&Xid::from(5)), Some("1"));
+        assert!(!restored.contains(&Xid::from(4)));
+        assert!(restored.is_floating(&Xid::from(3)));
+        assert_eq!(restored.current_tag(), "3");
+        assert_eq!(restored.tag_for_screen(0), Some("3"));
+        assert_eq!(restored.tag_for_screen(1), Some("2"));
+        assert_eq!(
+            restored.current_workspace().layout_name(),
+            cs.current_workspace().layout_name()
+        );
+        assert_eq!(restored_params, params);
+    }
+}
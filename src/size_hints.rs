@@ -0,0 +1,530 @@
+//! Decoding the ICCCM `WM_NORMAL_HINTS` property, including the resize increments, aspect
+//! ratios and gravity that penrose leaves out.
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock, RwLockReadGuard},
+};
+
+use penrose::{
+    core::{hooks::ManageHook, State},
+    pure::geometry::{Point, Rect},
+    x::{event::PropertyEvent, property::WmNormalHintsFlags, Atom, XConn, XConnExt, XEvent},
+    x11rb::RustConn,
+    Error, Result, Xid,
+};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, Gravity};
+
+use crate::props::PropConn;
+
+// Clients written against pre-ICCCM Xlib omit the base size and window gravity.
+const MIN_LEN: usize = 15;
+
+/// A width and height in pixels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Size {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self { w, h }
+    }
+
+    fn non_zero(w: u32, h: u32) -> Option<Self> {
+        (w > 0 && h > 0).then_some(Self { w, h })
+    }
+}
+
+/// An aspect ratio expressed as `num / den`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aspect {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Aspect {
+    pub fn new(num: u32, den: u32) -> Self {
+        Self { num, den }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+/// The decoded contents of a `WM_NORMAL_HINTS` property.
+///
+/// Fields are only populated when the corresponding flag is set and the values given are
+/// usable, so `min: None` means the client has no minimum size rather than a minimum of zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHints {
+    pub flags: WmNormalHintsFlags,
+    /// The position the client asked for, set by either the user or the program.
+    pub position: Option<Point>,
+    /// The size the client asked for, set by either the user or the program.
+    pub size: Option<Size>,
+    pub min: Option<Size>,
+    pub max: Option<Size>,
+    /// The steps the client prefers to be resized in, on top of `base`.
+    pub increment: Option<Size>,
+    /// The range of allowed aspect ratios as `(min, max)`.
+    pub aspect: Option<(Aspect, Aspect)>,
+    pub base: Option<Size>,
+    pub gravity: Option<Gravity>,
+}
+
+impl SizeHints {
+    /// Decode the 18 `u32` words of a raw `WM_SIZE_HINTS` property.
+    ///
+    /// ```C
+    /// typedef struct {
+    ///     long flags;
+    ///     int x, y;                  /* Obsolete */
+    ///     int width, height;         /* Obsolete */
+    ///     int min_width, min_height;
+    ///     int max_width, max_height;
+    ///     int width_inc, height_inc;
+    ///     struct {
+    ///            int x;              /* numerator */
+    ///            int y;              /* denominator */
+    ///     } min_aspect, max_aspect;
+    ///     int base_width, base_height;
+    ///     int win_gravity;
+    /// } XSizeHints;
+    /// ```
+    pub fn try_from_raw(raw: &[u32]) -> Result<Self> {
+        if raw.len() < MIN_LEN {
+            return Err(Error::InvalidHints {
+                reason: format!(
+                    "raw bytes should be [u32; 18] for WM_SIZE_HINTS, got [u32; {}]",
+                    raw.len()
+                ),
+            });
+        }
+
+        let flags = WmNormalHintsFlags::from_bits_truncate(raw[0]);
+        let word = |i: usize| raw.get(i).copied().unwrap_or_default();
+        let size_if = |flag, i: usize| {
+            if flags.intersects(flag) {
+                Size::non_zero(word(i), word(i + 1))
+            } else {
+                None
+            }
+        };
+
+        let position = flags
+            .intersects(WmNormalHintsFlags::U_POSITION | WmNormalHintsFlags::P_POSITION)
+            .then(|| Point::new(raw[1] as i32, raw[2] as i32));
+
+        let aspect = if flags.contains(WmNormalHintsFlags::P_ASPECT) {
+            let (min, max) = (Aspect::new(raw[11], raw[12]), Aspect::new(raw[13], raw[14]));
+            [min, max]
+                .iter()
+                .all(|a| a.num > 0 && a.den > 0)
+                .then_some((min, max))
+        } else {
+            None
+        };
+
+        let gravity = (flags.contains(WmNormalHintsFlags::P_WIN_GRAVITY) && raw.len() > 17)
+            .then(|| Gravity::from(raw[17]));
+
+        Ok(Self {
+            position,
+            size: size_if(WmNormalHintsFlags::U_SIZE | WmNormalHintsFlags::P_SIZE, 3),
+            min: size_if(WmNormalHintsFlags::P_MIN_SIZE, 5),
+            max: size_if(WmNormalHintsFlags::P_MAX_SIZE, 7),
+            increment: size_if(WmNormalHintsFlags::P_RESIZE_INC, 9),
+            aspect,
+            base: size_if(WmNormalHintsFlags::P_BASE_SIZE, 15),
+            gravity,
+            flags,
+        })
+    }
+
+    /// Whether the position was chosen by the user rather than the program.
+    pub fn user_position(&self) -> bool {
+        self.flags.contains(WmNormalHintsFlags::U_POSITION)
+    }
+
+    /// Whether the size was chosen by the user rather than the program.
+    pub fn user_size(&self) -> bool {
+        self.flags.contains(WmNormalHintsFlags::U_SIZE)
+    }
+
+    /// Whether the client has asked to never be resized.
+    pub fn is_fixed(&self) -> bool {
+        matches!((self.min, self.max), (Some(min), Some(max)) if min == max)
+    }
+
+    /// The window gravity, defaulting to `NorthWest` as per the ICCCM.
+    pub fn gravity(&self) -> Gravity {
+        self.gravity.unwrap_or(Gravity::NORTH_WEST)
+    }
+
+    /// Adjust the size of `r` to respect every size constraint the client has given: aspect
+    /// ratio, resize increments and the min and max sizes. The position of `r` is untouched.
+    pub fn constrain(&self, r: Rect) -> Rect {
+        self.apply(r, true)
+    }
+
+    /// As [SizeHints::constrain] while also keeping `r` no larger than `bounds`, unless the
+    /// client's minimum size is larger.
+    pub fn constrain_within(&self, r: Rect, bounds: Size) -> Rect {
+        let max = match self.max {
+            Some(max) => Size::new(max.w.min(bounds.w), max.h.min(bounds.h)),
+            None => bounds,
+        };
+
+        Self {
+            max: Some(max),
+            ..self.clone()
+        }
+        .constrain(r)
+    }
+
+    /// Shrink the size of `r` to a whole number of resize increments, respecting the min and
+    /// max sizes but not the aspect ratio. The position of `r` is untouched.
+    pub fn round_to_increments(&self, r: Rect) -> Rect {
+        self.apply(r, false)
+    }
+
+    // This follows the same steps as dwm's applysizehints: the base size is removed before the
+    // aspect ratio is checked unless it is the same as the min size, in which case the ICCCM
+    // says the aspect ratio applies to the full window size.
+    fn apply(&self, mut r: Rect, with_aspect: bool) -> Rect {
+        // Each of the base and min sizes is used in place of the other if it is missing
+        let base = self.base.or(self.min).unwrap_or_default();
+        let min = self.min.or(self.base).unwrap_or_default();
+        let base_is_min = base == min;
+
+        let (mut w, mut h) = (r.w, r.h);
+        if !base_is_min {
+            w = w.saturating_sub(base.w);
+            h = h.saturating_sub(base.h);
+        }
+
+        if let (true, Some((lo, hi))) = (with_aspect, self.aspect) {
+            if w > 0 && h > 0 {
+                let ratio = w as f64 / h as f64;
+                if ratio > hi.ratio() {
+                    w = (h as f64 * hi.ratio()).round() as u32;
+                } else if ratio < lo.ratio() {
+                    h = (w as f64 / lo.ratio()).round() as u32;
+                }
+            }
+        }
+
+        if base_is_min {
+            w = w.saturating_sub(base.w);
+            h = h.saturating_sub(base.h);
+        }
+
+        if let Some(inc) = self.increment {
+            w -= w % inc.w;
+            h -= h % inc.h;
+        }
+
+        w = (w + base.w).max(min.w);
+        h = (h + base.h).max(min.h);
+
+        if let Some(max) = self.max {
+            w = w.min(max.w);
+            h = h.min(max.h);
+        }
+
+        r.w = w;
+        r.h = h;
+
+        r
+    }
+}
+
+/// The [SizeHints] of every managed client, kept up to date by [track_size_hints] so that they
+/// are available to layouts, which have no access to the X connection.
+#[derive(Debug, Default)]
+pub struct TrackedSizeHints(RwLock<HashMap<Xid, SizeHints>>);
+
+impl TrackedSizeHints {
+    /// The hints for all clients managed by this window manager.
+    pub fn global() -> Arc<Self> {
+        static TRACKED: OnceLock<Arc<TrackedSizeHints>> = OnceLock::new();
+
+        Arc::clone(TRACKED.get_or_init(Default::default))
+    }
+
+    pub fn get(&self, id: Xid) -> Option<SizeHints> {
+        self.read().get(&id).cloned()
+    }
+
+    /// Store the hints for a client, returning whether they differ from those already stored.
+    pub fn insert(&self, id: Xid, hints: Option<SizeHints>) -> bool {
+        let mut map = self.0.write().unwrap_or_else(|e| e.into_inner());
+        match hints {
+            Some(hints) => map.insert(id, hints.clone()) != Some(hints),
+            None => map.remove(&id).is_some(),
+        }
+    }
+
+    /// Fetch and store the current hints for a client, returning whether they have changed.
+    pub fn update<X: SizeHintsConn>(&self, id: Xid, x: &X) -> Result<bool> {
+        Ok(self.insert(id, x.size_hints(id)?))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<Xid, SizeHints>> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A [ManageHook] recording the [SizeHints] of each new client in [TrackedSizeHints::global].
+pub struct TrackSizeHints;
+
+impl<X: PropConn> ManageHook<X> for TrackSizeHints {
+    fn call(&mut self, client: Xid, _: &mut State<X>, x: &X) -> Result<()> {
+        match x.cached_size_hints(client) {
+            Ok(hints) => {
+                TrackedSizeHints::global().insert(client, hints);
+            }
+            Err(e) => tracing::warn!(%e, %client, "unable to read size hints"),
+        }
+
+        Ok(())
+    }
+}
+
+/// An event hook keeping [TrackedSizeHints::global] up to date as clients change their hints
+/// or are destroyed.
+pub fn track_size_hints(event: &XEvent, state: &mut State<RustConn>, x: &RustConn) -> Result<bool> {
+    let tracked = TrackedSizeHints::global();
+
+    match event {
+        XEvent::PropertyNotify(PropertyEvent {
+            id,
+            atom,
+            is_root: false,
+        }) if atom == Atom::WmNormalHints.as_ref() && state.client_set.contains(id) => {
+            match tracked.update(*id, x) {
+                Ok(true) => x.refresh(state)?,
+                Ok(false) => (),
+                Err(e) => tracing::warn!(%e, %id, "unable to read size hints"),
+            }
+        }
+
+        XEvent::Destroy(id) => {
+            tracked.insert(*id, None);
+        }
+
+        _ => (),
+    }
+
+    Ok(true)
+}
+
+/// An [XConn] that can fetch the full [SizeHints] of a client.
+pub trait SizeHintsConn: XConn {
+    /// Fetch and decode the `WM_NORMAL_HINTS` property of a client in a single request,
+    /// returning `None` if it is not set.
+    fn size_hints(&self, id: Xid) -> Result<Option<SizeHints>>;
+}
+
+impl SizeHintsConn for RustConn {
+    fn size_hints(&self, id: Xid) -> Result<Option<SizeHints>> {
+        let atom = Atom::WmNormalHints.as_ref();
+        let atom_xid = self.intern_atom(atom)?;
+
+        let r = self
+            .connection()
+            .get_property(false, *id, atom_xid, AtomEnum::WM_SIZE_HINTS, 0, 1024)?
+            .reply()?;
+
+        if r.type_ == 0 {
+            return Ok(None);
+        }
+
+        let raw: Vec<u32> = r
+            .value32()
+            .ok_or_else(|| Error::InvalidPropertyData {
+                id,
+                prop: atom.to_owned(),
+                ty: "WM_SIZE_HINTS".to_owned(),
+            })?
+            .collect();
+
+        SizeHints::try_from_raw(&raw).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U_POSITION: u32 = 1;
+    const U_SIZE: u32 = 1 << 1;
+    const P_SIZE: u32 = 1 << 3;
+    const P_MIN_SIZE: u32 = 1 << 4;
+    const P_MAX_SIZE: u32 = 1 << 5;
+    const P_RESIZE_INC: u32 = 1 << 6;
+    const P_ASPECT: u32 = 1 << 7;
+    const P_BASE_SIZE: u32 = 1 << 8;
+    const P_WIN_GRAVITY: u32 = 1 << 9;
+
+    // Roughly what kitty sets: 2px of padding around 9x18 character cells
+    const TERMINAL: [u32; 18] = [
+        P_MIN_SIZE | P_RESIZE_INC | P_BASE_SIZE | P_WIN_GRAVITY,
+        0,
+        0,
+        0,
+        0,
+        22,
+        40,
+        0,
+        0,
+        9,
+        18,
+        0,
+        0,
+        0,
+        0,
+        4,
+        4,
+        1,
+    ];
+
+    #[test]
+    fn every_field_is_decoded() {
+        let raw = [
+            U_POSITION
+                | U_SIZE
+                | P_MIN_SIZE
+                | P_MAX_SIZE
+                | P_RESIZE_INC
+                | P_ASPECT
+                | P_BASE_SIZE
+                | P_WIN_GRAVITY,
+            (-20i32) as u32,
+            30,
+            640,
+            480,
+            100,
+            50,
+            1920,
+            1080,
+            8,
+            16,
+            4,
+            3,
+            16,
+            9,
+            10,
+            20,
+            10,
+        ];
+
+        let hints = SizeHints::try_from_raw(&raw).unwrap();
+
+        assert_eq!(hints.position, Some(Point::new(-20, 30)));
+        assert_eq!(hints.size, Some(Size::new(640, 480)));
+        assert_eq!(hints.min, Some(Size::new(100, 50)));
+        assert_eq!(hints.max, Some(Size::new(1920, 1080)));
+        assert_eq!(hints.increment, Some(Size::new(8, 16)));
+        assert_eq!(hints.aspect, Some((Aspect::new(4, 3), Aspect::new(16, 9))));
+        assert_eq!(hints.base, Some(Size::new(10, 20)));
+        assert_eq!(hints.gravity(), Gravity::STATIC);
+        assert!(hints.user_position() && hints.user_size());
+    }
+
+    #[test]
+    fn fields_without_their_flag_are_ignored() {
+        let hints = SizeHints::try_from_raw(&TERMINAL).unwrap();
+
+        assert_eq!(hints.min, Some(Size::new(22, 40)));
+        assert_eq!(hints.increment, Some(Size::new(9, 18)));
+        assert_eq!(hints.base, Some(Size::new(4, 4)));
+        assert_eq!(hints.gravity(), Gravity::NORTH_WEST);
+        assert_eq!((hints.position, hints.size, hints.max), (None, None, None));
+        assert_eq!(hints.aspect, None);
+        assert!(!hints.is_fixed());
+
+        let mut raw = TERMINAL;
+        raw[0] = 0;
+        let hints = SizeHints::try_from_raw(&raw).unwrap();
+        assert_eq!((hints.min, hints.increment, hints.base), (None, None, None));
+        assert_eq!(hints.gravity, None);
+    }
+
+    #[test]
+    fn unusable_values_are_treated_as_unset() {
+        let mut raw = [0; 18];
+        raw[0] = P_SIZE | P_MAX_SIZE | P_ASPECT;
+        raw[3] = 800;
+        raw[7] = 1024;
+        raw[11] = 1;
+
+        let hints = SizeHints::try_from_raw(&raw).unwrap();
+
+        assert_eq!((hints.size, hints.max, hints.aspect), (None, None, None));
+        assert!(!hints.user_size());
+    }
+
+    #[test]
+    fn short_properties_are_handled() {
+        let hints = SizeHints::try_from_raw(&TERMINAL[..15]).unwrap();
+        assert_eq!(hints.min, Some(Size::new(22, 40)));
+        assert_eq!((hints.base, hints.gravity), (None, None));
+
+        assert!(SizeHints::try_from_raw(&TERMINAL[..4]).is_err());
+    }
+
+    #[test]
+    fn fixed_size_clients_are_clamped_to_their_size() {
+        let mut raw = [0; 18];
+        raw[0] = P_MIN_SIZE | P_MAX_SIZE;
+        raw[5..9].copy_from_slice(&[300, 200, 300, 200]);
+
+        let hints = SizeHints::try_from_raw(&raw).unwrap();
+
+        assert!(hints.is_fixed());
+        assert_eq!(
+            hints.constrain(Rect::new(5, 10, 1000, 50)),
+            Rect::new(5, 10, 300, 200)
+        );
+    }
+
+    #[test]
+    fn sizes_are_rounded_down_to_whole_increments() {
+        let hints = SizeHints::try_from_raw(&TERMINAL).unwrap();
+
+        // 4px of base plus 9x18 cells: 100 = 4 + 10*9 + 6 and 200 = 4 + 10*18 + 16
+        let r = hints.round_to_increments(Rect::new(0, 0, 100, 200));
+        assert_eq!(r, Rect::new(0, 0, 94, 184));
+
+        // Never smaller than the min size
+        let r = hints.round_to_increments(Rect::new(0, 0, 10, 10));
+        assert_eq!(r, Rect::new(0, 0, 22, 40));
+    }
+
+    #[test]
+    fn sizes_are_adjusted_to_the_aspect_ratio() {
+        let mut raw = [0; 18];
+        raw[0] = P_ASPECT;
+        raw[11..15].copy_from_slice(&[16, 9, 16, 9]);
+        let hints = SizeHints::try_from_raw(&raw).unwrap();
+
+        // Too wide: the width is reduced
+        assert_eq!(
+            hints.constrain(Rect::new(0, 0, 1920, 900)),
+            Rect::new(0, 0, 1600, 900)
+        );
+        // Too tall: the height is reduced
+        assert_eq!(
+            hints.constrain(Rect::new(0, 0, 1280, 1024)),
+            Rect::new(0, 0, 1280, 720)
+        );
+        // The aspect ratio is ignored for tiled clients
+        assert_eq!(
+            hints.round_to_increments(Rect::new(0, 0, 1280, 1024)),
+            Rect::new(0, 0, 1280, 1024)
+        );
+    }
+}
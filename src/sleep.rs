@@ -0,0 +1,202 @@
+//! Locking the screen before the machine goes to sleep, using a logind `delay` inhibitor that
+//! is only released once the lock window has been mapped.
+use std::{
+    io,
+    os::fd::OwnedFd,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use penrose::{core::State, util, x11rb::RustConn};
+use zbus::{blocking::Connection, proxy::CacheProperties};
+
+use crate::{
+    config::LockSettings,
+    lock::{lock_screen, LockWatcher},
+    remote::Remote,
+};
+
+pub const LOGIND_NAME: &str = "org.freedesktop.login1";
+pub const LOGIND_PATH: &str = "/org/freedesktop/login1";
+pub const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// How long to wait for the lock window before letting the machine sleep anyway. logind
+/// gives up on delay inhibitors after 5 seconds by default.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(4);
+
+// Whether any SleepInhibitor is currently holding up sleep
+static HELD: AtomicBool = AtomicBool::new(false);
+
+/// Whether the screen will be locked by the sleep inhibitor before the machine goes to sleep.
+pub fn inhibitor_held() -> bool {
+    HELD.load(Ordering::Relaxed)
+}
+
+/// The ways that [suspend] can put the machine to sleep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    Suspend,
+    SuspendThenHibernate,
+}
+
+impl SleepMode {
+    fn command(self) -> &'static str {
+        match self {
+            Self::Suspend => "systemctl suspend",
+            Self::SuspendThenHibernate => "systemctl suspend-then-hibernate",
+        }
+    }
+}
+
+/// Put the machine to sleep, locking the screen first unless the sleep inhibitor is going to.
+pub fn suspend(state: &mut State<RustConn>, mode: SleepMode) -> penrose::Result<()> {
+    if !inhibitor_held() {
+        lock_screen(state)?;
+    }
+
+    util::spawn(mode.command())
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1",
+    gen_async = false
+)]
+trait Manager {
+    fn inhibit(
+        &self,
+        what: &str,
+        who: &str,
+        why: &str,
+        mode: &str,
+    ) -> zbus::Result<zbus::zvariant::OwnedFd>;
+
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+/// A logind `delay` inhibitor for sleep, along with the bus connection it was taken on.
+pub struct SleepInhibitor {
+    manager: ManagerProxy<'static>,
+    signals: PrepareForSleepIterator,
+    fd: Option<OwnedFd>,
+}
+
+impl SleepInhibitor {
+    /// Listen for `PrepareForSleep` on `bus` and take the inhibitor.
+    pub fn new(bus: &Connection) -> zbus::Result<Self> {
+        let manager = ManagerProxy::builder(bus)
+            .cache_properties(CacheProperties::No)
+            .build()?;
+        let signals = manager.receive_prepare_for_sleep()?;
+
+        let mut inhibitor = Self {
+            manager,
+            signals,
+            fd: None,
+        };
+        inhibitor.inhibit()?;
+
+        Ok(inhibitor)
+    }
+
+    /// Whether we are currently holding up sleep.
+    pub fn is_held(&self) -> bool {
+        self.fd.is_some()
+    }
+
+    fn inhibit(&mut self) -> zbus::Result<()> {
+        let fd = self
+            .manager
+            .inhibit("sleep", "favilo-penrose", "Lock the screen", "delay")?;
+        tracing::debug!("took the sleep inhibitor");
+        self.fd = Some(fd.into());
+        HELD.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        if self.fd.take().is_some() {
+            HELD.store(false, Ordering::Relaxed);
+            tracing::debug!("released the sleep inhibitor");
+        }
+    }
+
+    /// Wait for logind to announce sleeping and waking, calling `lock` before each sleep.
+    ///
+    /// `lock` should only return once the screen is locked: the inhibitor is released as soon
+    /// as it does.
+    pub fn run(mut self, mut lock: impl FnMut()) -> zbus::Result<()> {
+        while let Some(signal) = self.signals.next() {
+            let start = match signal.args() {
+                Ok(args) => args.start,
+                Err(e) => {
+                    tracing::warn!(%e, "ignoring malformed PrepareForSleep signal");
+                    continue;
+                }
+            };
+
+            if start {
+                tracing::info!("preparing for sleep");
+                lock();
+                self.release();
+            } else if !self.is_held() {
+                tracing::info!("woken up from sleep");
+                if let Err(e) = self.inhibit() {
+                    tracing::error!(%e, "unable to take the sleep inhibitor");
+                }
+            }
+        }
+
+        Err(zbus::Error::Failure(
+            "PrepareForSleep signals stopped".to_string(),
+        ))
+    }
+}
+
+impl Drop for SleepInhibitor {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Spawn a background thread that locks the screen every time the machine goes to sleep.
+///
+/// Not being able to reach logind on the system bus is logged rather than returned so that
+/// the window manager still runs without it.
+pub fn lock_before_sleep(settings: &LockSettings, remote: Remote) -> io::Result<()> {
+    let settings = settings.clone();
+
+    thread::Builder::new()
+        .name("sleep-inhibitor".to_string())
+        .spawn(move || {
+            let inhibitor = match Connection::system().and_then(|bus| SleepInhibitor::new(&bus)) {
+                Ok(inhibitor) => inhibitor,
+                Err(e) => {
+                    tracing::warn!(%e, "unable to inhibit sleep: the screen will not be locked");
+                    return;
+                }
+            };
+            let watcher = match LockWatcher::try_new(None, &settings) {
+                Ok(watcher) => watcher,
+                Err(e) => {
+                    tracing::error!(%e, "unable to connect to the X server");
+                    return;
+                }
+            };
+
+            let res = inhibitor.run(|| match watcher.lock(&remote, LOCK_TIMEOUT) {
+                Ok(true) => (),
+                Ok(false) => tracing::warn!("sleeping without the lock window mapped"),
+                Err(e) => tracing::error!(%e, "unable to lock the screen before sleeping"),
+            });
+            if let Err(e) = res {
+                tracing::error!(%e, "lost the connection to the system bus");
+            }
+        })?;
+
+    Ok(())
+}
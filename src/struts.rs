@@ -0,0 +1,401 @@
+//! Keeping tiled clients clear of the space reserved by docks and panels through
+//! `_NET_WM_STRUT_PARTIAL` and `_NET_WM_STRUT`.
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock, RwLockReadGuard},
+};
+
+use penrose::{
+    core::{hooks::ManageHook, layout::Layout, layout::LayoutTransformer, State},
+    pure::geometry::Rect,
+    x::{event::PropertyEvent, ClientAttr, Prop, XConnExt, XEvent},
+    Result, Xid,
+};
+
+use crate::props::PropConn;
+
+const NET_WM_STRUT: &str = "_NET_WM_STRUT";
+const NET_WM_STRUT_PARTIAL: &str = "_NET_WM_STRUT_PARTIAL";
+
+/// The space a window reserves along each edge of the root window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Strut {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+    /// The first and last row covered by the left strut
+    pub left_y: (u32, u32),
+    /// The first and last row covered by the right strut
+    pub right_y: (u32, u32),
+    /// The first and last column covered by the top strut
+    pub top_x: (u32, u32),
+    /// The first and last column covered by the bottom strut
+    pub bottom_x: (u32, u32),
+}
+
+impl Strut {
+    /// Decode `_NET_WM_STRUT_PARTIAL`, or `_NET_WM_STRUT` which covers the whole of each edge.
+    pub fn try_from_raw(raw: &[u32]) -> Option<Self> {
+        const ALL: (u32, u32) = (0, u32::MAX);
+
+        match *raw {
+            [left, right, top, bottom, ly0, ly1, ry0, ry1, tx0, tx1, bx0, bx1, ..] => Some(Self {
+                left,
+                right,
+                top,
+                bottom,
+                left_y: (ly0, ly1),
+                right_y: (ry0, ry1),
+                top_x: (tx0, tx1),
+                bottom_x: (bx0, bx1),
+            }),
+            [left, right, top, bottom] => Some(Self {
+                left,
+                right,
+                top,
+                bottom,
+                left_y: ALL,
+                right_y: ALL,
+                top_x: ALL,
+                bottom_x: ALL,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The space this strut takes up on a screen within the root window.
+    pub fn insets(&self, screen: Rect, root: Rect) -> Insets {
+        let overlaps = |(first, last): (u32, u32), start: i32, len: u32| {
+            let (first, last) = (first as i64, last as i64);
+            let (start, end) = (start as i64, start as i64 + len as i64);
+            first < end && last >= start
+        };
+        // How far past `edge` (towards the centre of the screen) `reserved_to` reaches
+        let depth = |reserved_to: i64, edge: i64, len: u32| {
+            (reserved_to - edge).clamp(0, len as i64) as u32
+        };
+
+        let (sx, sy) = (screen.x as i64, screen.y as i64);
+        let (rx, ry) = (root.x as i64, root.y as i64);
+        let mut insets = Insets::default();
+
+        if self.top > 0 && overlaps(self.top_x, screen.x, screen.w) {
+            insets.top = depth(ry + self.top as i64, sy, screen.h);
+        }
+        if self.bottom > 0 && overlaps(self.bottom_x, screen.x, screen.w) {
+            let edge = ry + root.h as i64 - self.bottom as i64;
+            insets.bottom = depth(sy + screen.h as i64, edge, screen.h);
+        }
+        if self.left > 0 && overlaps(self.left_y, screen.y, screen.h) {
+            insets.left = depth(rx + self.left as i64, sx, screen.w);
+        }
+        if self.right > 0 && overlaps(self.right_y, screen.y, screen.h) {
+            let edge = rx + root.w as i64 - self.right as i64;
+            insets.right = depth(sx + screen.w as i64, edge, screen.w);
+        }
+
+        insets
+    }
+}
+
+/// The space reserved along each edge of a screen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Insets {
+    pub top: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub right: u32,
+}
+
+impl Insets {
+    /// The larger of each edge of two insets.
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            top: self.top.max(other.top),
+            bottom: self.bottom.max(other.bottom),
+            left: self.left.max(other.left),
+            right: self.right.max(other.right),
+        }
+    }
+
+    /// What is left of `r` once the insets have been removed.
+    pub fn apply(&self, r: Rect) -> Rect {
+        Rect::new(
+            r.x + self.left as i32,
+            r.y + self.top as i32,
+            r.w.saturating_sub(self.left + self.right),
+            r.h.saturating_sub(self.top + self.bottom),
+        )
+    }
+}
+
+/// The struts of every dock window, along with the size of the root window that they are
+/// relative to.
+#[derive(Debug, Default)]
+pub struct Struts {
+    struts: RwLock<HashMap<Xid, Strut>>,
+    root: RwLock<Rect>,
+}
+
+impl Struts {
+    /// The struts shared between the layouts and the hooks keeping them up to date.
+    pub fn global() -> Arc<Self> {
+        static STRUTS: OnceLock<Arc<Struts>> = OnceLock::new();
+
+        Arc::clone(STRUTS.get_or_init(Default::default))
+    }
+
+    pub fn set_root(&self, r: Rect) {
+        *self.root.write().unwrap_or_else(|e| e.into_inner()) = r;
+    }
+
+    /// Set or clear the strut of a window, returning whether it has changed.
+    pub fn insert(&self, id: Xid, strut: Option<Strut>) -> bool {
+        let mut map = self.struts.write().unwrap_or_else(|e| e.into_inner());
+
+        match strut {
+            Some(strut) => map.insert(id, strut) != Some(strut),
+            None => map.remove(&id).is_some(),
+        }
+    }
+
+    /// The space reserved on a screen by all of the struts.
+    pub fn insets(&self, screen: Rect) -> Insets {
+        let root = *self.root.read().unwrap_or_else(|e| e.into_inner());
+
+        self.read()
+            .values()
+            .map(|s| s.insets(screen, root))
+            .fold(Insets::default(), Insets::max)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<Xid, Strut>> {
+        self.struts.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The strut of a window, if it has one.
+pub fn strut<X: PropConn>(id: Xid, x: &X) -> Result<Option<Strut>> {
+    for prop in [NET_WM_STRUT_PARTIAL, NET_WM_STRUT] {
+        if let Some(Prop::Cardinal(raw)) = x.cached_prop(id, prop)? {
+            return Ok(Strut::try_from_raw(&raw));
+        }
+    }
+
+    Ok(None)
+}
+
+// Start tracking the strut of a window that we aren't managing, including one that it only sets
+// after being mapped.
+fn watch<X: PropConn>(id: Xid, strut: Option<Strut>, struts: &Struts, x: &X) -> Result<()> {
+    // So that we hear about the strut changing and the window going away
+    x.set_client_attributes(id, &[ClientAttr::ClientEventMask])?;
+    struts.insert(id, strut);
+
+    Ok(())
+}
+
+/// Reserve the space taken up by docks on each screen.
+#[derive(Debug, Clone)]
+pub struct ReserveStruts {
+    pub layout: Box<dyn Layout>,
+    struts: Arc<Struts>,
+}
+
+impl ReserveStruts {
+    /// Wrap an existing [Layout], keeping clear of the struts in `struts`.
+    pub fn wrap(layout: Box<dyn Layout>, struts: Arc<Struts>) -> Box<dyn Layout> {
+        Box::new(Self { layout, struts })
+    }
+}
+
+impl LayoutTransformer for ReserveStruts {
+    fn transformed_name(&self) -> String {
+        self.layout.name()
+    }
+
+    fn inner_mut(&mut self) -> &mut Box<dyn Layout> {
+        &mut self.layout
+    }
+
+    fn transform_initial(&self, r: Rect) -> Rect {
+        if r.w == 0 || r.h == 0 {
+            return r;
+        }
+
+        self.struts.insets(r).apply(r)
+    }
+}
+
+/// A [ManageHook] for dock windows: they are left unmanaged and shown where they asked to be,
+/// with their strut kept clear of tiled clients.
+pub struct ManageDock;
+
+impl<X: PropConn> ManageHook<X> for ManageDock {
+    fn call(&mut self, client: Xid, state: &mut State<X>, x: &X) -> Result<()> {
+        state.client_set.remove_client(&client);
+        watch(client, strut(client, x)?, &Struts::global(), x)?;
+
+        x.map(client)
+    }
+}
+
+/// A startup hook picking up the struts of docks that were running before the window manager
+/// started, including those that never ask to be managed (override redirect windows).
+pub fn track_existing_struts<X: PropConn>(state: &mut State<X>, x: &X) -> Result<()> {
+    let struts = Struts::global();
+    struts.set_root(x.client_geometry(x.root())?);
+
+    for id in x.existing_clients()? {
+        let res = strut(id, x).and_then(|s| match s {
+            Some(s) => watch(id, Some(s), &struts, x),
+            None => Ok(()),
+        });
+        if let Err(e) = res {
+            tracing::warn!(%e, %id, "unable to read strut");
+        }
+    }
+
+    x.refresh(state)
+}
+
+/// An event hook re-laying out the screens when a dock changes its strut, goes away or the
+/// monitor layout changes.
+pub fn track_struts<X: PropConn>(event: &XEvent, state: &mut State<X>, x: &X) -> Result<bool> {
+    if update_struts(event, &Struts::global(), x)? {
+        tracing::debug!(?event, "struts changed");
+        x.refresh(state)?;
+    }
+
+    Ok(true)
+}
+
+// Apply an event to `struts`, returning whether the space reserved by any of them has changed.
+fn update_struts<X: PropConn>(event: &XEvent, struts: &Struts, x: &X) -> Result<bool> {
+    let changed = match event {
+        XEvent::PropertyNotify(PropertyEvent {
+            id,
+            atom,
+            is_root: false,
+        }) if atom == NET_WM_STRUT_PARTIAL || atom == NET_WM_STRUT => {
+            struts.insert(*id, strut(*id, x)?)
+        }
+
+        XEvent::Destroy(id) | XEvent::UnmapNotify(id) => struts.insert(*id, None),
+
+        // Penrose re-reads the screen sizes and refreshes once we are done
+        XEvent::RandrNotify => {
+            struts.set_root(x.client_geometry(x.root())?);
+            false
+        }
+
+        _ => false,
+    };
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockConn;
+
+    // A 1920x1080 monitor to the left of a taller 2560x1440 one
+    const LEFT: Rect = Rect::new(0, 0, 1920, 1080);
+    const RIGHT: Rect = Rect::new(1920, 0, 2560, 1440);
+    const ROOT: Rect = Rect::new(0, 0, 4480, 1440);
+
+    fn partial(raw: [u32; 12]) -> Strut {
+        Strut::try_from_raw(&raw).unwrap()
+    }
+
+    #[test]
+    fn struts_only_reserve_space_on_the_screens_they_cover() {
+        let struts = Struts::default();
+        struts.set_root(ROOT);
+
+        // A 30px bar along the top of the right hand monitor
+        struts.insert(
+            Xid::from(1),
+            Some(partial([0, 0, 30, 0, 0, 0, 0, 0, 1920, 4479, 0, 0])),
+        );
+        assert_eq!(struts.insets(LEFT), Insets::default());
+        assert_eq!(struts.insets(RIGHT).top, 30);
+
+        // A 24px tray along the bottom of the shorter left hand monitor has to reach past the
+        // bottom of the right hand one to get there
+        let bottom = 1440 - 1080 + 24;
+        let tray = partial([0, 0, 0, bottom, 0, 0, 0, 0, 0, 0, 0, 1919]);
+        struts.insert(Xid::from(2), Some(tray));
+        assert_eq!(struts.insets(LEFT).bottom, 24);
+        assert_eq!(struts.insets(RIGHT).bottom, 0);
+
+        // A panel down the right edge of everything along with a wider bar on the right monitor
+        struts.insert(Xid::from(3), Strut::try_from_raw(&[0, 48, 0, 0]));
+        struts.insert(
+            Xid::from(4),
+            Some(partial([0, 0, 40, 0, 0, 0, 0, 0, 2000, 2500, 0, 0])),
+        );
+        let expected = Insets {
+            top: 40,
+            bottom: 0,
+            left: 0,
+            right: 48,
+        };
+        assert_eq!(struts.insets(RIGHT), expected);
+        assert_eq!(expected.apply(RIGHT), Rect::new(1920, 40, 2512, 1400));
+        assert_eq!(struts.insets(LEFT).right, 0);
+
+        // Docks going away give the space back
+        assert!(struts.insert(Xid::from(4), None));
+        assert!(!struts.insert(Xid::from(4), None));
+        assert_eq!(struts.insets(RIGHT).top, 30);
+    }
+
+    #[test]
+    fn struts_set_after_a_dock_is_mapped_are_tracked() {
+        let struts = Struts::default();
+        struts.set_root(ROOT);
+        let dock = Xid::from(1);
+
+        let x = MockConn::default();
+        watch(dock, strut(dock, &x).unwrap(), &struts, &x).unwrap();
+        assert_eq!(x.client_attributes(dock), [ClientAttr::ClientEventMask]);
+        assert_eq!(struts.insets(LEFT), Insets::default());
+
+        let x = MockConn::default().with_prop(1, NET_WM_STRUT, Prop::Cardinal(vec![0, 0, 28, 0]));
+        let event = XEvent::PropertyNotify(PropertyEvent {
+            id: dock,
+            atom: NET_WM_STRUT.to_string(),
+            is_root: false,
+        });
+        assert!(update_struts(&event, &struts, &x).unwrap());
+        assert_eq!(struts.insets(LEFT).top, 28);
+
+        assert!(update_struts(&XEvent::Destroy(dock), &struts, &x).unwrap());
+        assert_eq!(struts.insets(LEFT), Insets::default());
+    }
+
+    #[test]
+    fn partial_struts_are_preferred() {
+        let x = MockConn::default()
+            .with_prop(1, NET_WM_STRUT, Prop::Cardinal(vec![0, 0, 28, 0]))
+            .with_prop(2, NET_WM_STRUT, Prop::Cardinal(vec![0, 0, 28, 0]))
+            .with_prop(
+                2,
+                NET_WM_STRUT_PARTIAL,
+                Prop::Cardinal(vec![0, 0, 28, 0, 0, 0, 0, 0, 0, 1919, 0, 0]),
+            );
+
+        let legacy = strut(Xid::from(1), &x).unwrap().unwrap();
+        assert_eq!(legacy.top_x, (0, u32::MAX));
+        assert_eq!(legacy.insets(RIGHT, ROOT).top, 28);
+
+        let partial = strut(Xid::from(2), &x).unwrap().unwrap();
+        assert_eq!(partial.top_x, (0, 1919));
+        assert_eq!(partial.insets(RIGHT, ROOT).top, 0);
+
+        assert_eq!(strut(Xid::from(3), &x).unwrap(), None);
+    }
+}
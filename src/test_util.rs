@@ -0,0 +1,20 @@
+//! Helpers shared between the unit tests.
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Poll `f` until it holds, giving up after [TIMEOUT].
+pub fn wait_until(mut f: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    false
+}
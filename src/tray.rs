@@ -0,0 +1,693 @@
+//! A freedesktop system tray that embeds icons using XEmbed on a thread of its own, and the
+//! status bar widget that shows it.
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use penrose::{
+    core::State,
+    pure::geometry::{Point, Rect},
+    x::{XConn, XEvent},
+    Color, Result, Xid,
+};
+use penrose_ui::{
+    bar::{schedule::UpdateSchedule, widgets::Widget},
+    Context,
+};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConfigureWindowAux,
+            ConnectionExt, CreateWindowAux, EventMask, PropMode, SetMode, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    CURRENT_TIME, NONE,
+};
+
+/// The client message type sent to the root window to get the bar redrawn when the icons in the
+/// tray change.
+pub const TRAY_CHANGED_ATOM: &str = "_FAVILO_PENROSE_TRAY_CHANGED";
+
+/// The space left around each icon.
+pub const ICON_PADDING: u32 = 2;
+
+// _NET_SYSTEM_TRAY_OPCODE messages
+const SYSTEM_TRAY_REQUEST_DOCK: u32 = 0;
+
+// _XEMBED messages and flags
+const XEMBED_EMBEDDED_NOTIFY: u32 = 0;
+const XEMBED_MAPPED: u32 = 1 << 0;
+const XEMBED_VERSION: u32 = 0;
+
+const ORIENTATION_HORIZONTAL: u32 = 0;
+
+/// The icons docked in a tray, in the order that they docked.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Icons {
+    icons: Vec<Icon>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Icon {
+    id: Window,
+    mapped: bool,
+}
+
+impl Icons {
+    /// Add a newly docked icon to the end of the tray, returning false if it was already docked.
+    pub fn dock(&mut self, id: Window, mapped: bool) -> bool {
+        if self.contains(id) {
+            return false;
+        }
+        self.icons.push(Icon { id, mapped });
+
+        true
+    }
+
+    /// Remove an icon, returning false if it was not docked.
+    pub fn remove(&mut self, id: Window) -> bool {
+        let n = self.icons.len();
+        self.icons.retain(|icon| icon.id != id);
+
+        self.icons.len() != n
+    }
+
+    /// Show or hide an icon as requested by the `XEMBED_MAPPED` flag of its `_XEMBED_INFO`,
+    /// returning false if nothing changed.
+    pub fn set_mapped(&mut self, id: Window, mapped: bool) -> bool {
+        match self.icons.iter_mut().find(|icon| icon.id == id) {
+            Some(icon) if icon.mapped != mapped => {
+                icon.mapped = mapped;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn contains(&self, id: Window) -> bool {
+        self.icons.iter().any(|icon| icon.id == id)
+    }
+
+    /// All docked icons, shown or not.
+    pub fn ids(&self) -> impl Iterator<Item = Window> + '_ {
+        self.icons.iter().map(|icon| icon.id)
+    }
+
+    /// The width of a tray `h` pixels high showing these icons.
+    pub fn width(&self, h: u32) -> u32 {
+        let n = self.icons.iter().filter(|icon| icon.mapped).count() as u32;
+        if n == 0 {
+            return 0;
+        }
+
+        n * icon_size(h) + (n + 1) * ICON_PADDING
+    }
+
+    /// Where each icon should be placed within a tray `h` pixels high, with `None` for icons that
+    /// are hidden.
+    pub fn layout(&self, h: u32) -> Vec<(Window, Option<Rect>)> {
+        let size = icon_size(h);
+        let mut x = ICON_PADDING;
+
+        self.icons
+            .iter()
+            .map(|icon| {
+                if !icon.mapped {
+                    return (icon.id, None);
+                }
+                let r = Rect::new(x as i32, ICON_PADDING as i32, size, size);
+                x += size + ICON_PADDING;
+
+                (icon.id, Some(r))
+            })
+            .collect()
+    }
+}
+
+fn icon_size(h: u32) -> u32 {
+    h.saturating_sub(2 * ICON_PADDING).max(1)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Atoms {
+    selection: u32,
+    opcode: u32,
+    orientation: u32,
+    manager: u32,
+    xembed: u32,
+    xembed_info: u32,
+    window_type: u32,
+    window_type_dock: u32,
+    changed: u32,
+}
+
+impl Atoms {
+    fn new(conn: &RustConnection, screen_num: usize) -> Result<Self> {
+        let selection = format!("_NET_SYSTEM_TRAY_S{screen_num}");
+        let names = [
+            selection.as_str(),
+            "_NET_SYSTEM_TRAY_OPCODE",
+            "_NET_SYSTEM_TRAY_ORIENTATION",
+            "MANAGER",
+            "_XEMBED",
+            "_XEMBED_INFO",
+            "_NET_WM_WINDOW_TYPE",
+            "_NET_WM_WINDOW_TYPE_DOCK",
+            TRAY_CHANGED_ATOM,
+        ];
+
+        let cookies = names
+            .iter()
+            .map(|name| Ok(conn.intern_atom(false, name.as_bytes())?))
+            .collect::<Result<Vec<_>>>()?;
+        let atoms = cookies
+            .into_iter()
+            .map(|c| Ok(c.reply()?.atom))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            selection: atoms[0],
+            opcode: atoms[1],
+            orientation: atoms[2],
+            manager: atoms[3],
+            xembed: atoms[4],
+            xembed_info: atoms[5],
+            window_type: atoms[6],
+            window_type_dock: atoms[7],
+            changed: atoms[8],
+        })
+    }
+}
+
+/// The manager of the system tray for an X screen, embedding the icons that dock into it on a
+/// background thread.
+///
+/// Any tray that already owns the selection (e.g. `stalonetray`) is replaced, with its icons
+/// docking again into this one.
+#[derive(Debug, Clone)]
+pub struct SystemTray {
+    conn: Arc<RustConnection>,
+    window: Window,
+    width: Arc<AtomicU32>,
+}
+
+impl SystemTray {
+    /// Take ownership of the system tray on `display` (or `$DISPLAY`) and start embedding icons
+    /// into a tray window `h` pixels high.
+    pub fn try_new(display: Option<&str>, h: u32, bg: Color) -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(display)?;
+        let conn = Arc::new(conn);
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let atoms = Atoms::new(&conn, screen_num)?;
+
+        let window = conn.generate_id()?;
+        let aux = CreateWindowAux::new()
+            .override_redirect(1)
+            .background_pixel(bg.rgb_u32())
+            .event_mask(EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY);
+        conn.create_window(
+            screen.root_depth,
+            window,
+            root,
+            0,
+            0,
+            1,
+            h as u16,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &aux,
+        )?;
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            atoms.window_type,
+            AtomEnum::ATOM,
+            &[atoms.window_type_dock],
+        )?;
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_CLASS,
+            AtomEnum::STRING,
+            b"penrose-systray\0penrose-systray\0",
+        )?;
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            atoms.orientation,
+            AtomEnum::CARDINAL,
+            &[ORIENTATION_HORIZONTAL],
+        )?;
+
+        let previous = conn.get_selection_owner(atoms.selection)?.reply()?.owner;
+        if previous != NONE {
+            tracing::warn!(%previous, "replacing the current system tray");
+        }
+        conn.set_selection_owner(window, atoms.selection, CURRENT_TIME)?;
+        if conn.get_selection_owner(atoms.selection)?.reply()?.owner != window {
+            conn.destroy_window(window)?;
+            conn.flush()?;
+            return Err(penrose::Error::Custom(
+                "unable to take ownership of the system tray".to_string(),
+            ));
+        }
+
+        // Let any icons that are already running know that they can dock
+        let event = ClientMessageEvent::new(
+            32,
+            root,
+            atoms.manager,
+            [CURRENT_TIME, atoms.selection, window, 0, 0],
+        );
+        conn.send_event(false, root, EventMask::STRUCTURE_NOTIFY, event)?;
+        conn.flush()?;
+
+        let width = Arc::new(AtomicU32::new(0));
+        let mut embedder = Embedder {
+            conn: conn.clone(),
+            root,
+            window,
+            h,
+            atoms,
+            icons: Icons::default(),
+            width: width.clone(),
+        };
+        thread::spawn(move || {
+            if let Err(e) = embedder.run() {
+                tracing::error!(%e, "system tray stopped");
+            }
+            embedder.release();
+        });
+
+        Ok(Self {
+            conn,
+            window,
+            width,
+        })
+    }
+
+    /// The window that icons are embedded into.
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// The current width of the tray: zero if there are no icons showing.
+    pub fn width(&self) -> u32 {
+        self.width.load(Ordering::Relaxed)
+    }
+
+    /// Move the tray window so that its top left corner is at `p`.
+    pub fn place(&self, p: Point) -> Result<()> {
+        let aux = ConfigureWindowAux::new().x(p.x).y(p.y);
+        self.conn.configure_window(self.window, &aux)?;
+        self.conn.flush()?;
+
+        Ok(())
+    }
+}
+
+// The state owned by the thread embedding icons.
+struct Embedder {
+    conn: Arc<RustConnection>,
+    root: Window,
+    window: Window,
+    h: u32,
+    atoms: Atoms,
+    icons: Icons,
+    width: Arc<AtomicU32>,
+}
+
+impl Embedder {
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let changed = match self.conn.wait_for_event()? {
+                Event::ClientMessage(e) if e.type_ == self.atoms.opcode => {
+                    let [_, opcode, icon, _, _] = e.data.as_data32();
+                    // Balloon messages are not supported
+                    opcode == SYSTEM_TRAY_REQUEST_DOCK && self.dock(icon)?
+                }
+
+                Event::DestroyNotify(e) => self.icons.remove(e.window),
+                Event::ReparentNotify(e) if e.parent != self.window => self.icons.remove(e.window),
+
+                Event::PropertyNotify(e) if e.atom == self.atoms.xembed_info => {
+                    let mapped = self.xembed_mapped(e.window)?;
+                    self.icons.set_mapped(e.window, mapped)
+                }
+
+                // Icons are only ever mapped, moved and resized by the tray itself
+                Event::MapRequest(e) if self.icons.contains(e.window) => {
+                    self.icons.set_mapped(e.window, true)
+                }
+                Event::ConfigureRequest(e) if self.icons.contains(e.window) => true,
+
+                Event::SelectionClear(e) if e.selection == self.atoms.selection => {
+                    tracing::warn!("another system tray has taken over");
+                    return Ok(());
+                }
+
+                Event::Error(e) => {
+                    // Most likely an icon that has gone away while being embedded
+                    tracing::debug!(?e, "X error in the system tray");
+                    false
+                }
+
+                _ => false,
+            };
+
+            if changed {
+                self.relayout()?;
+            }
+        }
+    }
+
+    // Returns false if the icon could not be embedded.
+    fn dock(&mut self, icon: Window) -> Result<bool> {
+        if self.icons.contains(icon) {
+            return Ok(false);
+        }
+
+        tracing::info!(%icon, "docking system tray icon");
+        let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        if let Err(e) = self.conn.change_window_attributes(icon, &aux)?.check() {
+            tracing::warn!(%icon, ?e, "unable to dock system tray icon");
+            return Ok(false);
+        }
+        let mapped = self.xembed_mapped(icon)?;
+
+        // The icons are put back on the root window if our connection is closed
+        self.conn.change_save_set(SetMode::INSERT, icon)?;
+        self.conn.reparent_window(icon, self.window, 0, 0)?;
+        let event = ClientMessageEvent::new(
+            32,
+            icon,
+            self.atoms.xembed,
+            [
+                CURRENT_TIME,
+                XEMBED_EMBEDDED_NOTIFY,
+                0,
+                self.window,
+                XEMBED_VERSION,
+            ],
+        );
+        self.conn
+            .send_event(false, icon, EventMask::NO_EVENT, event)?;
+
+        Ok(self.icons.dock(icon, mapped))
+    }
+
+    // Icons without an _XEMBED_INFO property are always shown.
+    fn xembed_mapped(&self, icon: Window) -> Result<bool> {
+        let info = self.atoms.xembed_info;
+        let r = match self
+            .conn
+            .get_property(false, icon, info, info, 0, 2)?
+            .reply()
+        {
+            Ok(r) => r,
+            Err(_) => return Ok(false),
+        };
+
+        Ok(match r.value32().and_then(|mut v| v.nth(1)) {
+            Some(flags) => flags & XEMBED_MAPPED != 0,
+            None => true,
+        })
+    }
+
+    fn relayout(&mut self) -> Result<()> {
+        for (icon, r) in self.icons.layout(self.h) {
+            match r {
+                Some(r) => {
+                    let aux = ConfigureWindowAux::new()
+                        .x(r.x)
+                        .y(r.y)
+                        .width(r.w)
+                        .height(r.h);
+                    self.conn.configure_window(icon, &aux)?;
+                    self.conn.map_window(icon)?;
+                }
+                None => {
+                    self.conn.unmap_window(icon)?;
+                }
+            }
+        }
+
+        let width = self.icons.width(self.h);
+        if width == 0 {
+            self.conn.unmap_window(self.window)?;
+        } else {
+            let aux = ConfigureWindowAux::new().width(width);
+            self.conn.configure_window(self.window, &aux)?;
+            self.conn.map_window(self.window)?;
+        }
+
+        if self.width.swap(width, Ordering::Relaxed) != width {
+            self.wake_window_manager()?;
+        }
+        self.conn.flush()?;
+
+        Ok(())
+    }
+
+    fn wake_window_manager(&self) -> Result<()> {
+        let event = ClientMessageEvent::new(32, self.root, self.atoms.changed, [0u32; 5]);
+        let mask = EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY;
+        self.conn.send_event(false, self.root, mask, event)?;
+
+        Ok(())
+    }
+
+    // Hand the icons back to the root window so that whichever tray replaces us can take them.
+    fn release(&mut self) {
+        let release = || -> Result<()> {
+            for icon in self.icons.ids() {
+                self.conn.unmap_window(icon)?;
+                self.conn.reparent_window(icon, self.root, 0, 0)?;
+            }
+            self.conn.destroy_window(self.window)?;
+            self.width.store(0, Ordering::Relaxed);
+            self.wake_window_manager()?;
+            self.conn.flush()?;
+
+            Ok(())
+        };
+
+        if let Err(e) = release() {
+            tracing::error!(%e, "unable to release system tray icons");
+        }
+        self.icons = Icons::default();
+    }
+}
+
+/// The widths that each of the widgets to the left of a [Tray] were last drawn at.
+///
+/// The bar does not tell its widgets where they are being drawn, so the widgets in front of the
+/// tray are wrapped with [Measured] to keep track of it.
+#[derive(Debug, Clone, Default)]
+pub struct Widths(Rc<RefCell<Vec<u32>>>);
+
+impl Widths {
+    fn offset(&self) -> u32 {
+        self.0.borrow().iter().sum()
+    }
+}
+
+/// A bar widget recording the width that it is drawn at in a shared [Widths].
+pub struct Measured<X: XConn> {
+    inner: Box<dyn Widget<X>>,
+    ix: usize,
+    widths: Widths,
+}
+
+impl<X: XConn> Measured<X> {
+    pub fn new(inner: Box<dyn Widget<X>>, ix: usize, widths: Widths) -> Self {
+        widths.0.borrow_mut().resize(ix + 1, 0);
+
+        Self { inner, ix, widths }
+    }
+}
+
+impl<X: XConn> Widget<X> for Measured<X> {
+    fn draw(
+        &mut self,
+        ctx: &mut Context<'_>,
+        screen: usize,
+        screen_has_focus: bool,
+        w: u32,
+        h: u32,
+    ) -> penrose_ui::Result<()> {
+        self.widths.0.borrow_mut()[self.ix] = w;
+        self.inner.draw(ctx, screen, screen_has_focus, w, h)
+    }
+
+    fn current_extent(&mut self, ctx: &mut Context<'_>, h: u32) -> penrose_ui::Result<(u32, u32)> {
+        self.inner.current_extent(ctx, h)
+    }
+
+    fn require_draw(&self) -> bool {
+        self.inner.require_draw()
+    }
+
+    fn is_greedy(&self) -> bool {
+        self.inner.is_greedy()
+    }
+
+    fn update_schedule(&mut self) -> Option<UpdateSchedule> {
+        self.inner.update_schedule()
+    }
+
+    fn on_startup(&mut self, state: &mut State<X>, x: &X) -> penrose_ui::Result<()> {
+        self.inner.on_startup(state, x)
+    }
+
+    fn on_refresh(&mut self, state: &mut State<X>, x: &X) -> penrose_ui::Result<()> {
+        self.inner.on_refresh(state, x)
+    }
+
+    fn on_event(&mut self, event: &XEvent, state: &mut State<X>, x: &X) -> penrose_ui::Result<()> {
+        self.inner.on_event(event, state, x)
+    }
+
+    fn on_new_client(&mut self, id: Xid, state: &mut State<X>, x: &X) -> penrose_ui::Result<()> {
+        self.inner.on_new_client(id, state, x)
+    }
+}
+
+/// The status bar widget showing a [SystemTray].
+///
+/// The widget takes up as much space as the icons in the tray need and keeps the tray window
+/// over it. The tray is only shown on one screen: if the same widgets are used for several
+/// screens it is drawn on the first of them.
+pub struct Tray {
+    tray: SystemTray,
+    screen: usize,
+    widths: Widths,
+    origin: Option<Point>,
+    placed: Option<Point>,
+    width: u32,
+}
+
+impl Tray {
+    /// A widget for `tray` in the bar on `screen`, after widgets measured in `widths`.
+    pub fn new(tray: SystemTray, screen: usize, widths: Widths) -> Self {
+        Self {
+            tray,
+            screen,
+            widths,
+            origin: None,
+            placed: None,
+            width: 0,
+        }
+    }
+
+    fn update_origin<X: XConn>(&mut self, state: &State<X>) {
+        self.origin = state
+            .client_set
+            .screens()
+            .find(|s| s.index() == self.screen)
+            .map(|s| s.geometry().into());
+    }
+}
+
+impl<X: XConn> Widget<X> for Tray {
+    fn draw(
+        &mut self,
+        _ctx: &mut Context<'_>,
+        _screen: usize,
+        _screen_has_focus: bool,
+        w: u32,
+        _h: u32,
+    ) -> penrose_ui::Result<()> {
+        self.width = w;
+        let origin = match self.origin {
+            Some(origin) if w > 0 => origin,
+            _ => return Ok(()),
+        };
+
+        let p = Point::new(origin.x + self.widths.offset() as i32, origin.y);
+        if self.placed != Some(p) {
+            self.tray.place(p)?;
+            self.placed = Some(p);
+        }
+
+        Ok(())
+    }
+
+    fn current_extent(&mut self, _ctx: &mut Context<'_>, h: u32) -> penrose_ui::Result<(u32, u32)> {
+        Ok((self.tray.width(), h))
+    }
+
+    fn require_draw(&self) -> bool {
+        self.tray.width() != self.width
+    }
+
+    fn is_greedy(&self) -> bool {
+        false
+    }
+
+    fn on_startup(&mut self, state: &mut State<X>, _: &X) -> penrose_ui::Result<()> {
+        self.update_origin(state);
+        Ok(())
+    }
+
+    fn on_refresh(&mut self, state: &mut State<X>, _: &X) -> penrose_ui::Result<()> {
+        let origin = self.origin;
+        self.update_origin(state);
+        if self.origin != origin {
+            self.placed = None;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icons_are_laid_out_in_the_order_they_docked() {
+        let mut icons = Icons::default();
+        assert_eq!(icons.width(28), 0);
+
+        assert!(icons.dock(10, true));
+        assert!(icons.dock(20, false));
+        assert!(icons.dock(30, true));
+        assert!(!icons.dock(10, true));
+
+        // Hidden icons take up no space
+        assert_eq!(icons.width(28), 2 * 24 + 3 * ICON_PADDING);
+        assert_eq!(
+            icons.layout(28),
+            vec![
+                (10, Some(Rect::new(2, 2, 24, 24))),
+                (20, None),
+                (30, Some(Rect::new(28, 2, 24, 24))),
+            ]
+        );
+
+        assert!(icons.set_mapped(20, true));
+        assert!(!icons.set_mapped(20, true));
+        assert!(icons.remove(10));
+        assert!(!icons.remove(10));
+        assert_eq!(
+            icons.layout(28),
+            vec![
+                (20, Some(Rect::new(2, 2, 24, 24))),
+                (30, Some(Rect::new(28, 2, 24, 24))),
+            ]
+        );
+    }
+}
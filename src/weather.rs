@@ -0,0 +1,300 @@
+//! Current weather conditions for the status bar, cached on disk so that there is something
+//! to show straight away after a restart.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use penrose::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{state_home, WeatherSettings, WeatherUnits};
+
+const CACHE_FILE_NAME: &str = "weather.json";
+
+/// The location of the cached weather reading: `$XDG_STATE_HOME/penrose/weather.json`.
+pub fn cache_path() -> PathBuf {
+    state_home().join(CACHE_FILE_NAME)
+}
+
+/// A successfully fetched weather report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reading {
+    pub text: String,
+    /// Seconds since the unix epoch.
+    pub fetched_at: u64,
+}
+
+/// Fetches weather readings and tracks whether the last one is still current.
+#[derive(Debug)]
+pub struct Weather {
+    settings: WeatherSettings,
+    cache_path: PathBuf,
+    agent: ureq::Agent,
+    last: Option<Reading>,
+    last_attempt: Option<Instant>,
+    failures: u32,
+}
+
+impl Weather {
+    /// Create a new [Weather], starting from the reading cached at `cache_path` if there is one.
+    pub fn new(settings: WeatherSettings, cache_path: impl Into<PathBuf>) -> Self {
+        let cache_path = cache_path.into();
+        let timeout = Duration::from_secs(settings.timeout_secs);
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(timeout)
+            .timeout(timeout)
+            .build();
+
+        Self {
+            last: load_cache(&cache_path),
+            settings,
+            cache_path,
+            agent,
+            last_attempt: None,
+            failures: 0,
+        }
+    }
+
+    /// The URL readings are fetched from.
+    pub fn url(&self) -> String {
+        let WeatherSettings {
+            endpoint,
+            location,
+            units,
+            format,
+            ..
+        } = &self.settings;
+
+        let mut url = format!(
+            "{}/{}?format={}",
+            endpoint.trim_end_matches('/'),
+            encode(&location.replace(' ', "+")),
+            encode(format)
+        );
+        match units {
+            WeatherUnits::Auto => (),
+            WeatherUnits::Metric => url.push_str("&m"),
+            WeatherUnits::Imperial => url.push_str("&u"),
+        }
+
+        url
+    }
+
+    /// Make a single request for the current weather.
+    pub fn fetch(&self) -> Result<String> {
+        let url = self.url();
+        let body = self
+            .agent
+            .get(&url)
+            .call()
+            .map_err(|e| Error::Custom(format!("weather request to {url} failed: {e}")))?
+            .into_string()?;
+
+        let text = body.trim();
+        if text.is_empty() || text.contains('\n') {
+            return Err(Error::Custom(format!(
+                "unexpected weather response from {url}: {text:?}"
+            )));
+        }
+
+        Ok(text.to_string())
+    }
+
+    /// Fetch a new reading, falling back to the last good one if that fails, and return the
+    /// text to display.
+    pub fn update(&mut self) -> Option<String> {
+        self.last_attempt = Some(Instant::now());
+        match self.fetch() {
+            Ok(text) => {
+                self.failures = 0;
+                let reading = Reading {
+                    text,
+                    fetched_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                };
+                if let Err(e) = save_cache(&self.cache_path, &reading) {
+                    tracing::warn!(%e, path = ?self.cache_path, "unable to cache weather reading");
+                }
+                self.last = Some(reading);
+            }
+
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                tracing::warn!(%e, failures = self.failures, "unable to fetch weather");
+            }
+        }
+
+        self.text()
+    }
+
+    /// As [Weather::update], unless a fetch was already attempted within the last `max_age`.
+    ///
+    /// This lets the bars on several screens share one [Weather] without each of them fetching.
+    pub fn update_every(&mut self, max_age: Duration) -> Option<String> {
+        match self.last_attempt {
+            Some(t) if t.elapsed() < max_age => self.text(),
+            _ => self.update(),
+        }
+    }
+
+    /// Whether enough fetches in a row have failed that the last reading is out of date.
+    pub fn is_stale(&self) -> bool {
+        self.failures > 0 && self.failures >= self.settings.stale_after
+    }
+
+    /// The text to display for the last good reading, if there has been one.
+    pub fn text(&self) -> Option<String> {
+        let reading = self.last.as_ref()?;
+
+        if self.is_stale() {
+            Some(format!("{}{}", reading.text, self.settings.stale_marker))
+        } else {
+            Some(reading.text.clone())
+        }
+    }
+}
+
+fn load_cache(path: &Path) -> Option<Reading> {
+    let raw = std::fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&raw) {
+        Ok(reading) => Some(reading),
+        Err(e) => {
+            tracing::warn!(%e, ?path, "ignoring invalid weather cache");
+            None
+        }
+    }
+}
+
+fn save_cache(path: &Path, reading: &Reading) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let raw = serde_json::to_string(reading).map_err(|e| Error::Custom(e.to_string()))?;
+    // Write then rename so that a restart never sees a half written cache
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, raw)?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+// Percent encode everything other than the characters that are always safe in a URL.
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'+' | b',' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver},
+        thread,
+    };
+
+    // A stand in for wttr.in that answers each request in turn with the given status and body,
+    // sending back the request line it received.
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+                tx.send(lines.next().unwrap().unwrap()).unwrap();
+                for line in lines {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+
+        (endpoint, rx)
+    }
+
+    fn settings(endpoint: String) -> WeatherSettings {
+        WeatherSettings {
+            endpoint,
+            location: "New York".to_string(),
+            units: WeatherUnits::Metric,
+            timeout_secs: 5,
+            stale_after: 2,
+            ..WeatherSettings::default()
+        }
+    }
+
+    #[test]
+    fn fetch_requests_the_configured_location_units_and_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let (endpoint, requests) = serve(vec![(200, "☀️ +21°C\n")]);
+        let weather = Weather::new(settings(endpoint), dir.path().join(CACHE_FILE_NAME));
+
+        assert_eq!(weather.fetch().unwrap(), "☀️ +21°C");
+        assert_eq!(
+            requests.recv().unwrap(),
+            "GET /New+York?format=%25c%25t&m HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn last_good_reading_is_cached_and_marked_stale_after_repeated_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("penrose").join(CACHE_FILE_NAME);
+        let (endpoint, _requests) = serve(vec![
+            (200, "☁️ +12°C"),
+            (503, "busy"),
+            (200, "Unknown location\nplease try again"),
+            (200, "🌧 +9°C"),
+        ]);
+        let mut weather = Weather::new(settings(endpoint.clone()), &path);
+
+        assert_eq!(weather.text(), None);
+        assert_eq!(weather.update().as_deref(), Some("☁️ +12°C"));
+        assert_eq!(weather.update().as_deref(), Some("☁️ +12°C"));
+        assert_eq!(weather.update().as_deref(), Some("☁️ +12°C?"));
+
+        // A restart picks up the cached reading before anything has been fetched
+        let restarted = Weather::new(settings(endpoint), &path);
+        assert_eq!(restarted.text().as_deref(), Some("☁️ +12°C"));
+
+        assert_eq!(weather.update().as_deref(), Some("🌧 +9°C"));
+        assert!(!weather.is_stale());
+    }
+
+    #[test]
+    fn shared_readings_are_only_fetched_once_per_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let (endpoint, requests) = serve(vec![(200, "☀️ +21°C")]);
+        let mut weather = Weather::new(settings(endpoint), dir.path().join(CACHE_FILE_NAME));
+        let interval = Duration::from_secs(60);
+
+        assert_eq!(weather.update_every(interval).as_deref(), Some("☀️ +21°C"));
+        assert_eq!(weather.update_every(interval).as_deref(), Some("☀️ +21°C"));
+        assert_eq!(requests.try_iter().count(), 1);
+        assert_eq!(weather.failures, 0);
+    }
+}
@@ -0,0 +1,379 @@
+//! Running the applications that have installed an XDG autostart entry, once per session.
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::autostart::{Program, RestartPolicy};
+
+/// The name of the desktop given by `DesktopNames` in `penrose.desktop`, used when
+/// `$XDG_CURRENT_DESKTOP` is not set.
+pub const DESKTOP_NAME: &str = "favilo-penrose";
+
+const GROUP: &str = "[Desktop Entry]";
+
+/// The directories that autostart entries are read from, in order of precedence.
+pub fn autostart_dirs() -> Vec<PathBuf> {
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .unwrap_or_else(|_| format!("{}/.config", env::var("HOME").unwrap_or_default()));
+    let config_dirs = env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".to_string());
+
+    std::iter::once(config_home.as_str())
+        .chain(config_dirs.split(':'))
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join("autostart"))
+        .collect()
+}
+
+/// The names of the current desktop, from `$XDG_CURRENT_DESKTOP`.
+pub fn current_desktops() -> Vec<String> {
+    match env::var("XDG_CURRENT_DESKTOP") {
+        Ok(desktops) if !desktops.is_empty() => desktops.split(':').map(String::from).collect(),
+        _ => vec![DESKTOP_NAME.to_string()],
+    }
+}
+
+/// The `[Desktop Entry]` keys of an autostart entry that decide whether and how it is run.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DesktopEntry {
+    /// The file name of the entry, e.g. `nm-applet.desktop`.
+    pub id: String,
+    pub path: PathBuf,
+    pub entry_type: Option<String>,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub exec: Option<String>,
+    pub try_exec: Option<String>,
+    pub hidden: bool,
+    pub only_show_in: Vec<String>,
+    pub not_show_in: Vec<String>,
+    /// `X-GNOME-Autostart-enabled`, which a lot of entries use in place of `Hidden`.
+    pub gnome_enabled: bool,
+}
+
+impl DesktopEntry {
+    /// Parse the contents of a desktop entry file, ignoring anything outside of the
+    /// `[Desktop Entry]` group and any localised keys.
+    pub fn parse(id: &str, path: &Path, raw: &str) -> Self {
+        let mut entry = Self {
+            id: id.to_string(),
+            path: path.to_path_buf(),
+            gnome_enabled: true,
+            ..Self::default()
+        };
+
+        let mut in_group = false;
+        for line in raw.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_group = line == GROUP;
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=').filter(|_| in_group) else {
+                continue;
+            };
+
+            let value = unescape(value.trim());
+            match key.trim() {
+                "Type" => entry.entry_type = Some(value),
+                "Name" => entry.name = Some(value),
+                "Icon" => entry.icon = Some(value),
+                "Exec" => entry.exec = Some(value),
+                "TryExec" => entry.try_exec = Some(value),
+                "Hidden" => entry.hidden = value == "true",
+                "OnlyShowIn" => entry.only_show_in = list(&value),
+                "NotShowIn" => entry.not_show_in = list(&value),
+                "X-GNOME-Autostart-enabled" => entry.gnome_enabled = value != "false",
+                _ => (),
+            }
+        }
+
+        entry
+    }
+
+    /// Whether the entry should be started on one of `desktops`.
+    pub fn should_start(&self, desktops: &[String]) -> bool {
+        let on_desktop = |names: &[String]| names.iter().any(|name| desktops.contains(name));
+
+        let shown = if self.only_show_in.is_empty() {
+            !on_desktop(&self.not_show_in)
+        } else {
+            on_desktop(&self.only_show_in)
+        };
+
+        self.entry_type.as_deref() == Some("Application")
+            && !self.hidden
+            && self.gnome_enabled
+            && shown
+    }
+
+    /// Whether the program named by `TryExec`, if any, is installed.
+    pub fn is_installed(&self) -> bool {
+        match &self.try_exec {
+            Some(program) => find_executable(program).is_some(),
+            None => true,
+        }
+    }
+
+    /// The `Exec` key with its field codes expanded, as a shell command.
+    ///
+    /// There are no files or URLs to open when autostarting so `%f`, `%u` and friends are
+    /// dropped.
+    pub fn command(&self) -> Option<String> {
+        let exec = self.exec.as_deref()?;
+        let mut args = Vec::new();
+
+        for arg in split_exec(exec)? {
+            match arg.as_str() {
+                "%f" | "%F" | "%u" | "%U" | "%d" | "%D" | "%n" | "%N" | "%v" | "%m" => (),
+                "%i" => {
+                    if let Some(icon) = &self.icon {
+                        args.push("--icon".to_string());
+                        args.push(icon.clone());
+                    }
+                }
+                _ => args.push(self.expand(&arg)),
+            }
+        }
+
+        if args.is_empty() {
+            return None;
+        }
+
+        Some(
+            args.iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    // Expand the field codes within a single argument.
+    fn expand(&self, arg: &str) -> String {
+        let mut expanded = String::new();
+        let mut chars = arg.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some('c') => expanded.push_str(self.name.as_deref().unwrap_or_default()),
+                Some('k') => expanded.push_str(&self.path.to_string_lossy()),
+                // Anything else expands to nothing
+                _ => (),
+            }
+        }
+
+        expanded
+    }
+}
+
+// The escape sequences allowed in any string value
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            // Left for the list parsing that follows
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Split an Exec value into arguments: arguments are separated by spaces and may be wrapped in
+// double quotes, within which `"`, `` ` ``, `$` and `\` are escaped with a backslash. Returns
+// None for unterminated quotes.
+fn split_exec(exec: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut chars = exec.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' if in_arg => {
+                args.push(std::mem::take(&mut arg));
+                in_arg = false;
+            }
+            ' ' => (),
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => arg.push(chars.next()?),
+                        c => arg.push(c),
+                    }
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+
+    Some(args)
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn find_executable(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+/// All of the entries in `dirs`, keeping only the first of those sharing a file name.
+pub fn entries(dirs: &[PathBuf]) -> Vec<DesktopEntry> {
+    let mut entries = BTreeMap::new();
+
+    for dir in dirs {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            continue;
+        };
+
+        for path in read_dir.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let Some(id) = path.file_name().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if !id.ends_with(".desktop") {
+                continue;
+            }
+
+            if let Entry::Vacant(e) = entries.entry(id.to_string()) {
+                match fs::read_to_string(&path) {
+                    Ok(raw) => {
+                        e.insert(DesktopEntry::parse(id, &path, &raw));
+                    }
+                    Err(err) => tracing::warn!(%err, ?path, "unable to read autostart entry"),
+                }
+            }
+        }
+    }
+
+    entries.into_values().collect()
+}
+
+/// The programs to start for the autostart entries in `dirs` on one of `desktops`.
+///
+/// Entries running an executable that is already one of `configured` are skipped so that it
+/// is not started twice.
+pub fn programs(dirs: &[PathBuf], desktops: &[String], configured: &[Program]) -> Vec<Program> {
+    entries(dirs)
+        .into_iter()
+        .filter(|entry| entry.should_start(desktops) && entry.is_installed())
+        .filter_map(|entry| {
+            let command = entry.command()?;
+            let program = Program {
+                name: Some(entry.id),
+                command,
+                restart: RestartPolicy::Never,
+                once: true,
+            };
+
+            if configured
+                .iter()
+                .any(|p| p.executable() == program.executable())
+            {
+                tracing::debug!(name = program.name(), "already an autostart program");
+                return None;
+            }
+
+            Some(program)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_field_codes_are_expanded() {
+        let entry = DesktopEntry::parse(
+            "viewer.desktop",
+            Path::new("/etc/xdg/autostart/viewer.desktop"),
+            r#"
+            [Desktop Entry]
+            Type=Application
+            Name=Image Viewer
+            Icon=viewer
+            Exec="/opt/my viewer/bin/viewer" --title=%c %i --from %k %U --rate 100%% "quoted \\"arg\\""
+            "#,
+        );
+
+        assert_eq!(
+            entry.command().as_deref(),
+            Some(
+                r#"'/opt/my viewer/bin/viewer' '--title=Image Viewer' --icon viewer --from /etc/xdg/autostart/viewer.desktop --rate 100% 'quoted "arg"'"#
+            )
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        let entry = DesktopEntry {
+            exec: Some(r#"viewer "oops"#.to_string()),
+            ..DesktopEntry::default()
+        };
+
+        assert_eq!(entry.command(), None);
+    }
+}
@@ -0,0 +1,69 @@
+//! Supervising autostart programs with SIGCHLD ignored, as it is once penrose is running.
+mod common;
+
+use std::{fs, path::Path, thread, time::Duration};
+
+use common::wait_until;
+use favilo_penrose::autostart::{Backoff, Program, RestartPolicy, Supervisor};
+
+const BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(10),
+    max: Duration::from_millis(40),
+};
+
+fn program(name: &str, command: String) -> Program {
+    Program {
+        name: Some(name.to_string()),
+        command,
+        restart: RestartPolicy::OnFailure,
+        once: true,
+    }
+}
+
+fn runs(path: &Path) -> usize {
+    fs::read_to_string(path).map_or(0, |s| s.lines().count())
+}
+
+fn comm(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+
+    Some(comm.trim().to_string())
+}
+
+#[test]
+fn exit_statuses_are_seen_with_sigchld_ignored() {
+    // SAFETY: this is the only test in this binary so nothing else is waiting on children
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+
+    let dir = tempfile::tempdir().unwrap();
+    let succeeds = dir.path().join("succeeds");
+    let fails = dir.path().join("fails");
+    let programs = [
+        program(
+            "succeeds",
+            format!("echo run >> {}; exit 0", succeeds.display()),
+        ),
+        program("fails", format!("echo run >> {}; exit 1", fails.display())),
+        program("sleeper", "exec sleep 30".to_string()),
+    ];
+
+    let supervisor = Supervisor::start(&programs, BACKOFF, false, dir.path().join("record"));
+
+    assert!(
+        wait_until(|| runs(&fails) >= 3),
+        "failure was not restarted"
+    );
+    assert!(wait_until(|| runs(&succeeds) == 1), "success was not run");
+    thread::sleep(BACKOFF.max * 3);
+    assert_eq!(runs(&succeeds), 1, "success was restarted");
+
+    // The program itself is tracked rather than the shell waiting on it
+    let mut pid = None;
+    assert!(wait_until(|| {
+        pid = supervisor.running().get("sleeper").copied();
+        pid.and_then(comm).as_deref() == Some("sleep")
+    }));
+
+    supervisor.stop();
+    assert!(wait_until(|| comm(pid.unwrap()).is_none()));
+}
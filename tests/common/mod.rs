@@ -0,0 +1,52 @@
+//! Helpers shared between the integration tests.
+// Each test crate only uses some of these
+#![allow(dead_code)]
+
+use std::{
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Poll `f` until it holds, giving up after [TIMEOUT].
+pub fn wait_until(mut f: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    false
+}
+
+/// An X server of our own, killed when dropped.
+pub struct Xvfb(Child);
+
+impl Xvfb {
+    pub fn start(display: &str) -> Self {
+        let child = Command::new("Xvfb")
+            .args([display, "-nolisten", "tcp", "-screen", "0", "1280x800x24"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Xvfb to be installed");
+        let xvfb = Self(child);
+
+        let socket = format!("/tmp/.X11-unix/X{}", &display[1..]);
+        assert!(wait_until(|| Path::new(&socket).exists()), "Xvfb to start");
+
+        xvfb
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
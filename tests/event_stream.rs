@@ -0,0 +1,124 @@
+//! Subscribing to the event stream over the control socket.
+//!
+//! There is no X server to run against here so the test stands in for the main event loop,
+//! handling the commands queued by the IPC server against a plain [StackSet].
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Lines, Write},
+    os::unix::net::UnixStream,
+    thread,
+};
+
+use common::TIMEOUT;
+use favilo_penrose::{
+    config::Settings,
+    ipc::{self, EventStream, WmState},
+    layouts::layouts,
+    remote::{Command, Remote},
+};
+use penrose::{
+    pure::{geometry::Rect, StackSet},
+    Xid,
+};
+
+fn next_state(lines: &mut Lines<BufReader<UnixStream>>) -> WmState {
+    let line = lines.next().expect("a line").expect("a valid line");
+    serde_json::from_str(&line).expect("a valid state")
+}
+
+#[test]
+fn subscribers_get_one_line_per_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("penrose.sock");
+
+    let (remote, inbox) = Remote::new(|| Ok(()));
+    ipc::serve(&path, remote).unwrap();
+
+    let wm = thread::spawn(move || {
+        let screens = [Rect::new(0, 0, 1920, 1080), Rect::new(1920, 0, 1920, 1080)];
+        let mut cs: StackSet<Xid> =
+            StackSet::try_new(layouts(&Settings::default()), ["1", "2", "3"], screens).unwrap();
+        let urgent = Xid::from(2);
+        let is_urgent = |id| id == urgent;
+        let mut stream = EventStream::default();
+
+        match inbox.recv_timeout(TIMEOUT) {
+            Some(Command::Subscribe(tx)) => {
+                stream.subscribe(tx, WmState::new(&cs, None, is_urgent))
+            }
+            cmd => panic!("expected a subscription, got {cmd:?}"),
+        }
+
+        cs.insert(Xid::from(1));
+        stream.publish(WmState::new(&cs, Some("kitty".to_string()), is_urgent));
+
+        // Nothing has changed so nothing should be sent
+        stream.publish(WmState::new(&cs, Some("kitty".to_string()), is_urgent));
+
+        cs.focus_tag("3");
+        cs.insert(urgent);
+        cs.focus_tag("1");
+        stream.publish(WmState::new(&cs, Some("kitty".to_string()), is_urgent));
+    });
+
+    let mut conn = UnixStream::connect(&path).unwrap();
+    conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+    writeln!(conn, r#"{{"command": "subscribe"}}"#).unwrap();
+    let mut lines = BufReader::new(conn).lines();
+
+    let initial = next_state(&mut lines);
+    assert_eq!(initial.focused_tag, "1");
+    assert_eq!(initial.screen, 0);
+    assert_eq!(initial.title, None);
+    assert!(initial.tags.iter().all(|t| !t.occupied && !t.urgent));
+    assert_eq!(
+        initial.tags.iter().map(|t| t.screen).collect::<Vec<_>>(),
+        vec![Some(0), Some(1), None]
+    );
+
+    let opened = next_state(&mut lines);
+    assert_eq!(opened.title.as_deref(), Some("kitty"));
+    assert_eq!(opened.focused_client, Some(Xid::from(1)));
+    assert!(opened.tags[0].occupied && opened.tags[0].focused);
+
+    let urgent = next_state(&mut lines);
+    assert!(urgent.tags[2].occupied && urgent.tags[2].urgent);
+    assert!(!urgent.tags[0].urgent);
+
+    wm.join().unwrap();
+
+    // The window manager has gone away so the stream ends rather than repeating itself
+    assert!(lines.next().is_none());
+}
+
+#[test]
+fn requests_get_a_response_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("penrose.sock");
+
+    let (remote, inbox) = Remote::new(|| Ok(()));
+    ipc::serve(&path, remote).unwrap();
+
+    let wm = thread::spawn(move || match inbox.recv_timeout(TIMEOUT) {
+        Some(Command::Ipc(request, reply)) => {
+            assert_eq!(request, ipc::Request::Reload);
+            reply.send(ipc::Response::Ok).unwrap();
+        }
+        cmd => panic!("expected a request, got {cmd:?}"),
+    });
+
+    let mut conn = UnixStream::connect(&path).unwrap();
+    conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+    writeln!(conn, r#"{{"command": "bogus"}}"#).unwrap();
+    writeln!(conn, r#"{{"command": "reload"}}"#).unwrap();
+    let mut lines = BufReader::new(conn).lines();
+
+    let invalid: ipc::Response = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+    assert!(matches!(invalid, ipc::Response::Error { .. }));
+
+    let ok: ipc::Response = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+    assert_eq!(ok, ipc::Response::Ok);
+
+    wm.join().unwrap();
+}
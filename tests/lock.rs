@@ -0,0 +1,89 @@
+//! Waiting for the lock window to be mapped before letting the machine sleep.
+//!
+//! Like the tray test this needs `Xvfb` and is ignored by default: run it with
+//! `cargo test --test lock -- --ignored`.
+mod common;
+
+use std::{
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+use favilo_penrose::{config::LockSettings, lock::LockWatcher};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt, CreateWindowAux, PropMode, WindowClass},
+    wrapper::ConnectionExt as _,
+};
+
+use common::{Xvfb, TIMEOUT};
+
+const DISPLAY: &str = ":58";
+const SHORT: Duration = Duration::from_millis(300);
+
+// Map a window with the given class shortly after being called, as a locker would
+fn map_window_later(class: &'static str) {
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let (conn, screen_num) = x11rb::connect(Some(DISPLAY)).unwrap();
+        let screen = &conn.setup().roots[screen_num];
+        let window = conn.generate_id().unwrap();
+        conn.create_window(
+            screen.root_depth,
+            window,
+            screen.root,
+            0,
+            0,
+            100,
+            100,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &CreateWindowAux::new(),
+        )
+        .unwrap();
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_CLASS,
+            AtomEnum::STRING,
+            format!("{class}\0{class}\0").as_bytes(),
+        )
+        .unwrap();
+        conn.map_window(window).unwrap();
+        conn.flush().unwrap();
+
+        // Keep the window around for the rest of the test
+        thread::sleep(TIMEOUT);
+    });
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn only_the_configured_lock_window_counts_as_locked() {
+    let _xvfb = Xvfb::start(DISPLAY);
+    let settings = LockSettings {
+        window_class: "i3lock".to_string(),
+        ..LockSettings::default()
+    };
+    let watcher = LockWatcher::try_new(Some(DISPLAY), &settings).unwrap();
+
+    let started = |class| {
+        move |tx: Sender<bool>| {
+            map_window_later(class);
+            tx.send(true).unwrap();
+        }
+    };
+
+    assert!(watcher.lock_with(started("i3lock"), TIMEOUT).unwrap());
+
+    // Any other window being mapped leaves us waiting until the timeout
+    let start = Instant::now();
+    assert!(!watcher.lock_with(started("kitty"), SHORT).unwrap());
+    assert!(start.elapsed() >= SHORT);
+
+    // There is nothing to wait for when the screen was already locked
+    let already_locked = |tx: Sender<bool>| tx.send(false).unwrap();
+    assert!(watcher.lock_with(already_locked, SHORT).unwrap());
+}
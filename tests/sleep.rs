@@ -0,0 +1,232 @@
+//! Holding and releasing the sleep inhibitor around `PrepareForSleep`.
+//!
+//! logind is stood in for by a mock service on a private bus run by `dbus-daemon`. Set
+//! `SKIP_DBUS_TESTS` to skip these tests where `dbus-daemon` is not installed.
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read},
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use favilo_penrose::sleep::{SleepInhibitor, LOGIND_MANAGER, LOGIND_NAME, LOGIND_PATH};
+use tempfile::TempDir;
+use zbus::{
+    blocking::{connection::Builder, Connection},
+    fdo, interface, zvariant,
+};
+
+use common::{wait_until, TIMEOUT};
+
+const BUS_CONFIG: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path=BUS_SOCKET</listen>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+struct Bus {
+    daemon: Child,
+    address: String,
+    _dir: TempDir,
+}
+
+impl Bus {
+    fn start() -> Option<Self> {
+        let dir = TempDir::new().unwrap();
+        let config = dir.path().join("bus.conf");
+        let socket = dir.path().join("bus");
+        std::fs::write(
+            &config,
+            BUS_CONFIG.replace("BUS_SOCKET", &socket.to_string_lossy()),
+        )
+        .unwrap();
+
+        let res = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match res {
+            Ok(daemon) => daemon,
+            Err(_) if std::env::var_os("SKIP_DBUS_TESTS").is_some() => {
+                eprintln!("dbus-daemon is not installed: skipping");
+                return None;
+            }
+            Err(e) => panic!("unable to run dbus-daemon (set SKIP_DBUS_TESTS to skip): {e}"),
+        };
+
+        let mut address = String::new();
+        let stdout = daemon.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut address).unwrap();
+
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+            _dir: dir,
+        })
+    }
+
+    fn connect(&self) -> Connection {
+        Builder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+type Inhibit = (Vec<String>, UnixStream);
+
+// Just enough of org.freedesktop.login1 to hand out inhibitors and announce sleeping.
+struct MockManager {
+    inhibits: Sender<Inhibit>,
+    deny: bool,
+}
+
+#[interface(name = "org.freedesktop.login1.Manager")]
+impl MockManager {
+    // As with logind, the inhibitor is released when the other end is closed
+    fn inhibit(
+        &self,
+        what: String,
+        who: String,
+        why: String,
+        mode: String,
+    ) -> fdo::Result<zvariant::OwnedFd> {
+        if self.deny {
+            return Err(fdo::Error::AccessDenied("not today".to_string()));
+        }
+
+        let (ours, theirs) = UnixStream::pair().map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        let _ = self.inhibits.send((vec![what, who, why, mode], ours));
+
+        Ok(OwnedFd::from(theirs).into())
+    }
+}
+
+struct MockLogind {
+    conn: Connection,
+    inhibits: Receiver<Inhibit>,
+}
+
+impl MockLogind {
+    fn start(bus: &Bus, deny: bool) -> Self {
+        let (tx, inhibits) = channel();
+        let manager = MockManager { inhibits: tx, deny };
+        let conn = Builder::address(bus.address.as_str())
+            .unwrap()
+            .name(LOGIND_NAME)
+            .unwrap()
+            .serve_at(LOGIND_PATH, manager)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        Self { conn, inhibits }
+    }
+
+    fn expect_inhibit(&self) -> Inhibit {
+        self.inhibits
+            .recv_timeout(TIMEOUT)
+            .expect("an Inhibit call")
+    }
+
+    fn prepare_for_sleep(&self, start: bool) {
+        self.emit(&start);
+    }
+
+    fn emit<B: serde::Serialize + zvariant::DynamicType>(&self, body: &B) {
+        self.conn
+            .emit_signal(
+                None::<&str>,
+                LOGIND_PATH,
+                LOGIND_MANAGER,
+                "PrepareForSleep",
+                body,
+            )
+            .unwrap();
+    }
+}
+
+fn is_released(inhibitor: &mut UnixStream) -> bool {
+    inhibitor
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+
+    match inhibitor.read(&mut [0u8]) {
+        Ok(0) => true,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+        res => panic!("unexpected read from the inhibitor: {res:?}"),
+    }
+}
+
+#[test]
+fn the_inhibitor_is_released_once_the_screen_is_locked() {
+    let Some(bus) = Bus::start() else {
+        return;
+    };
+    let logind = MockLogind::start(&bus, false);
+
+    let (locking_tx, locking_rx) = channel();
+    let (locked_tx, locked_rx) = channel::<()>();
+    let conn = bus.connect();
+    thread::spawn(move || {
+        let inhibitor = SleepInhibitor::new(&conn).unwrap();
+        assert!(inhibitor.is_held());
+        let _ = inhibitor.run(|| {
+            locking_tx.send(()).unwrap();
+            let _ = locked_rx.recv_timeout(TIMEOUT);
+        });
+    });
+
+    let (args, mut inhibitor) = logind.expect_inhibit();
+    assert_eq!(args[0], "sleep");
+    assert_eq!(args[3], "delay");
+    assert!(!is_released(&mut inhibitor));
+
+    // Signals that don't parse are skipped rather than ending the watch
+    logind.emit(&"not a bool");
+
+    // Sleep is held up for as long as it takes to lock the screen
+    logind.prepare_for_sleep(true);
+    locking_rx.recv_timeout(TIMEOUT).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(!is_released(&mut inhibitor));
+
+    locked_tx.send(()).unwrap();
+    assert!(wait_until(|| is_released(&mut inhibitor)));
+
+    // And taken again on the way back up
+    logind.prepare_for_sleep(false);
+    let (_, mut inhibitor) = logind.expect_inhibit();
+    assert!(!is_released(&mut inhibitor));
+}
+
+#[test]
+fn errors_from_logind_are_returned() {
+    let Some(bus) = Bus::start() else {
+        return;
+    };
+    let _logind = MockLogind::start(&bus, true);
+
+    let err = SleepInhibitor::new(&bus.connect()).err().unwrap();
+    assert!(err.to_string().contains("not today"), "{err}");
+}
@@ -0,0 +1,156 @@
+//! Docking a tiny tray icon client into the system tray.
+//!
+//! This needs a real X server so the test runs against its own `Xvfb`. It is ignored by default:
+//! run it with `cargo test --test tray -- --ignored` where `Xvfb` is installed.
+mod common;
+
+use favilo_penrose::tray::{SystemTray, ICON_PADDING};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            ClientMessageEvent, ConnectionExt, CreateWindowAux, EventMask, PropMode, Window,
+            WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    CURRENT_TIME,
+};
+
+use common::{wait_until, Xvfb};
+
+const DISPLAY: &str = ":57";
+
+fn atom(conn: &RustConnection, name: &str) -> u32 {
+    conn.intern_atom(false, name.as_bytes())
+        .unwrap()
+        .reply()
+        .unwrap()
+        .atom
+}
+
+// The smallest tray icon there is: a window asking to be docked.
+struct TestIcon {
+    conn: RustConnection,
+    window: Window,
+    xembed_info: u32,
+}
+
+impl TestIcon {
+    fn new() -> Self {
+        let (conn, screen_num) = x11rb::connect(Some(DISPLAY)).unwrap();
+        let screen = &conn.setup().roots[screen_num];
+        let window = conn.generate_id().unwrap();
+        let aux = CreateWindowAux::new().event_mask(EventMask::STRUCTURE_NOTIFY);
+        conn.create_window(
+            screen.root_depth,
+            window,
+            screen.root,
+            0,
+            0,
+            16,
+            16,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &aux,
+        )
+        .unwrap();
+        let xembed_info = atom(&conn, "_XEMBED_INFO");
+
+        let icon = Self {
+            conn,
+            window,
+            xembed_info,
+        };
+        icon.set_mapped(true);
+
+        icon
+    }
+
+    fn set_mapped(&self, mapped: bool) {
+        self.conn
+            .change_property32(
+                PropMode::REPLACE,
+                self.window,
+                self.xembed_info,
+                self.xembed_info,
+                &[0, mapped as u32],
+            )
+            .unwrap();
+        self.conn.flush().unwrap();
+    }
+
+    fn dock(&self) {
+        let selection = atom(&self.conn, "_NET_SYSTEM_TRAY_S0");
+        let tray = self
+            .conn
+            .get_selection_owner(selection)
+            .unwrap()
+            .reply()
+            .unwrap()
+            .owner;
+        let opcode = atom(&self.conn, "_NET_SYSTEM_TRAY_OPCODE");
+        let event = ClientMessageEvent::new(32, tray, opcode, [CURRENT_TIME, 0, self.window, 0, 0]);
+        self.conn
+            .send_event(false, tray, EventMask::NO_EVENT, event)
+            .unwrap();
+        self.conn.flush().unwrap();
+    }
+
+    fn parent(&self) -> Window {
+        self.conn
+            .query_tree(self.window)
+            .unwrap()
+            .reply()
+            .unwrap()
+            .parent
+    }
+
+    // Whether the tray has told us that we are embedded.
+    fn embedded(&self) -> bool {
+        let xembed = atom(&self.conn, "_XEMBED");
+        while let Some(event) = self.conn.poll_for_event().unwrap() {
+            if let Event::ClientMessage(e) = event {
+                if e.type_ == xembed && e.data.as_data32()[1] == 0 {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn icons_dock_into_the_tray() {
+    let _xvfb = Xvfb::start(DISPLAY);
+
+    let tray = SystemTray::try_new(Some(DISPLAY), 28, 0x282828ff.into()).unwrap();
+    assert_eq!(tray.width(), 0);
+
+    let icon = TestIcon::new();
+    icon.dock();
+    assert!(wait_until(|| icon.embedded()));
+    assert_eq!(icon.parent(), tray.window());
+
+    let one_icon = 24 + 2 * ICON_PADDING;
+    assert!(wait_until(|| tray.width() == one_icon));
+
+    // Hiding the icon via _XEMBED_INFO frees up its space in the bar
+    icon.set_mapped(false);
+    assert!(wait_until(|| tray.width() == 0));
+    icon.set_mapped(true);
+    assert!(wait_until(|| tray.width() == one_icon));
+
+    let second = TestIcon::new();
+    second.dock();
+    assert!(wait_until(|| tray.width() == 2 * 24 + 3 * ICON_PADDING));
+
+    icon.conn.destroy_window(icon.window).unwrap();
+    icon.conn.flush().unwrap();
+    assert!(wait_until(|| tray.width() == one_icon));
+}
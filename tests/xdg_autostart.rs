@@ -0,0 +1,97 @@
+//! Reading XDG autostart entries from the fixture directories under `tests/fixtures/autostart`.
+use std::path::PathBuf;
+
+use favilo_penrose::{
+    autostart::{Program, RestartPolicy},
+    xdg_autostart::{entries, programs, DESKTOP_NAME},
+};
+
+fn fixture_dirs() -> Vec<PathBuf> {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/autostart");
+
+    vec![root.join("user"), root.join("system")]
+}
+
+#[test]
+fn user_entries_hide_system_entries_with_the_same_name() {
+    let dirs = fixture_dirs();
+    let entries = entries(&dirs);
+
+    let ids: Vec<_> = entries.iter().map(|e| e.id.as_str()).collect();
+    assert_eq!(
+        ids,
+        vec![
+            "disabled.desktop",
+            "editor.desktop",
+            "gnome-only.desktop",
+            "installed.desktop",
+            "link.desktop",
+            "missing.desktop",
+            "nm-applet.desktop",
+            "not-here.desktop",
+            "ours.desktop",
+            "overridden.desktop",
+        ]
+    );
+
+    let overridden = entries
+        .iter()
+        .find(|e| e.id == "overridden.desktop")
+        .unwrap();
+    assert!(overridden.hidden);
+    assert!(overridden.path.starts_with(&dirs[0]));
+}
+
+#[test]
+fn entries_for_this_desktop_become_programs() {
+    let desktops = vec![DESKTOP_NAME.to_string()];
+    let configured = vec![Program {
+        name: Some("network".to_string()),
+        command: "/usr/bin/nm-applet --indicator".to_string(),
+        restart: RestartPolicy::OnFailure,
+        once: true,
+    }];
+
+    let programs = programs(&fixture_dirs(), &desktops, &configured);
+    let found: Vec<_> = programs
+        .iter()
+        .map(|p| (p.name(), p.command.as_str()))
+        .collect();
+
+    assert_eq!(
+        found,
+        vec![
+            (
+                "editor.desktop",
+                "'/opt/My Editor/editor' --name 'My Editor' --icon editor --restore"
+            ),
+            ("installed.desktop", "sh"),
+            (
+                "ours.desktop",
+                r#"sh -c 'echo "hello from $DESKTOP_SESSION"'"#
+            ),
+        ]
+    );
+    assert!(programs
+        .iter()
+        .all(|p| p.once && p.restart == RestartPolicy::Never));
+}
+
+#[test]
+fn only_show_in_and_not_show_in_use_the_current_desktop() {
+    // nm-applet is not shown on GNOME, and not-here only hides itself from us
+    let desktops = vec!["GNOME".to_string()];
+    let programs = programs(&fixture_dirs(), &desktops, &[]);
+    let names: Vec<_> = programs.iter().map(|p| p.name()).collect();
+
+    assert_eq!(
+        names,
+        vec![
+            "editor.desktop",
+            "gnome-only.desktop",
+            "installed.desktop",
+            "not-here.desktop",
+            "ours.desktop"
+        ]
+    );
+}